@@ -1,4 +1,7 @@
 pub(crate) mod addurl;
+pub(crate) mod fromkey;
+pub(crate) mod key;
+pub(crate) mod lookupkey;
 pub(crate) mod metadata;
 pub(crate) mod outputs;
 pub(crate) mod registerurl;
@@ -7,33 +10,38 @@ use anyhow::Context;
 use bytes::Bytes;
 use futures_util::{SinkExt, TryStream, TryStreamExt};
 use indenter::indented;
-use serde::Deserialize;
+use serde::Serialize;
 use std::ffi::OsStr;
 use std::fmt::{self, Write};
 use std::future::Future;
 use std::path::Path;
-use std::pin::Pin;
+use std::pin::{pin, Pin};
 use std::process::Stdio;
 use std::time::Duration;
 use tokio::process::{Child, ChildStdin, ChildStdout, Command};
 use tokio::time;
 use tokio_serde::formats::Json;
-use tokio_serde::{Framed, Serializer};
+use tokio_serde::{Deserializer, Framed, Serializer};
 use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
 
 pub(crate) type StdinTransport = FramedWrite<ChildStdin, BinaryLinesCodec>;
 pub(crate) type StdoutTransport = FramedRead<ChildStdout, BinaryLinesCodec>;
 pub(crate) type AnnexSink<Input> = Framed<StdinTransport, (), Input, AnnexCodec>;
-pub(crate) type AnnexStream<Output> = Framed<StdoutTransport, Output, (), Json<Output, ()>>;
+pub(crate) type AnnexStream<Output, Decoder = Json<Output, ()>> =
+    Framed<StdoutTransport, Output, (), Decoder>;
 
-pub(crate) struct AnnexProcess<Input, Output> {
+/// A `git-annex` command running in batch mode.  By default, the command's
+/// output is decoded as JSON; commands that output something else (e.g.,
+/// `lookupkey`) take a different `Decoder`.
+pub(crate) struct AnnexProcess<Input, Output, Decoder = Json<Output, ()>> {
     name: String,
     p: Child,
     stdin: AnnexSink<Input>,
-    stdout: AnnexStream<Output>,
+    stdout: AnnexStream<Output, Decoder>,
 }
 
-impl<Input, Output> AnnexProcess<Input, Output> {
+impl<Input, Output, Decoder> AnnexProcess<Input, Output, Decoder> {
     const MAX_INPUT_LEN: usize = 65535;
     const ERR_TIMEOUT: Duration = Duration::from_secs(3);
 
@@ -42,6 +50,7 @@ impl<Input, Output> AnnexProcess<Input, Output> {
         I: IntoIterator<Item = S> + Send,
         S: AsRef<OsStr> + Send,
         P: AsRef<Path> + Send,
+        Decoder: Default,
     {
         let args = args
             .into_iter()
@@ -53,12 +62,18 @@ impl<Input, Output> AnnexProcess<Input, Output> {
             shell_words::join(args.iter().map(|s| s.to_string_lossy()))
         );
         log::debug!("Opening pipe to: {cmdstr}");
-        let mut p = Command::new("git-annex")
-            .arg(name)
+        let mut cmd = std::process::Command::new("git-annex");
+        cmd.arg(name)
             .args(args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .current_dir(repo)
+            .current_dir(repo);
+        // Put the process in its own process group so that a Ctrl-C at the
+        // terminal is only delivered to us, letting us shut the process down
+        // gracefully.
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        let mut p = Command::from(cmd)
             .spawn()
             .with_context(|| format!("Error spawning `{cmdstr}`"))?;
         let stdin = p.stdin.take().expect("Child.stdin was unexpectedly None");
@@ -72,31 +87,51 @@ impl<Input, Output> AnnexProcess<Input, Output> {
                     stdout,
                     BinaryLinesCodec::new_with_max_length(Self::MAX_INPUT_LEN),
                 ),
-                Json::default(),
+                Decoder::default(),
             ),
         })
     }
 
-    pub(crate) async fn in_context<Func, F, T, E>(self, func: Func) -> Result<T, E>
+    /// Run `func` on the process's I/O handles, then wait for the process to
+    /// exit if `func` succeeded or terminate it if `func` failed.
+    ///
+    /// If `kill` is cancelled while `func` is running, the process is
+    /// terminated immediately, after which `func` is left to run to
+    /// completion (which it should do quickly, now that the process's pipes
+    /// are closed).
+    pub(crate) async fn in_context<Func, F, T, E>(
+        self,
+        func: Func,
+        kill: &CancellationToken,
+    ) -> Result<T, E>
     where
         Input: Send,
         Output: Send,
-        Func: (FnOnce(AnnexIO<Input, Output>) -> F) + Send,
+        Decoder: Send,
+        Func: (FnOnce(AnnexIO<Input, Output, Decoder>) -> F) + Send,
         F: Future<Output = Result<T, E>> + Send,
         T: Send,
         E: Send,
     {
         let (terminator, io) = self.split();
-        let r = func(io).await;
-        if r.is_ok() {
-            terminator.wait(None).await;
-        } else {
-            terminator.terminate(Some(Self::ERR_TIMEOUT)).await;
+        let mut fut = pin!(func(io));
+        tokio::select! {
+            biased;
+            r = &mut fut => {
+                if r.is_ok() {
+                    terminator.wait(None).await;
+                } else {
+                    terminator.terminate(Some(Self::ERR_TIMEOUT)).await;
+                }
+                return r;
+            }
+            () = kill.cancelled() => (),
         }
-        r
+        terminator.terminate(Some(Self::ERR_TIMEOUT)).await;
+        fut.await
     }
 
-    pub(crate) fn split(self) -> (AnnexTerminator, AnnexIO<Input, Output>) {
+    pub(crate) fn split(self) -> (AnnexTerminator, AnnexIO<Input, Output, Decoder>) {
         let terminator = AnnexTerminator {
             name: self.name.clone(),
             p: self.p,
@@ -110,6 +145,76 @@ impl<Input, Output> AnnexProcess<Input, Output> {
     }
 }
 
+/// A `git-annex` command in batch mode that is only started the first time
+/// it's sent any input.  Once the caller is done with it, it must be shut
+/// down with [`LazyAnnexProcess::finish()`].
+pub(crate) struct LazyAnnexProcess<'a, Input, Output, Decoder = Json<Output, ()>> {
+    name: &'static str,
+    args: &'static [&'static str],
+    repo: &'a Path,
+    kill: &'a CancellationToken,
+    running: Option<(AnnexTerminator, AnnexIO<Input, Output, Decoder>)>,
+}
+
+impl<'a, Input, Output, Decoder> LazyAnnexProcess<'a, Input, Output, Decoder> {
+    /// Prepare to run `git-annex {name} {args}` in `repo`.  If `kill` is
+    /// cancelled while waiting for the command's output, the wait is
+    /// abandoned with an error.
+    pub(crate) fn new(
+        name: &'static str,
+        args: &'static [&'static str],
+        repo: &'a Path,
+        kill: &'a CancellationToken,
+    ) -> Self {
+        LazyAnnexProcess {
+            name,
+            args,
+            repo,
+            kill,
+            running: None,
+        }
+    }
+
+    /// Send `value` to the command, starting it if it isn't running yet, and
+    /// return its response
+    pub(crate) async fn chat(&mut self, value: Input) -> Result<Output, anyhow::Error>
+    where
+        Input: AnnexInput + Send,
+        <Input as AnnexInput>::Error: Into<BinaryLinesCodecError>,
+        Decoder: Deserializer<Output> + Default + Unpin + Send,
+        <StdoutTransport as TryStream>::Error: From<Decoder::Error>,
+        Output: Unpin + Send,
+    {
+        let (_, io) = match self.running {
+            Some(ref mut running) => running,
+            None => self
+                .running
+                .insert(AnnexProcess::new(self.name, self.args, self.repo)?.split()),
+        };
+        tokio::select! {
+            r = io.chat(value) => r,
+            () = self.kill.cancelled() => {
+                anyhow::bail!("Killed while waiting for `git-annex {}`", self.name)
+            }
+        }
+    }
+
+    /// If the command was started, close its input and then wait for it to
+    /// exit if `success` is true or terminate it if it's false
+    pub(crate) async fn finish(self, success: bool) {
+        if let Some((terminator, io)) = self.running {
+            drop(io);
+            if success {
+                terminator.wait(None).await;
+            } else {
+                terminator
+                    .terminate(Some(AnnexProcess::<Input, Output, Decoder>::ERR_TIMEOUT))
+                    .await;
+            }
+        }
+    }
+}
+
 pub(crate) struct AnnexTerminator {
     name: String,
     p: Child,
@@ -187,14 +292,14 @@ impl AnnexTerminator {
     }
 }
 
-pub(crate) struct AnnexIO<Input, Output> {
+pub(crate) struct AnnexIO<Input, Output, Decoder = Json<Output, ()>> {
     name: String,
     stdin: AnnexSink<Input>,
-    stdout: AnnexStream<Output>,
+    stdout: AnnexStream<Output, Decoder>,
 }
 
-impl<Input, Output> AnnexIO<Input, Output> {
-    pub(crate) fn split(self) -> (AnnexSink<Input>, AnnexStream<Output>) {
+impl<Input, Output, Decoder> AnnexIO<Input, Output, Decoder> {
+    pub(crate) fn split(self) -> (AnnexSink<Input>, AnnexStream<Output, Decoder>) {
         (self.stdin, self.stdout)
     }
 
@@ -202,8 +307,9 @@ impl<Input, Output> AnnexIO<Input, Output> {
     where
         Input: AnnexInput + Send,
         <Input as AnnexInput>::Error: Into<BinaryLinesCodecError>,
-        <StdoutTransport as TryStream>::Error: From<serde_json::Error>,
-        Output: for<'a> Deserialize<'a> + Unpin + Send,
+        Decoder: Deserializer<Output> + Unpin + Send,
+        <StdoutTransport as TryStream>::Error: From<Decoder::Error>,
+        Output: Unpin + Send,
     {
         // send() always flushes
         self.stdin
@@ -241,8 +347,8 @@ impl<I: AnnexInput> Serializer<I> for AnnexCodec {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct AnnexError(Vec<String>);
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct AnnexError(pub(crate) Vec<String>);
 
 impl fmt::Display for AnnexError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -265,3 +371,95 @@ impl fmt::Display for AnnexError {
 }
 
 impl std::error::Error for AnnexError {}
+
+impl AnnexError {
+    /// The error messages reported by git-annex
+    pub fn messages(&self) -> &[String] {
+        &self.0
+    }
+
+    /// Phrases that, when they appear in an error message, indicate a failure
+    /// that may go away if the operation is retried
+    const TRANSIENT_PHRASES: &'static [&'static str] = &[
+        "timed out",
+        "timeout",
+        "connection reset",
+        "connection refused",
+        "connection closed",
+        "broken pipe",
+        "temporary failure",
+        "try again",
+        "internal server error",
+        "bad gateway",
+        "service unavailable",
+        "gateway time-out",
+        "too many requests",
+    ];
+
+    /// Returns true if the error messages indicate a transient failure, such
+    /// as a timeout, a dropped connection, or a 5xx response from the server
+    pub fn is_transient(&self) -> bool {
+        self.0.iter().any(|msg| {
+            let msg = msg.to_lowercase();
+            Self::TRANSIENT_PHRASES.iter().any(|p| msg.contains(p)) || has_5xx_status(&msg)
+        })
+    }
+}
+
+/// Returns true if the lowercased message `msg` contains a 5xx HTTP status
+/// code in a context that marks it as one, i.e., directly after "status",
+/// "code", or "statuscode" (as in "status code 503" or "statusCode = 503")
+/// or after an HTTP version (as in "HTTP/1.1 502").  Other numbers starting
+/// with 5, such as sizes or parts of URLs, don't count.
+fn has_5xx_status(msg: &str) -> bool {
+    let words = msg
+        .split(|c: char| c.is_whitespace() || matches!(c, '=' | ':' | ',' | '(' | ')' | '{' | '}'))
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>();
+    words
+        .iter()
+        .zip(words.iter().skip(1))
+        .any(|(&context, &code)| {
+            code.len() == 3
+                && code.starts_with('5')
+                && code.bytes().all(|b| b.is_ascii_digit())
+                && (matches!(context, "status" | "code" | "statuscode")
+                    || context.starts_with("http/"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("  download failed: Connection reset by peer", true)]
+    #[case("  download failed: ResponseTimeout", true)]
+    #[case("  download failed: Service Unavailable", true)]
+    #[case("  download failed: status code 502", true)]
+    #[case("  download failed: Status code: 503", true)]
+    #[case(
+        "  StatusCodeException (Response {responseStatus = Status {statusCode = 504",
+        true
+    )]
+    #[case("  download failed: HTTP/1.1 520 Unknown", true)]
+    #[case("  download failed: Not Found", false)]
+    #[case("  download failed: status code 404", false)]
+    #[case("  download failed: 5000 bytes expected", false)]
+    #[case("  download failed: 512 bytes expected", false)]
+    #[case("  https://example.com/503/a.txt: Not Found", false)]
+    #[case("  download failed: file 503.txt not found", false)]
+    #[case("  download failed: status code 404 (after 500 ms)", false)]
+    fn test_is_transient(#[case] msg: &str, #[case] transient: bool) {
+        assert_eq!(
+            AnnexError(vec![String::from(msg)]).is_transient(),
+            transient
+        );
+    }
+
+    #[test]
+    fn test_is_transient_empty() {
+        assert!(!AnnexError(Vec::new()).is_transient());
+    }
+}
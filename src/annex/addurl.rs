@@ -2,6 +2,7 @@
 use super::outputs::{Action, AnnexResult};
 use super::{AnnexError, AnnexInput};
 use crate::filepath::FilePath;
+use crate::Downloadable;
 use bytes::Bytes;
 use serde::Deserialize;
 use url::Url;
@@ -11,6 +12,15 @@ pub(crate) struct AddURLInput {
     pub(crate) path: FilePath,
 }
 
+impl From<&Downloadable> for AddURLInput {
+    fn from(dl: &Downloadable) -> AddURLInput {
+        AddURLInput {
+            url: dl.url.clone(),
+            path: dl.path.clone(),
+        }
+    }
+}
+
 impl AnnexInput for AddURLInput {
     type Error = std::io::Error;
 
@@ -0,0 +1,68 @@
+use super::outputs::{Action, AnnexResult};
+use super::{AnnexError, AnnexInput};
+use crate::filepath::FilePath;
+use bytes::Bytes;
+use serde::Deserialize;
+
+pub(crate) struct FromKeyInput {
+    pub(crate) key: String,
+    pub(crate) path: FilePath,
+}
+
+impl AnnexInput for FromKeyInput {
+    type Error = std::io::Error;
+
+    fn for_input(&self) -> Result<Bytes, Self::Error> {
+        Ok(Bytes::from(format!("{} {}", self.key, self.path)))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub(crate) struct FromKeyOutput {
+    #[serde(flatten)]
+    pub(crate) action: Action,
+    #[serde(flatten)]
+    pub(crate) result: AnnexResult,
+}
+
+impl FromKeyOutput {
+    pub(crate) fn check(self) -> Result<Self, AnnexError> {
+        if self.result.success {
+            Ok(self)
+        } else {
+            Err(AnnexError(self.result.error_messages))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_fromkey_output_success() {
+        let s = r#"{"command":"fromkey","error-messages":[],"file":"copy/file.txt","input":["SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt copy/file.txt"],"success":true}"#;
+        let parsed = serde_json::from_str::<FromKeyOutput>(s).unwrap();
+        assert_eq!(
+            parsed,
+            FromKeyOutput {
+                action: Action {
+                    command: String::from("fromkey"),
+                    file: Some(FilePath::try_from("copy/file.txt").unwrap()),
+                    input: vec![String::from("SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt copy/file.txt")],
+                },
+                result: AnnexResult {
+                    success: true,
+                    error_messages: Vec::new(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_load_fromkey_output_failure() {
+        let s = r#"{"command":"fromkey","error-messages":["key (SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt) is not present in backend (use --force to override this sanity check)"],"file":"copy/file.txt","input":["SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt copy/file.txt"],"success":false}"#;
+        let parsed = serde_json::from_str::<FromKeyOutput>(s).unwrap();
+        assert!(parsed.check().is_err());
+    }
+}
@@ -0,0 +1,33 @@
+/// Extract the size in bytes recorded in a git-annex key (e.g., the `12345`
+/// in `SHA256E-s12345--<hash>.txt`), if any
+pub(crate) fn key_size(key: &str) -> Option<u64> {
+    let (fields, _) = key.split_once("--")?;
+    fields
+        .split('-')
+        .skip(1)
+        .find_map(|f| f.strip_prefix('s'))
+        .and_then(|s| s.parse::<u64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        "SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt",
+        Some(19)
+    )]
+    #[case("MD5E-s3405224--dd15380fc1b27858f647a30cc2399a52.pdf", Some(3405224))]
+    #[case(
+        "SHA256-s0-m1234--e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        Some(0)
+    )]
+    #[case("URL--https&c%%example.com%foo.txt", None)]
+    #[case("WORM-m1666048413--file.txt", None)]
+    #[case("nonsense", None)]
+    fn test_key_size(#[case] key: &str, #[case] size: Option<u64>) {
+        assert_eq!(key_size(key), size);
+    }
+}
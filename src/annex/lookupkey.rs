@@ -0,0 +1,58 @@
+use super::AnnexInput;
+use crate::filepath::FilePath;
+use bytes::{Bytes, BytesMut};
+use std::io;
+use std::pin::Pin;
+use tokio_serde::Deserializer;
+
+pub(crate) struct LookupKeyInput(pub(crate) FilePath);
+
+impl AnnexInput for LookupKeyInput {
+    type Error = io::Error;
+
+    fn for_input(&self) -> Result<Bytes, Self::Error> {
+        Ok(Bytes::from(self.0.to_string()))
+    }
+}
+
+/// Decoder for the output of `git-annex lookupkey --batch`, which is not
+/// JSON but rather one line per input path containing either the path's key
+/// or nothing if the path is not an annexed file
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct LookupKeyDecoder;
+
+impl Deserializer<Option<String>> for LookupKeyDecoder {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<Option<String>, io::Error> {
+        let line = std::str::from_utf8(src)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .trim();
+        Ok((!line.is_empty()).then(|| line.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        "SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt",
+        Some("SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt")
+    )]
+    #[case("", None)]
+    fn test_decode_lookupkey_output(#[case] line: &str, #[case] key: Option<&str>) {
+        let mut decoder = LookupKeyDecoder;
+        let r = Pin::new(&mut decoder).deserialize(&BytesMut::from(line));
+        assert_eq!(r.unwrap().as_deref(), key);
+    }
+
+    #[test]
+    fn test_decode_lookupkey_output_invalid() {
+        let mut decoder = LookupKeyDecoder;
+        let r = Pin::new(&mut decoder).deserialize(&BytesMut::from(&b"\xFF\xFE"[..]));
+        assert!(r.is_err());
+    }
+}
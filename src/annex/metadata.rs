@@ -10,6 +10,9 @@ pub(crate) struct MetadataInput {
     // files (e.g., on crippled Windows filesystems) when addurl has not yet
     // exited.
     pub(crate) key: String,
+    /// The fields to set.  If empty, the key's current metadata is output
+    /// without changing anything.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub(crate) fields: HashMap<String, Vec<String>>,
 }
 
@@ -83,4 +86,14 @@ mod tests {
         let s = r#"{"key":"SHA256E-s14239--c3784aaf20ae0867e2f491504a57a15f19eafafb59ed9faea1cfc5cfbbea2b1b.txt","fields":{"color":["blue"]}}"#.as_bytes();
         assert_eq!(mi.for_input().unwrap(), s);
     }
+
+    #[test]
+    fn test_dump_metadata_input_no_fields() {
+        let mi = MetadataInput {
+            key: "SHA256E-s14239--c3784aaf20ae0867e2f491504a57a15f19eafafb59ed9faea1cfc5cfbbea2b1b.txt".into(),
+            fields: HashMap::new(),
+        };
+        let s = r#"{"key":"SHA256E-s14239--c3784aaf20ae0867e2f491504a57a15f19eafafb59ed9faea1cfc5cfbbea2b1b.txt"}"#.as_bytes();
+        assert_eq!(mi.for_input().unwrap(), s);
+    }
 }
@@ -0,0 +1,364 @@
+use crate::commit::SaveOptions;
+use crate::events::EventSender;
+use crate::{
+    CollisionPolicies, DuplicatePolicy, Event, ExistingPolicy, Gamdam, HostLimits, Jobs,
+    MetadataDefaults, MetadataError, RetryPolicy,
+};
+use patharg::OutputArg;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// A builder for configuring a [`Gamdam`]
+///
+/// Every option defaults to the same value as the corresponding `gamdam`
+/// command-line option.
+///
+/// Options that only concern reading the input (`--input-format`, the column
+/// options, `--path-template`, `--sanitize-paths`, and `--on-bad-metadata`)
+/// are not part of the builder, as a `Gamdam` is handed items that have
+/// already been parsed; use [`read_table()`][crate::read_table] or
+/// [`InputItem::complete()`][crate::InputItem::complete] with
+/// [`PathOptions`][crate::PathOptions] and
+/// [`BadMetadataPolicy`][crate::BadMetadataPolicy] instead.  The
+/// `--dry-run` and `--registerurl-only` modes correspond to calling
+/// [`Gamdam::plan()`] and [`Gamdam::register_mirrors()`] in place of the
+/// download methods.
+#[derive(Clone, Debug)]
+pub struct GamdamBuilder {
+    repo: PathBuf,
+    init_repo: bool,
+    addurl_options: Vec<String>,
+    addurl_jobs: Jobs,
+    retry: RetryPolicy,
+    host_limits: HostLimits,
+    on_existing: Option<ExistingPolicy>,
+    duplicates: DuplicatePolicy,
+    collisions: CollisionPolicies,
+    dedupe_urls: bool,
+    drop_mismatched: bool,
+    metadata_only: bool,
+    metadata_defaults: MetadataDefaults,
+    defaults_for_existing: bool,
+    metadata_jobs: NonZeroUsize,
+    registerurl_jobs: NonZeroUsize,
+    journal: Option<PathBuf>,
+    resume: bool,
+    save: bool,
+    save_options: SaveOptions,
+    report: Option<OutputArg>,
+    failures: Option<OutputArg>,
+    events: EventSender,
+}
+
+impl GamdamBuilder {
+    /// Create a builder for a `Gamdam` that downloads into the git-annex
+    /// repository at `repo`
+    pub fn new<P: Into<PathBuf>>(repo: P) -> GamdamBuilder {
+        GamdamBuilder {
+            repo: repo.into(),
+            init_repo: true,
+            addurl_options: Vec::new(),
+            addurl_jobs: Jobs::CPUs,
+            retry: RetryPolicy::default(),
+            host_limits: HostLimits::default(),
+            on_existing: None,
+            duplicates: DuplicatePolicy::default(),
+            collisions: CollisionPolicies::default(),
+            dedupe_urls: false,
+            drop_mismatched: false,
+            metadata_only: false,
+            metadata_defaults: MetadataDefaults::default(),
+            defaults_for_existing: false,
+            metadata_jobs: NonZeroUsize::MIN,
+            registerurl_jobs: NonZeroUsize::MIN,
+            journal: None,
+            resume: false,
+            save: true,
+            save_options: SaveOptions::default(),
+            report: None,
+            failures: None,
+            events: EventSender::default(),
+        }
+    }
+
+    /// If true, create & initialize the repository as with
+    /// [`ensure_annex_repo()`][crate::ensure_annex_repo] at the start of each
+    /// download or URL registration run  [default: true]
+    pub fn init_repo(mut self, flag: bool) -> Self {
+        self.init_repo = flag;
+        self
+    }
+
+    /// Set additional options to pass to `git-annex addurl`
+    pub fn addurl_options<I, S>(mut self, options: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.addurl_options = options.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the number of jobs for `git-annex addurl` to use  [default: one
+    /// per CPU]
+    pub fn jobs(mut self, jobs: Jobs) -> Self {
+        self.addurl_jobs = jobs;
+        self
+    }
+
+    /// Set how to retry downloads that fail for transient reasons
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Set the limits on downloads from each host
+    pub fn host_limits(mut self, limits: HostLimits) -> Self {
+        self.host_limits = limits;
+        self
+    }
+
+    /// Set what to do with items whose paths already exist in the repository.
+    /// If this is not set, the items are passed to `git-annex addurl` as
+    /// usual, which registers the URL for an existing annexed file (failing
+    /// if the sizes don't match).
+    pub fn on_existing(mut self, policy: ExistingPolicy) -> Self {
+        self.on_existing = Some(policy);
+        self
+    }
+
+    /// Set what to do with items that have the same path as an earlier item
+    /// in the run  [default: first wins]
+    pub fn duplicates(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicates = policy;
+        self
+    }
+
+    /// Check the input for paths that collide on case-insensitive or
+    /// normalizing filesystems, or that are used as both files and
+    /// directories, before downloading anything.  With any policy set, the
+    /// entire input is read before the first download starts.
+    pub fn collisions(mut self, policies: CollisionPolicies) -> Self {
+        self.collisions = policies;
+        self
+    }
+
+    /// If true, download each distinct URL in the input only once, and point
+    /// the paths of any other items with the same URL at the downloaded key
+    /// via `git-annex fromkey`  [default: false]
+    pub fn dedupe_urls(mut self, flag: bool) -> Self {
+        self.dedupe_urls = flag;
+        self
+    }
+
+    /// If true, remove files whose content doesn't match the size &
+    /// checksums given for their items from the repository and drop their
+    /// content  [default: false]
+    pub fn drop_mismatched(mut self, flag: bool) -> Self {
+        self.drop_mismatched = flag;
+        self
+    }
+
+    /// If true, don't download anything; instead, look up the keys of the
+    /// files already at the items' paths and set their metadata & register
+    /// their extra URLs.  Items whose paths are not annexed files are failed.
+    /// [default: false]
+    pub fn metadata_only(mut self, flag: bool) -> Self {
+        self.metadata_only = flag;
+        self
+    }
+
+    /// Set metadata fields to add to every downloaded item that does not
+    /// set the same fields itself, including fields with
+    /// automatically-determined values.  The fields are set along with the
+    /// items' own metadata.
+    ///
+    /// Returns an error if [`MetadataDefaults::check()`] fails.
+    pub fn metadata_defaults(mut self, defaults: MetadataDefaults) -> Result<Self, MetadataError> {
+        defaults.check()?;
+        self.metadata_defaults = defaults;
+        Ok(self)
+    }
+
+    /// If true, also add the metadata defaults to items that weren't
+    /// downloaded because their paths already exist (i.e., items whose URLs
+    /// were only registered and, with
+    /// [`metadata_only()`][GamdamBuilder::metadata_only], all items).
+    /// [default: false]
+    pub fn defaults_for_existing(mut self, flag: bool) -> Self {
+        self.defaults_for_existing = flag;
+        self
+    }
+
+    /// Set the number of `git-annex metadata` processes to run in parallel.
+    /// Updates to the same key are always made by the same process, in
+    /// order.  [default: 1]
+    pub fn metadata_jobs(mut self, jobs: NonZeroUsize) -> Self {
+        self.metadata_jobs = jobs;
+        self
+    }
+
+    /// Set the number of `git-annex registerurl` processes to run in
+    /// parallel  [default: 1]
+    pub fn registerurl_jobs(mut self, jobs: NonZeroUsize) -> Self {
+        self.registerurl_jobs = jobs;
+        self
+    }
+
+    /// Append a record of each pipeline stage completed for each item to the
+    /// given file
+    pub fn journal<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.journal = Some(path.into());
+        self.resume = false;
+        self
+    }
+
+    /// Resume an interrupted run using the journal in the given file,
+    /// skipping any stages that it records as completed.  Newly completed
+    /// stages are appended to the same file.
+    pub fn resume<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.journal = Some(path.into());
+        self.resume = true;
+        self
+    }
+
+    /// Set whether to commit the downloaded files  [default: true]
+    pub fn save(mut self, save: bool) -> Self {
+        self.save = save;
+        self
+    }
+
+    /// Set the commit message template.  Occurrences of `{downloaded}` are
+    /// replaced by the number of files downloaded since the previous commit,
+    /// and occurrences of `{total}` are replaced by the number of files
+    /// downloaded so far in the run.  [default: `"Downloaded {downloaded}
+    /// URLs"`]
+    pub fn commit_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.save_options.message = message.into();
+        self
+    }
+
+    /// Set whether to commit if any items failed  [default: true]
+    pub fn save_on_fail(mut self, flag: bool) -> Self {
+        self.save_options.save_on_fail = flag;
+        self
+    }
+
+    /// In addition to committing at the end of the run, commit after every
+    /// `n` files are successfully downloaded
+    pub fn commit_every(mut self, n: NonZeroUsize) -> Self {
+        self.save_options.every_files = Some(n);
+        self
+    }
+
+    /// In addition to committing at the end of the run, commit any files
+    /// downloaded since the previous commit at the given interval
+    pub fn commit_interval(mut self, interval: Duration) -> Self {
+        self.save_options.every_interval = Some(interval);
+        self
+    }
+
+    /// Write a JSON Lines report with one record per input item to the given
+    /// file once the run is done.  With [`Gamdam::plan()`], the plan is
+    /// written instead.
+    pub fn report<P: Into<OutputArg>>(mut self, path: P) -> Self {
+        self.report = Some(path.into());
+        self
+    }
+
+    /// Write the input records of any failed or unprocessed items to the
+    /// given file once the run is done
+    pub fn failures<P: Into<OutputArg>>(mut self, path: P) -> Self {
+        self.failures = Some(path.into());
+        self
+    }
+
+    /// Send an [`Event`] to `sender` for each notable occurrence during a
+    /// run.  The channel is unbounded so that a slow receiver never holds up
+    /// the downloads; events are discarded once the receiver is dropped.
+    pub fn events(mut self, sender: UnboundedSender<Event>) -> Self {
+        self.events = EventSender::new(sender);
+        self
+    }
+
+    pub fn build(self) -> Gamdam {
+        Gamdam {
+            repo: self.repo,
+            init_repo: self.init_repo,
+            addurl_options: self.addurl_options,
+            addurl_jobs: self.addurl_jobs,
+            retry: self.retry,
+            host_limits: self.host_limits,
+            on_existing: self.on_existing,
+            duplicates: self.duplicates,
+            collisions: self.collisions,
+            dedupe_urls: self.dedupe_urls,
+            drop_mismatched: self.drop_mismatched,
+            metadata_only: self.metadata_only,
+            metadata_defaults: self.metadata_defaults,
+            defaults_for_existing: self.defaults_for_existing,
+            metadata_jobs: self.metadata_jobs,
+            registerurl_jobs: self.registerurl_jobs,
+            journal: self.journal,
+            resume: self.resume,
+            save: self.save.then_some(self.save_options),
+            report: self.report,
+            failures: self.failures,
+            events: self.events,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_defaults() {
+        let gamdam = GamdamBuilder::new("repo").build();
+        assert_eq!(gamdam.repo(), std::path::Path::new("repo"));
+        assert!(gamdam.init_repo);
+        assert_eq!(gamdam.addurl_jobs, Jobs::CPUs);
+        assert_eq!(gamdam.metadata_jobs, NonZeroUsize::MIN);
+        assert_eq!(gamdam.registerurl_jobs, NonZeroUsize::MIN);
+        assert_eq!(gamdam.save, Some(SaveOptions::default()));
+        assert!(gamdam.journal.is_none());
+        assert!(gamdam.report.is_none());
+    }
+
+    #[test]
+    fn test_build_options() {
+        let gamdam = GamdamBuilder::new("repo")
+            .addurl_options(["--fast"])
+            .resume("journal.jsonl")
+            .commit_message("Got {downloaded}")
+            .save_on_fail(false)
+            .commit_every(NonZeroUsize::new(10).unwrap())
+            .failures("-")
+            .metadata_jobs(NonZeroUsize::new(4).unwrap())
+            .build();
+        assert_eq!(gamdam.addurl_options, ["--fast"]);
+        assert_eq!(gamdam.journal, Some(PathBuf::from("journal.jsonl")));
+        assert!(gamdam.resume);
+        assert_eq!(
+            gamdam.save,
+            Some(SaveOptions {
+                message: "Got {downloaded}".into(),
+                save_on_fail: false,
+                every_files: NonZeroUsize::new(10),
+                every_interval: None,
+            })
+        );
+        assert_eq!(gamdam.failures, Some(OutputArg::Stdout));
+        assert_eq!(gamdam.metadata_jobs, NonZeroUsize::new(4).unwrap());
+        assert_eq!(GamdamBuilder::new("repo").save(false).build().save, None);
+        assert!(
+            !GamdamBuilder::new("repo")
+                .init_repo(false)
+                .build()
+                .init_repo
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use crate::cmd::LoggedCommand;
+use crate::{Downloadable, FilePath};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+/// A class of collision between paths that are distinct as strings but may
+/// refer to the same file on some filesystems
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CollisionKind {
+    /// The paths differ only in letter case, as on case-insensitive
+    /// filesystems (the default on macOS and Windows)
+    Case,
+    /// The paths differ only in Unicode normalization form (e.g., NFC vs.
+    /// NFD), as on filesystems that normalize filenames
+    Normalization,
+    /// The same path is used both as a file and as a directory
+    FileDirectory,
+}
+
+impl CollisionKind {
+    const NAMES: [(CollisionKind, &'static str); 3] = [
+        (CollisionKind::Case, "case"),
+        (CollisionKind::Normalization, "normalization"),
+        (CollisionKind::FileDirectory, "file-directory"),
+    ];
+}
+
+impl fmt::Display for CollisionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Self::NAMES
+            .iter()
+            .find_map(|&(k, name)| (k == *self).then_some(name))
+            .unwrap_or_default();
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for CollisionKind {
+    type Err = ParseCollisionError;
+
+    fn from_str(s: &str) -> Result<CollisionKind, ParseCollisionError> {
+        Self::NAMES
+            .iter()
+            .find_map(|&(k, name)| name.eq_ignore_ascii_case(s).then_some(k))
+            .ok_or_else(|| ParseCollisionError::Kind(s.into()))
+    }
+}
+
+/// What to do about an input item whose path collides with an earlier path
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CollisionPolicy {
+    /// Log a warning and download the item as-is
+    Warn,
+    /// Refuse to start the run
+    Error,
+    /// Rename the item's path: colliding directory names are respelled to
+    /// match the earlier path, and other colliding names get `-<N>` inserted
+    /// before the file extension
+    Rename,
+}
+
+impl CollisionPolicy {
+    const NAMES: [(CollisionPolicy, &'static str); 3] = [
+        (CollisionPolicy::Warn, "warn"),
+        (CollisionPolicy::Error, "error"),
+        (CollisionPolicy::Rename, "rename"),
+    ];
+}
+
+impl fmt::Display for CollisionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Self::NAMES
+            .iter()
+            .find_map(|&(p, name)| (p == *self).then_some(name))
+            .unwrap_or_default();
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = ParseCollisionError;
+
+    fn from_str(s: &str) -> Result<CollisionPolicy, ParseCollisionError> {
+        Self::NAMES
+            .iter()
+            .find_map(|&(p, name)| name.eq_ignore_ascii_case(s).then_some(p))
+            .ok_or_else(|| ParseCollisionError::Policy(s.into()))
+    }
+}
+
+/// Error returned when parsing an invalid [`CollisionKind`] or
+/// [`CollisionPolicy`]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ParseCollisionError {
+    #[error("invalid collision kind {0:?}; expected one of case, normalization, file-directory")]
+    Kind(String),
+    #[error("invalid collision policy {0:?}; expected one of warn, error, rename")]
+    Policy(String),
+}
+
+/// Which classes of path collisions to check for before a run, and what to
+/// do about each.  Checking requires reading the entire input before
+/// downloading anything, so no checks are done unless a policy is set for at
+/// least one class.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CollisionPolicies {
+    pub case: Option<CollisionPolicy>,
+    pub normalization: Option<CollisionPolicy>,
+    pub file_directory: Option<CollisionPolicy>,
+    /// Also check input paths against the files already committed to the
+    /// repository.  Committed paths are never renamed.
+    pub check_repo: bool,
+}
+
+impl CollisionPolicies {
+    /// Set the policy for `kind`, or for all kinds if `kind` is `None`
+    pub fn set(&mut self, kind: Option<CollisionKind>, policy: CollisionPolicy) {
+        match kind {
+            Some(CollisionKind::Case) => self.case = Some(policy),
+            Some(CollisionKind::Normalization) => self.normalization = Some(policy),
+            Some(CollisionKind::FileDirectory) => self.file_directory = Some(policy),
+            None => {
+                self.case = Some(policy);
+                self.normalization = Some(policy);
+                self.file_directory = Some(policy);
+            }
+        }
+    }
+
+    pub fn get(&self, kind: CollisionKind) -> Option<CollisionPolicy> {
+        match kind {
+            CollisionKind::Case => self.case,
+            CollisionKind::Normalization => self.normalization,
+            CollisionKind::FileDirectory => self.file_directory,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.case.is_some() || self.normalization.is_some() || self.file_directory.is_some()
+    }
+}
+
+/// A collision between an input item's path and an earlier path
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Collision {
+    pub kind: CollisionKind,
+    /// The item's path
+    pub path: FilePath,
+    /// The leading portion of `path` that collides
+    pub prefix: String,
+    /// The earlier path (or leading portion thereof) that `prefix` collides
+    /// with
+    pub other: String,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            CollisionKind::Case => write!(
+                f,
+                "{:?} differs only in case from {:?}",
+                self.prefix, self.other
+            )?,
+            CollisionKind::Normalization => write!(
+                f,
+                "{:?} differs only in Unicode normalization from {:?}",
+                self.prefix, self.other
+            )?,
+            CollisionKind::FileDirectory => {
+                write!(f, "{:?} is used as both a file and a directory", self.other)?;
+            }
+        }
+        if self.prefix != self.path.as_str() {
+            write!(f, " (in {})", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracker of every path and leading directory seen so far, keyed by the
+/// form in which colliding paths compare equal
+#[derive(Clone, Debug)]
+struct Tracker<'a> {
+    policies: &'a CollisionPolicies,
+    /// Map from folded paths to the first spelling seen and whether it was
+    /// a directory
+    seen: HashMap<String, (String, bool)>,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(policies: &'a CollisionPolicies) -> Tracker<'a> {
+        Tracker {
+            policies,
+            seen: HashMap::new(),
+        }
+    }
+
+    fn fold(&self, path: &str) -> String {
+        let mut folded = path.to_owned();
+        if self.policies.normalization.is_some() {
+            folded = folded.nfc().collect();
+        }
+        if self.policies.case.is_some() {
+            folded = folded.to_lowercase();
+        }
+        folded
+    }
+
+    fn record(&mut self, path: &str, is_dir: bool) {
+        self.seen
+            .entry(self.fold(path))
+            .or_insert_with(|| (path.to_owned(), is_dir));
+    }
+
+    /// Record a path that can't be renamed, such as one already in the
+    /// repository
+    fn seed(&mut self, path: &str) {
+        let mut end = 0;
+        while let Some(i) = path[end..].find('/') {
+            end += i;
+            self.record(&path[..end], true);
+            end += 1;
+        }
+        self.record(path, false);
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.seen
+            .get(&self.fold(path))
+            .is_some_and(|(other, was_dir)| other == path && !was_dir)
+    }
+
+    /// Check whether `path` collides with anything seen so far.  Returns the
+    /// kind of collision, the earlier spelling, and whether the earlier path
+    /// was a directory.
+    fn check(&self, path: &str, is_dir: bool) -> Option<(CollisionKind, &str, bool)> {
+        let (other, was_dir) = self.seen.get(&self.fold(path))?;
+        let kind = if *was_dir != is_dir && self.policies.file_directory.is_some() {
+            CollisionKind::FileDirectory
+        } else if other != path {
+            if other.nfc().eq(path.nfc()) {
+                CollisionKind::Normalization
+            } else {
+                CollisionKind::Case
+            }
+        } else {
+            return None;
+        };
+        Some((kind, other, *was_dir))
+    }
+
+    /// Check `dl`'s path against everything seen so far and apply the
+    /// configured policies, returning the first collision whose policy is
+    /// "error", if any
+    fn process(&mut self, dl: &mut Downloadable) -> Option<Collision> {
+        let original = dl.path.clone();
+        let components = original.as_str().split('/').collect::<Vec<_>>();
+        let mut newpath = String::new();
+        let mut error = None;
+        let mut respelled = false;
+        let mut kept = false;
+        for (i, &name) in components.iter().enumerate() {
+            let is_dir = i + 1 < components.len();
+            let join = |name: &str| {
+                if newpath.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{newpath}/{name}")
+                }
+            };
+            let mut candidate = join(name);
+            if respelled && !is_dir && self.is_file(&candidate) {
+                // Respelling the directories turned the path into a duplicate
+                // of an earlier file.
+                let mut n = 1;
+                candidate = loop {
+                    let renamed = join(&with_suffix(name, n, true));
+                    if self.check(&renamed, false).is_none() && !self.is_file(&renamed) {
+                        break renamed;
+                    }
+                    n += 1;
+                };
+            } else if let Some((kind, other, was_dir)) = self.check(&candidate, is_dir) {
+                let collision = Collision {
+                    kind,
+                    path: original.clone(),
+                    prefix: candidate.clone(),
+                    other: other.to_owned(),
+                };
+                match self.policies.get(kind) {
+                    // Collisions in a path below a kept collision follow from
+                    // it and aren't worth reporting separately.
+                    Some(CollisionPolicy::Warn) => {
+                        if !kept {
+                            log::warn!("Path collision: {collision}");
+                        }
+                        kept = true;
+                    }
+                    Some(CollisionPolicy::Error) => {
+                        if error.is_none() {
+                            log::error!("Path collision: {collision}");
+                            error = Some(collision);
+                        }
+                        kept = true;
+                    }
+                    Some(CollisionPolicy::Rename) if is_dir && was_dir => {
+                        candidate = collision.other;
+                        respelled = true;
+                    }
+                    Some(CollisionPolicy::Rename) => {
+                        let mut n = 1;
+                        candidate = loop {
+                            let renamed = join(&with_suffix(name, n, !is_dir));
+                            match self.check(&renamed, is_dir) {
+                                None => break renamed,
+                                Some(_) => n += 1,
+                            }
+                        };
+                    }
+                    None => (),
+                }
+            }
+            self.record(&candidate, is_dir);
+            newpath = candidate;
+        }
+        if newpath != original.as_str() {
+            let path = FilePath::try_from(newpath.as_str())
+                .expect("renamed path should be a valid FilePath");
+            log::info!("Renaming {original} to {path} to avoid path collision");
+            dl.path = path;
+            dl.original_path.get_or_insert_with(|| original.to_string());
+        }
+        error
+    }
+}
+
+/// Returns `name` with `-<n>` inserted before the file extension (if any and
+/// `keep_extension` is true)
+fn with_suffix(name: &str, n: usize, keep_extension: bool) -> String {
+    match name.rfind('.').filter(|&i| i > 0 && keep_extension) {
+        Some(i) => format!("{}-{n}{}", &name[..i], &name[i..]),
+        None => format!("{name}-{n}"),
+    }
+}
+
+/// Check the paths of `items` for collisions with each other and with
+/// `existing` according to `policies`, renaming items as configured.
+/// Returns each item paired with the first collision found for it whose
+/// policy is "error", if any.
+pub(crate) fn resolve_collisions(
+    items: Vec<Downloadable>,
+    existing: &[String],
+    policies: &CollisionPolicies,
+) -> Vec<(Downloadable, Option<Collision>)> {
+    let mut tracker = Tracker::new(policies);
+    for path in existing {
+        tracker.seed(path);
+    }
+    items
+        .into_iter()
+        .map(|mut dl| {
+            let error = tracker.process(&mut dl);
+            (dl, error)
+        })
+        .collect()
+}
+
+/// List the files committed to the git repository at `repo`.  If `repo`
+/// does not exist, an empty list is returned.
+pub(crate) async fn committed_files(repo: &Path) -> Result<Vec<String>, anyhow::Error> {
+    if !repo.exists() {
+        return Ok(Vec::new());
+    }
+    let output = LoggedCommand::new("git", ["ls-files", "-z"], repo)
+        .check_output()
+        .await?;
+    Ok(output
+        .split('\0')
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExpectedContent;
+    use rstest::rstest;
+    use url::Url;
+
+    fn dl(path: &str) -> Downloadable {
+        Downloadable {
+            path: FilePath::try_from(path).unwrap(),
+            url: Url::parse("https://example.com/file").unwrap(),
+            metadata: HashMap::new(),
+            metadata_ops: HashMap::new(),
+            extra_urls: Vec::new(),
+            on_existing: None,
+            original_path: None,
+            expected: ExpectedContent::default(),
+        }
+    }
+
+    fn all(policy: CollisionPolicy) -> CollisionPolicies {
+        let mut policies = CollisionPolicies::default();
+        policies.set(None, policy);
+        policies
+    }
+
+    fn paths(items: &[(Downloadable, Option<Collision>)]) -> Vec<&str> {
+        items.iter().map(|(dl, _)| dl.path.as_str()).collect()
+    }
+
+    #[rstest]
+    #[case(&["Docs/a.pdf", "docs/a.pdf"], &["Docs/a.pdf", "Docs/a-1.pdf"])]
+    #[case(&["Docs/a.pdf", "docs/b.pdf"], &["Docs/a.pdf", "Docs/b.pdf"])]
+    #[case(&["caf\u{e9}.txt", "cafe\u{301}.txt"], &["caf\u{e9}.txt", "cafe\u{301}-1.txt"])]
+    #[case(&["a", "a/b", "a/c"], &["a", "a-1/b", "a-1/c"])]
+    #[case(&["a/b", "a"], &["a/b", "a-1"])]
+    #[case(&["README", "readme", "Readme"], &["README", "readme-1", "Readme-2"])]
+    #[case(&["x/y.txt", "x/y.txt"], &["x/y.txt", "x/y.txt"])]
+    fn test_rename(#[case] input: &[&str], #[case] output: &[&str]) {
+        let items = resolve_collisions(
+            input.iter().map(|p| dl(p)).collect(),
+            &[],
+            &all(CollisionPolicy::Rename),
+        );
+        assert_eq!(paths(&items), output);
+        assert!(items.iter().all(|(_, e)| e.is_none()));
+        for ((dl, _), orig) in items.iter().zip(input) {
+            let expected = (dl.path.as_str() != *orig).then(|| String::from(*orig));
+            assert_eq!(dl.original_path, expected);
+        }
+    }
+
+    #[test]
+    fn test_error_and_warn() {
+        let policies = CollisionPolicies {
+            case: Some(CollisionPolicy::Error),
+            normalization: None,
+            file_directory: Some(CollisionPolicy::Warn),
+            check_repo: false,
+        };
+        let items = resolve_collisions(
+            vec![
+                dl("Docs/a.pdf"),
+                dl("docs/a.pdf"),
+                dl("Docs"),
+                dl("caf\u{e9}"),
+                dl("cafe\u{301}"),
+            ],
+            &[],
+            &policies,
+        );
+        assert_eq!(
+            paths(&items),
+            [
+                "Docs/a.pdf",
+                "docs/a.pdf",
+                "Docs",
+                "caf\u{e9}",
+                "cafe\u{301}"
+            ]
+        );
+        let errors = items.iter().map(|(_, e)| e.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                None,
+                Some(Collision {
+                    kind: CollisionKind::Case,
+                    path: FilePath::try_from("docs/a.pdf").unwrap(),
+                    prefix: "docs".into(),
+                    other: "Docs".into(),
+                }),
+                None,
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_existing() {
+        let items = resolve_collisions(
+            vec![dl("Data/x.csv"), dl("notes.txt/y")],
+            &["data/x.csv".into(), "notes.txt".into()],
+            &all(CollisionPolicy::Rename),
+        );
+        assert_eq!(paths(&items), ["data/x-1.csv", "notes.txt-1/y"]);
+    }
+
+    #[test]
+    fn test_display() {
+        let collision = Collision {
+            kind: CollisionKind::Case,
+            path: FilePath::try_from("docs/a.pdf").unwrap(),
+            prefix: "docs".into(),
+            other: "Docs".into(),
+        };
+        assert_eq!(
+            collision.to_string(),
+            r#""docs" differs only in case from "Docs" (in docs/a.pdf)"#
+        );
+    }
+
+    #[rstest]
+    #[case("warn", Ok(CollisionPolicy::Warn))]
+    #[case("Rename", Ok(CollisionPolicy::Rename))]
+    #[case("skip", Err(ParseCollisionError::Policy("skip".into())))]
+    fn test_parse_policy(#[case] s: &str, #[case] r: Result<CollisionPolicy, ParseCollisionError>) {
+        assert_eq!(s.parse::<CollisionPolicy>(), r);
+    }
+}
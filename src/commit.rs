@@ -0,0 +1,318 @@
+use crate::cmd::{CommandError, LoggedCommand};
+use crate::events::EventSender;
+use crate::{quantify, Event, Report};
+use std::future::pending;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::{interval_at, sleep, Instant, Interval, MissedTickBehavior};
+
+/// Settings for committing the downloaded files
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct SaveOptions {
+    /// Commit message template; see [`format_commit_message()`]
+    pub(crate) message: String,
+    /// If false, don't commit once any item has failed
+    pub(crate) save_on_fail: bool,
+    /// Also commit after every this many files are successfully downloaded
+    pub(crate) every_files: Option<NonZeroUsize>,
+    /// Also commit at this interval if any files have been downloaded since
+    /// the last commit
+    pub(crate) every_interval: Option<Duration>,
+}
+
+impl Default for SaveOptions {
+    fn default() -> SaveOptions {
+        SaveOptions {
+            message: String::from(SaveOptions::DEFAULT_MESSAGE),
+            save_on_fail: true,
+            every_files: None,
+            every_interval: None,
+        }
+    }
+}
+
+impl SaveOptions {
+    pub(crate) const DEFAULT_MESSAGE: &'static str = "Downloaded {downloaded} URLs";
+}
+
+/// Fill in a commit message template.  Occurrences of `{downloaded}` are
+/// replaced by `downloaded`, the number of files downloaded since the last
+/// commit, and occurrences of `{total}` are replaced by `total`, the number
+/// of files downloaded so far in the run.
+pub(crate) fn format_commit_message(template: &str, downloaded: usize, total: usize) -> String {
+    template
+        .replace("{downloaded}", &downloaded.to_string())
+        .replace("{total}", &total.to_string())
+}
+
+/// How many times to try committing while another process (such as
+/// `git-annex addurl` staging a file) holds the index lock
+const COMMIT_ATTEMPTS: u32 = 10;
+
+/// How long to wait between checks for the index lock to be released
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Commit any staged changes in `repo` with the given message.  Returns
+/// `false` if there was nothing to commit.
+///
+/// As the commit may be made while git-annex is still staging files, the
+/// commit is held off while another process holds the index lock, and it is
+/// retried if it fails while the lock is held.
+pub(crate) async fn commit_staged<P: AsRef<Path> + Send>(
+    repo: P,
+    message: &str,
+) -> Result<bool, CommandError> {
+    let repo = repo.as_ref();
+    let lock = index_lock(repo).await;
+    let mut attempt = 1;
+    loop {
+        if let Some(ref lock) = lock {
+            wait_for_unlock(lock).await;
+        }
+        match try_commit_staged(repo, message).await {
+            Err(CommandError::Exit { .. })
+                if attempt < COMMIT_ATTEMPTS && lock.as_ref().is_some_and(|p| p.exists()) =>
+            {
+                log::debug!("Git index is locked by another process; retrying commit");
+                attempt += 1;
+            }
+            r => return r,
+        }
+    }
+}
+
+async fn try_commit_staged(repo: &Path, message: &str) -> Result<bool, CommandError> {
+    match LoggedCommand::new("git", ["diff", "--cached", "--quiet"], repo)
+        .status()
+        .await
+    {
+        Err(CommandError::Exit { .. }) => {
+            LoggedCommand::new("git", ["commit", "-m", message], repo)
+                .status()
+                .await?;
+            Ok(true)
+        }
+        Ok(()) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the path to the index lock file of the Git repository at `repo`,
+/// or `None` if it can't be determined
+async fn index_lock(repo: &Path) -> Option<PathBuf> {
+    match LoggedCommand::new("git", ["rev-parse", "--git-path", "index.lock"], repo)
+        .check_output()
+        .await
+    {
+        Ok(s) => Some(repo.join(s.trim_end_matches(['\r', '\n']))),
+        Err(e) => {
+            log::debug!("Could not determine location of Git index lock: {e}");
+            None
+        }
+    }
+}
+
+/// Wait until nothing exists at `lock`, giving up after
+/// [`COMMIT_ATTEMPTS`] checks
+async fn wait_for_unlock(lock: &Path) {
+    for _ in 0..COMMIT_ATTEMPTS {
+        if !lock.exists() {
+            return;
+        }
+        sleep(LOCK_POLL_INTERVAL).await;
+    }
+}
+
+/// Commit the files downloaded in a finished run, unless nothing succeeded
+/// or (depending on `options`) something failed
+pub(crate) async fn save_run(
+    repo: &Path,
+    options: &SaveOptions,
+    report: &Report,
+    events: &EventSender,
+) -> Result<(), CommandError> {
+    if report.successful.is_empty() || (!options.save_on_fail && !report.failed.is_empty()) {
+        return Ok(());
+    }
+    let total = report.successful.iter().filter(|r| r.downloaded()).count();
+    let message = format_commit_message(&options.message, total - report.committed, total);
+    if commit_staged(repo, &message).await? {
+        events.emit(Event::Committed {
+            downloaded: total - report.committed,
+            total,
+        });
+    } else {
+        // This can happen if we only downloaded files that were already
+        // present in the repo.
+        log::info!("Nothing to commit");
+    }
+    Ok(())
+}
+
+/// Tracks downloads since the last commit and makes periodic commits
+/// according to a [`SaveOptions`]
+#[derive(Debug)]
+pub(crate) struct BatchCommitter<'a> {
+    repo: &'a Path,
+    config: Option<&'a SaveOptions>,
+    events: &'a EventSender,
+    interval: Option<Interval>,
+    pending: usize,
+    committed: usize,
+    failed: bool,
+}
+
+impl<'a> BatchCommitter<'a> {
+    pub(crate) fn new(
+        repo: &'a Path,
+        config: Option<&'a SaveOptions>,
+        events: &'a EventSender,
+    ) -> Self {
+        let interval = config.and_then(|c| c.every_interval).map(|period| {
+            let mut iv = interval_at(Instant::now() + period, period);
+            iv.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            iv
+        });
+        BatchCommitter {
+            repo,
+            config,
+            events,
+            interval,
+            pending: 0,
+            committed: 0,
+            failed: false,
+        }
+    }
+
+    /// Number of downloaded files included in periodic commits so far
+    pub(crate) fn committed(&self) -> usize {
+        self.committed
+    }
+
+    /// Record that a file was downloaded, committing if a batch is complete
+    pub(crate) async fn downloaded(&mut self) {
+        self.pending += 1;
+        if self
+            .config
+            .and_then(|c| c.every_files)
+            .is_some_and(|n| self.pending >= n.get())
+        {
+            self.commit().await;
+        }
+    }
+
+    /// Record that an item failed
+    pub(crate) fn failed(&mut self) {
+        self.failed = true;
+    }
+
+    /// Wait until it's time for an interval-based commit.  Never returns if
+    /// there is no commit interval.
+    pub(crate) async fn tick(&mut self) {
+        match self.interval {
+            Some(ref mut iv) => {
+                iv.tick().await;
+            }
+            None => pending().await,
+        }
+    }
+
+    /// Commit the files downloaded since the last commit, if any.  Failure to
+    /// commit is logged but is otherwise not an error, as the files will be
+    /// included in a later commit.
+    pub(crate) async fn commit(&mut self) {
+        let Some(config) = self.config else {
+            return;
+        };
+        if self.pending == 0 || (self.failed && !config.save_on_fail) {
+            return;
+        }
+        let total = self.committed + self.pending;
+        let message = format_commit_message(&config.message, self.pending, total);
+        match commit_staged(self.repo, &message).await {
+            Ok(true) => {
+                log::info!(
+                    "Committed {} ({} so far)",
+                    quantify(self.pending, "downloaded file"),
+                    total
+                );
+                self.events.emit(Event::Committed {
+                    downloaded: self.pending,
+                    total,
+                });
+                self.committed = total;
+                self.pending = 0;
+            }
+            // This can happen if we only downloaded files that were already
+            // present in the repo.  The files stay pending so that they're
+            // counted by whichever commit does include them.
+            Ok(false) => log::debug!("Nothing to commit"),
+            Err(e) => log::warn!("Periodic commit failed; will try again later: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    async fn git(repo: &Path, args: &[&str]) {
+        LoggedCommand::new("git", args, repo)
+            .status()
+            .await
+            .unwrap();
+    }
+
+    async fn init_repo(repo: &Path) {
+        git(repo, &["init", "-q"]).await;
+        git(repo, &["config", "user.name", "Test"]).await;
+        git(repo, &["config", "user.email", "test@example.com"]).await;
+    }
+
+    #[test]
+    fn test_format_commit_message() {
+        assert_eq!(
+            format_commit_message("Downloaded {downloaded} URLs ({total} total)", 5, 15),
+            "Downloaded 5 URLs (15 total)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_committer_nothing_to_commit() {
+        let tmpdir = tempdir().unwrap();
+        let repo = tmpdir.path();
+        init_repo(repo).await;
+        let config = SaveOptions {
+            every_files: NonZeroUsize::new(1),
+            ..SaveOptions::default()
+        };
+        let events = EventSender::default();
+        let mut committer = BatchCommitter::new(repo, Some(&config), &events);
+        // A "downloaded" file that was already committed stages nothing.
+        committer.downloaded().await;
+        assert_eq!(committer.committed(), 0);
+        std::fs::write(repo.join("foo.txt"), "foo\n").unwrap();
+        git(repo, &["add", "foo.txt"]).await;
+        committer.downloaded().await;
+        assert_eq!(committer.committed(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_commit_waits_for_index_lock() {
+        let tmpdir = tempdir().unwrap();
+        let repo = tmpdir.path();
+        init_repo(repo).await;
+        std::fs::write(repo.join("foo.txt"), "foo\n").unwrap();
+        git(repo, &["add", "foo.txt"]).await;
+        let lock = repo.join(".git").join("index.lock");
+        std::fs::write(&lock, "").unwrap();
+        let unlock = async {
+            sleep(Duration::from_millis(300)).await;
+            std::fs::remove_file(&lock).unwrap();
+        };
+        let (r, ()) = tokio::join!(commit_staged(repo, "Add foo"), unlock);
+        assert!(r.unwrap());
+    }
+}
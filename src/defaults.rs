@@ -0,0 +1,263 @@
+use crate::{check_field_name, Downloadable, MetadataError};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Metadata added to every item in a run that does not set the same fields
+/// itself, either in `metadata` or `metadata_ops`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MetadataDefaults {
+    /// Fields with fixed values
+    pub fields: HashMap<String, Vec<String>>,
+    /// Fields whose values are filled in automatically for each item
+    pub automatic: Vec<AutoField>,
+    /// The name of the input file, used for [`AutoValue::InputFile`] fields.
+    /// If this is `None`, such fields are not set.
+    pub input_file: Option<String>,
+}
+
+impl MetadataDefaults {
+    /// Returns true if no fields are configured
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.automatic.is_empty()
+    }
+
+    /// Check the names of the fixed & automatic fields with
+    /// [`check_field_name()`], along with the fixed values, which cannot be
+    /// empty
+    pub fn check(&self) -> Result<(), MetadataError> {
+        for (field, values) in &self.fields {
+            check_field_name(field)?;
+            if values.iter().any(String::is_empty) {
+                return Err(MetadataError::EmptyValue(field.clone()));
+            }
+        }
+        for auto in &self.automatic {
+            check_field_name(&auto.field)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the metadata of `dl` with the default & automatic fields
+    /// added, skipping any fields that `dl` already sets or operates on.
+    /// `downloaded` is when the item's URL finished downloading, if it was
+    /// downloaded during this run.  `dl` itself is left as it was in the
+    /// input.
+    pub(crate) fn merged(
+        &self,
+        dl: &Downloadable,
+        downloaded: Option<DateTime<Utc>>,
+    ) -> HashMap<String, Vec<String>> {
+        let mut metadata = dl.metadata.clone();
+        let mut defaults = self.fields.clone();
+        for auto in &self.automatic {
+            let value = match auto.value {
+                AutoValue::Downloaded => {
+                    downloaded.map(|dt| dt.to_rfc3339_opts(SecondsFormat::Secs, true))
+                }
+                AutoValue::SourceUrl => Some(dl.url.to_string()),
+                AutoValue::GamdamVersion => Some(String::from(env!("CARGO_PKG_VERSION"))),
+                AutoValue::InputFile => self.input_file.clone(),
+            };
+            if let Some(value) = value {
+                defaults.insert(auto.field.clone(), vec![value]);
+            }
+        }
+        for (field, values) in defaults {
+            if !dl.metadata_ops.contains_key(&field) {
+                metadata.entry(field).or_insert(values);
+            }
+        }
+        metadata
+    }
+}
+
+/// A metadata field that is set to an automatically-determined value
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct AutoField {
+    /// The name of the field
+    pub field: String,
+    /// The value to set the field to
+    pub value: AutoValue,
+}
+
+impl AutoField {
+    /// Returns an `AutoField` for `value` whose field is named after the
+    /// value's kind
+    pub fn new(value: AutoValue) -> AutoField {
+        AutoField {
+            field: value.to_string(),
+            value,
+        }
+    }
+}
+
+impl FromStr for AutoField {
+    type Err = ParseAutoFieldError;
+
+    /// Parse a string of the form `KIND[=FIELD]`
+    fn from_str(s: &str) -> Result<AutoField, ParseAutoFieldError> {
+        match s.split_once('=') {
+            Some((kind, field)) => {
+                check_field_name(field)?;
+                Ok(AutoField {
+                    field: field.into(),
+                    value: kind.parse()?,
+                })
+            }
+            None => Ok(AutoField::new(s.parse()?)),
+        }
+    }
+}
+
+/// The kinds of automatically-determined metadata values
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AutoValue {
+    /// The time at which the item's URL finished downloading, in RFC 3339
+    /// format.  Not set for items that were not downloaded during the run.
+    Downloaded,
+    /// The item's URL
+    SourceUrl,
+    /// The version of gamdam performing the run
+    GamdamVersion,
+    /// The name of the input file
+    InputFile,
+}
+
+impl AutoValue {
+    const NAMES: [(AutoValue, &'static str); 4] = [
+        (AutoValue::Downloaded, "downloaded"),
+        (AutoValue::SourceUrl, "source-url"),
+        (AutoValue::GamdamVersion, "gamdam-version"),
+        (AutoValue::InputFile, "input-file"),
+    ];
+}
+
+impl fmt::Display for AutoValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Self::NAMES
+            .iter()
+            .find_map(|&(v, name)| (v == *self).then_some(name))
+            .unwrap_or_default();
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for AutoValue {
+    type Err = ParseAutoFieldError;
+
+    fn from_str(s: &str) -> Result<AutoValue, ParseAutoFieldError> {
+        Self::NAMES
+            .iter()
+            .find_map(|&(v, name)| name.eq_ignore_ascii_case(s).then_some(v))
+            .ok_or_else(|| ParseAutoFieldError::Kind(s.into()))
+    }
+}
+
+/// Error returned when parsing an invalid [`AutoField`] or [`AutoValue`]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ParseAutoFieldError {
+    #[error("invalid automatic metadata kind {0:?}; expected one of downloaded, source-url, gamdam-version, input-file")]
+    Kind(String),
+    #[error(transparent)]
+    Field(#[from] MetadataError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ExpectedContent, FilePath, MetadataOp};
+    use chrono::TimeZone;
+    use rstest::rstest;
+    use url::Url;
+
+    #[rstest]
+    #[case("source-url", Ok(AutoField::new(AutoValue::SourceUrl)))]
+    #[case("Downloaded=fetched", Ok(AutoField {field: "fetched".into(), value: AutoValue::Downloaded}))]
+    #[case(
+        "input-file=",
+        Err(ParseAutoFieldError::Field(MetadataError::EmptyField))
+    )]
+    #[case("input-file=in file", Err(ParseAutoFieldError::Field(MetadataError::BadChar {field: "in file".into(), c: ' '})))]
+    #[case("hostname", Err(ParseAutoFieldError::Kind("hostname".into())))]
+    fn test_parse_auto_field(#[case] s: &str, #[case] r: Result<AutoField, ParseAutoFieldError>) {
+        assert_eq!(s.parse::<AutoField>(), r);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut defaults = MetadataDefaults {
+            fields: HashMap::from([("source".into(), vec!["crawl".into()])]),
+            automatic: vec![AutoField::new(AutoValue::SourceUrl)],
+            input_file: None,
+        };
+        assert_eq!(defaults.check(), Ok(()));
+        defaults.automatic.push(AutoField {
+            field: "-url".into(),
+            value: AutoValue::SourceUrl,
+        });
+        assert_eq!(
+            defaults.check(),
+            Err(MetadataError::BadStart("-url".into()))
+        );
+        defaults.automatic.pop();
+        defaults.fields.insert("empty".into(), vec![String::new()]);
+        assert_eq!(
+            defaults.check(),
+            Err(MetadataError::EmptyValue("empty".into()))
+        );
+    }
+
+    #[test]
+    fn test_merged() {
+        let defaults = MetadataDefaults {
+            fields: HashMap::from([
+                ("source".into(), vec!["crawl-2026-10".into()]),
+                ("collector".into(), vec!["bot".into()]),
+                ("tags".into(), vec!["crawled".into()]),
+            ]),
+            automatic: vec![
+                AutoField::new(AutoValue::Downloaded),
+                AutoField::new(AutoValue::SourceUrl),
+                AutoField {
+                    field: "infile".into(),
+                    value: AutoValue::InputFile,
+                },
+            ],
+            input_file: Some("batch.jsonl".into()),
+        };
+        let dl = Downloadable {
+            path: FilePath::try_from("a.txt").unwrap(),
+            url: Url::parse("https://example.com/a.txt").unwrap(),
+            metadata: HashMap::from([("collector".into(), vec!["alice".into()])]),
+            metadata_ops: HashMap::from([("tags".into(), MetadataOp::Add(vec!["x".into()]))]),
+            extra_urls: Vec::new(),
+            on_existing: None,
+            original_path: None,
+            expected: ExpectedContent::default(),
+        };
+        let metadata = defaults.merged(
+            &dl,
+            Some(Utc.with_ymd_and_hms(2026, 10, 16, 12, 0, 0).unwrap()),
+        );
+        assert_eq!(
+            dl.metadata,
+            HashMap::from([("collector".into(), vec!["alice".into()])])
+        );
+        assert_eq!(
+            metadata,
+            HashMap::from([
+                ("source".into(), vec!["crawl-2026-10".into()]),
+                ("collector".into(), vec!["alice".into()]),
+                ("downloaded".into(), vec!["2026-10-16T12:00:00Z".into()]),
+                (
+                    "source-url".into(),
+                    vec!["https://example.com/a.txt".into()]
+                ),
+                ("infile".into(), vec!["batch.jsonl".into()]),
+            ])
+        );
+    }
+}
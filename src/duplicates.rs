@@ -0,0 +1,234 @@
+use crate::{Downloadable, FilePath};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// What to do with input items that have the same path as another item in
+/// the run
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Process the first item with the path and discard the rest
+    #[default]
+    FirstWins,
+    /// Process the last item with the path and discard the rest.  Requires
+    /// reading the entire input before the first download starts.
+    LastWins,
+    /// Process the first item with the path after merging into it the
+    /// metadata and URLs of the rest, which are then discarded.  The rest's
+    /// main URLs become extra URLs of the first item.  Requires reading the
+    /// entire input before the first download starts.
+    Merge,
+    /// Process the first item with the path and fail the rest
+    Error,
+}
+
+impl DuplicatePolicy {
+    const NAMES: [(DuplicatePolicy, &'static str); 4] = [
+        (DuplicatePolicy::FirstWins, "first-wins"),
+        (DuplicatePolicy::LastWins, "last-wins"),
+        (DuplicatePolicy::Merge, "merge"),
+        (DuplicatePolicy::Error, "error"),
+    ];
+
+    /// Returns true if applying the policy requires reading the entire input
+    /// first
+    pub(crate) fn needs_whole_input(self) -> bool {
+        matches!(self, DuplicatePolicy::LastWins | DuplicatePolicy::Merge)
+    }
+}
+
+impl fmt::Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Self::NAMES
+            .iter()
+            .find_map(|&(p, name)| (p == *self).then_some(name))
+            .unwrap_or_default();
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = ParseDuplicatePolicyError;
+
+    fn from_str(s: &str) -> Result<DuplicatePolicy, ParseDuplicatePolicyError> {
+        Self::NAMES
+            .iter()
+            .find_map(|&(p, name)| name.eq_ignore_ascii_case(s).then_some(p))
+            .ok_or_else(|| ParseDuplicatePolicyError(s.into()))
+    }
+}
+
+/// Error returned when parsing an invalid [`DuplicatePolicy`]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("invalid duplicate-path policy {0:?}; expected one of first-wins, last-wins, merge, error")]
+pub struct ParseDuplicatePolicyError(String);
+
+/// Apply [`DuplicatePolicy::LastWins`] or [`DuplicatePolicy::Merge`] to
+/// `items`, leaving at most one item per path.  For the other policies,
+/// `items` is returned unchanged, as they are applied while downloading.
+pub(crate) fn dedupe(items: Vec<Downloadable>, policy: DuplicatePolicy) -> Vec<Downloadable> {
+    match policy {
+        DuplicatePolicy::FirstWins | DuplicatePolicy::Error => items,
+        DuplicatePolicy::LastWins => {
+            let mut last = HashMap::new();
+            for (i, dl) in items.iter().enumerate() {
+                last.insert(dl.path.clone(), i);
+            }
+            items
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, dl)| {
+                    if last[&dl.path] == i {
+                        Some(dl)
+                    } else {
+                        log::warn!(
+                            "Multiple entries encountered downloading to {}; discarding all but the last",
+                            dl.path
+                        );
+                        None
+                    }
+                })
+                .collect()
+        }
+        DuplicatePolicy::Merge => {
+            let mut merged: Vec<Downloadable> = Vec::with_capacity(items.len());
+            let mut index = HashMap::<FilePath, usize>::new();
+            for dl in items {
+                match index.entry(dl.path.clone()) {
+                    Entry::Occupied(e) => {
+                        log::info!(
+                            "Multiple entries encountered downloading to {}; merging their metadata and URLs",
+                            dl.path
+                        );
+                        merge_into(&mut merged[*e.get()], dl);
+                    }
+                    Entry::Vacant(e) => {
+                        e.insert(merged.len());
+                        merged.push(dl);
+                    }
+                }
+            }
+            merged
+        }
+    }
+}
+
+/// Add the metadata values, metadata operations, and URLs of `other` that
+/// `dl` lacks to `dl`.  The main URL of `other` becomes an extra URL of `dl`.
+fn merge_into(dl: &mut Downloadable, other: Downloadable) {
+    for (field, values) in other.metadata {
+        let existing = dl.metadata.entry(field).or_default();
+        for v in values {
+            if !existing.contains(&v) {
+                existing.push(v);
+            }
+        }
+    }
+    for (field, op) in other.metadata_ops {
+        dl.metadata_ops.entry(field).or_insert(op);
+    }
+    for url in std::iter::once(other.url).chain(other.extra_urls) {
+        if url != dl.url && !dl.extra_urls.contains(&url) {
+            dl.extra_urls.push(url);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExpectedContent;
+    use rstest::rstest;
+    use url::Url;
+
+    fn dl(path: &str, url: &str) -> Downloadable {
+        Downloadable {
+            path: FilePath::try_from(path).unwrap(),
+            url: Url::parse(url).unwrap(),
+            metadata: HashMap::new(),
+            metadata_ops: HashMap::new(),
+            extra_urls: Vec::new(),
+            on_existing: None,
+            original_path: None,
+            expected: ExpectedContent::default(),
+        }
+    }
+
+    fn sample() -> Vec<Downloadable> {
+        let mut a1 = dl("a.txt", "https://example.com/a1");
+        a1.metadata
+            .insert("tag".into(), vec!["x".into(), "y".into()]);
+        let b = dl("b.txt", "https://example.com/b");
+        let mut a2 = dl("a.txt", "https://example.com/a2");
+        a2.metadata
+            .insert("tag".into(), vec!["y".into(), "z".into()]);
+        a2.metadata.insert("year".into(), vec!["2020".into()]);
+        a2.extra_urls = vec![
+            Url::parse("https://example.com/a1").unwrap(),
+            Url::parse("https://mirror.example/a").unwrap(),
+        ];
+        vec![a1, b, a2]
+    }
+
+    #[rstest]
+    #[case(DuplicatePolicy::FirstWins, &["https://example.com/a1", "https://example.com/b", "https://example.com/a2"])]
+    #[case(DuplicatePolicy::Error, &["https://example.com/a1", "https://example.com/b", "https://example.com/a2"])]
+    #[case(DuplicatePolicy::LastWins, &["https://example.com/b", "https://example.com/a2"])]
+    #[case(DuplicatePolicy::Merge, &["https://example.com/a1", "https://example.com/b"])]
+    fn test_dedupe(#[case] policy: DuplicatePolicy, #[case] urls: &[&str]) {
+        let items = dedupe(sample(), policy);
+        assert_eq!(
+            items.iter().map(|dl| dl.url.as_str()).collect::<Vec<_>>(),
+            urls
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let items = dedupe(sample(), DuplicatePolicy::Merge);
+        let a = &items[0];
+        assert_eq!(
+            a.metadata,
+            HashMap::from([
+                ("tag".into(), vec!["x".into(), "y".into(), "z".into()]),
+                ("year".into(), vec!["2020".into()]),
+            ])
+        );
+        assert_eq!(
+            a.extra_urls,
+            [
+                Url::parse("https://example.com/a2").unwrap(),
+                Url::parse("https://mirror.example/a").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_main_urls() {
+        let items = dedupe(
+            vec![
+                dl("a.txt", "https://example.com/a"),
+                dl("a.txt", "https://mirror.example/a"),
+                dl("a.txt", "https://example.com/a"),
+                dl("a.txt", "https://mirror.example/a"),
+            ],
+            DuplicatePolicy::Merge,
+        );
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].url.as_str(), "https://example.com/a");
+        assert_eq!(
+            items[0].extra_urls,
+            [Url::parse("https://mirror.example/a").unwrap()]
+        );
+    }
+
+    #[rstest]
+    #[case("first-wins", Ok(DuplicatePolicy::FirstWins))]
+    #[case("MERGE", Ok(DuplicatePolicy::Merge))]
+    #[case("skip", Err(ParseDuplicatePolicyError("skip".into())))]
+    fn test_parse(#[case] s: &str, #[case] r: Result<DuplicatePolicy, ParseDuplicatePolicyError>) {
+        assert_eq!(s.parse::<DuplicatePolicy>(), r);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{AnnexError, FilePath};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use url::Url;
+
+/// A notable occurrence during a run, as sent to the channel passed to
+/// [`GamdamBuilder::events()`][crate::GamdamBuilder::events]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// An input item was accepted for downloading.  It may be held back for
+    /// a while by per-host limits before it is started.
+    Queued { path: FilePath, url: Url },
+    /// An item was sent to `git-annex addurl`
+    Started { path: FilePath, url: Url },
+    /// `git-annex addurl` reported progress on a download
+    Progress {
+        path: FilePath,
+        bytes: usize,
+        total: Option<usize>,
+    },
+    /// A download attempt failed for a transient reason and will be retried
+    /// after `delay`
+    Retrying {
+        path: FilePath,
+        attempt: usize,
+        delay: Duration,
+        error: AnnexError,
+    },
+    /// A download finished successfully
+    Downloaded { path: FilePath, key: Option<String> },
+    /// An item's metadata was set
+    MetadataSet { path: FilePath, key: String },
+    /// An extra URL was registered for an item
+    UrlRegistered {
+        path: FilePath,
+        key: String,
+        url: Url,
+    },
+    /// A stage of processing an item failed
+    Failed {
+        path: FilePath,
+        stage: Stage,
+        error: AnnexError,
+    },
+    /// Downloaded files were committed; `downloaded` is the number of files
+    /// in this commit, and `total` is the number committed so far in the run
+    Committed { downloaded: usize, total: usize },
+}
+
+/// A stage in the processing of an item
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Stage {
+    /// Looking up the key of an existing file in metadata-only mode
+    LookupKey,
+    Download,
+    /// Checking the downloaded content against the item's expected size &
+    /// checksums
+    Verify,
+    Metadata,
+    RegisterUrl {
+        url: Url,
+    },
+}
+
+/// Handle for emitting [`Event`]s to the caller's channel, if any
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EventSender(Option<UnboundedSender<Event>>);
+
+impl EventSender {
+    pub(crate) fn new(sender: UnboundedSender<Event>) -> EventSender {
+        EventSender(Some(sender))
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        if let Some(ref sender) = self.0 {
+            // The caller is free to stop listening at any time.
+            let _ = sender.send(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn test_emit() {
+        let event = Event::Committed {
+            downloaded: 2,
+            total: 5,
+        };
+        EventSender::default().emit(event.clone());
+        let (sender, mut receiver) = unbounded_channel();
+        let events = EventSender::new(sender);
+        events.emit(event.clone());
+        assert_eq!(receiver.try_recv(), Ok(event.clone()));
+        drop(receiver);
+        events.emit(event);
+    }
+}
@@ -0,0 +1,376 @@
+use crate::FilePath;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+use tokio::fs::{read_link, symlink_metadata, File};
+use tokio::io::AsyncReadExt;
+
+/// What to do with an item whose path already exists in the repository
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExistingPolicy {
+    /// Leave the existing file alone and don't download anything
+    Skip,
+    /// Don't download anything, but register the item's URLs for the
+    /// existing file's key and set its metadata
+    RegisterUrlOnly,
+    /// Download the URL in place of the existing file.  The existing file is
+    /// moved aside until the download succeeds and is moved back if it
+    /// doesn't.
+    Replace,
+    /// Download the URL to the path with `-<N>` inserted before the file
+    /// extension, for the lowest `N` that gives a path that doesn't exist
+    RenameWithSuffix,
+    /// Fail the item
+    Error,
+}
+
+impl ExistingPolicy {
+    const NAMES: [(ExistingPolicy, &'static str); 5] = [
+        (ExistingPolicy::Skip, "skip"),
+        (ExistingPolicy::RegisterUrlOnly, "register-url-only"),
+        (ExistingPolicy::Replace, "replace"),
+        (ExistingPolicy::RenameWithSuffix, "rename-with-suffix"),
+        (ExistingPolicy::Error, "error"),
+    ];
+}
+
+impl fmt::Display for ExistingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Self::NAMES
+            .iter()
+            .find_map(|&(p, name)| (p == *self).then_some(name))
+            .unwrap_or_default();
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for ExistingPolicy {
+    type Err = ParseExistingPolicyError;
+
+    fn from_str(s: &str) -> Result<ExistingPolicy, ParseExistingPolicyError> {
+        Self::NAMES
+            .iter()
+            .find_map(|&(p, name)| name.eq_ignore_ascii_case(s).then_some(p))
+            .ok_or_else(|| ParseExistingPolicyError(s.into()))
+    }
+}
+
+/// Error returned when parsing an invalid [`ExistingPolicy`]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("invalid existing-path policy {0:?}; expected one of skip, register-url-only, replace, rename-with-suffix, error")]
+pub struct ParseExistingPolicyError(String);
+
+/// What was done about a file already existing at an item's path
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ExistingOutcome {
+    /// The item was skipped
+    Skipped,
+    /// The item's URLs were registered for the existing file instead of
+    /// downloading
+    RegisteredUrl,
+    /// The URL was downloaded in place of the existing file
+    Replaced,
+    /// The URL was downloaded to a new path; `from` is the path given in the
+    /// input
+    Renamed { from: FilePath },
+    /// The item was failed
+    Refused,
+    /// Only the existing file's metadata & extra URLs were updated, as
+    /// requested with
+    /// [`GamdamBuilder::metadata_only()`][crate::GamdamBuilder::metadata_only]
+    MetadataOnly,
+}
+
+/// The state of an item's path in the working tree
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum PathStatus {
+    /// Nothing exists at the path
+    Absent,
+    /// A file or symlink exists at the path.  Whether it's annexed is
+    /// determined separately, either with `git-annex lookupkey` or, when
+    /// git-annex can't be run, with [`looks_annexed()`].
+    File,
+    /// A directory exists at the path
+    Directory,
+    /// A leading component of the path exists and is not a directory
+    ParentNotDirectory,
+}
+
+impl PathStatus {
+    /// Returns true if something exists at the path itself
+    pub(crate) fn exists(self) -> bool {
+        matches!(self, PathStatus::File | PathStatus::Directory)
+    }
+}
+
+/// Examine what currently exists at `path` in `repo`'s working tree, without
+/// running any external processes
+pub(crate) async fn path_status(repo: &Path, path: &FilePath) -> Result<PathStatus, anyhow::Error> {
+    let mut target = repo.to_path_buf();
+    let mut parts = path.as_str().split('/').peekable();
+    while let Some(part) = parts.next() {
+        target.push(part);
+        let md = match symlink_metadata(&target).await {
+            Ok(md) => md,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(PathStatus::Absent),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Error examining {}", target.display()))
+            }
+        };
+        if parts.peek().is_some() {
+            // Symlinks to directories are fine as parents, so follow them.
+            if !target.is_dir() {
+                return Ok(PathStatus::ParentNotDirectory);
+            }
+        } else if md.is_dir() {
+            return Ok(PathStatus::Directory);
+        } else {
+            return Ok(PathStatus::File);
+        }
+    }
+    Ok(PathStatus::Absent)
+}
+
+/// Guess whether the existing file at `path` in `repo` is an annexed file
+/// by examining it on disk, without running git or git-annex.  Locked files
+/// and unlocked files whose content is not present are recognized, but
+/// unlocked files whose content is present look like any other file.
+pub(crate) async fn looks_annexed(repo: &Path, path: &FilePath) -> Result<bool, anyhow::Error> {
+    let target = repo.join(path.as_str());
+    let md = symlink_metadata(&target)
+        .await
+        .with_context(|| format!("Error examining {}", target.display()))?;
+    if md.is_symlink() {
+        // Locked files are symlinks into `.git/annex/objects/`
+        let dest = read_link(&target)
+            .await
+            .with_context(|| format!("Error reading symlink {}", target.display()))?;
+        return Ok(dest.to_str().is_some_and(|s| s.contains(ANNEX_OBJECTS_DIR)));
+    }
+    // Unlocked files whose content is not present consist of just a pointer
+    // to the key
+    let mut head = Vec::with_capacity(ANNEX_POINTER_PREFIX.len());
+    File::open(&target)
+        .await
+        .with_context(|| format!("Error opening {}", target.display()))?
+        .take(ANNEX_POINTER_PREFIX.len() as u64)
+        .read_to_end(&mut head)
+        .await
+        .with_context(|| format!("Error reading {}", target.display()))?;
+    Ok(head == ANNEX_POINTER_PREFIX.as_bytes())
+}
+
+/// An existing file that has been moved aside so that a replacement can be
+/// downloaded to its path.  Once the replacement has been downloaded, the
+/// file is deleted with [`Backup::discard()`]; if the `Backup` is dropped
+/// without being discarded (because the download failed, the item was
+/// abandoned, or the run was aborted), the file is moved back into place.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Backup {
+    path: FilePath,
+    /// The location of the file in the working tree
+    original: PathBuf,
+    /// Where the file was moved to; `None` once it has been discarded
+    moved: Option<PathBuf>,
+}
+
+impl Backup {
+    /// Move the file at `path` in `repo` to a hidden file alongside it named
+    /// `.<NAME>.gamdam-replaced`.  On failure, returns a message describing
+    /// why.
+    pub(crate) fn create(repo: &Path, path: &FilePath) -> Result<Backup, String> {
+        let original = repo.join(path.as_str());
+        let mut name = OsString::from(".");
+        name.push(original.file_name().unwrap_or_default());
+        name.push(".gamdam-replaced");
+        let moved = original.with_file_name(name);
+        if std::fs::symlink_metadata(&moved).is_ok() {
+            return Err(format!(
+                "{} already exists, possibly left over from an interrupted run; not replacing {path}",
+                moved.display()
+            ));
+        }
+        std::fs::rename(&original, &moved)
+            .map_err(|e| format!("error moving {path} aside to replace it: {e}"))?;
+        log::debug!("Moved {path} aside to {}", moved.display());
+        Ok(Backup {
+            path: path.clone(),
+            original,
+            moved: Some(moved),
+        })
+    }
+
+    /// Delete the moved-aside file now that its replacement has been
+    /// downloaded
+    pub(crate) fn discard(mut self) {
+        if let Some(moved) = self.moved.take() {
+            if let Err(e) = std::fs::remove_file(&moved) {
+                log::warn!(
+                    "Failed to remove replaced copy of {} at {}: {e}",
+                    self.path,
+                    moved.display()
+                );
+            }
+        }
+    }
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        let Some(moved) = self.moved.take() else {
+            return;
+        };
+        if std::fs::symlink_metadata(&self.original).is_ok() {
+            log::warn!(
+                "Cannot restore {} from {}, as something now exists at its path",
+                self.path,
+                moved.display()
+            );
+        } else if let Err(e) = std::fs::rename(&moved, &self.original) {
+            log::warn!(
+                "Failed to restore {} from {}: {e}",
+                self.path,
+                moved.display()
+            );
+        } else {
+            log::info!("Restored original {}", self.path);
+        }
+    }
+}
+
+/// Returns `path` with `-<n>` inserted before the file extension (if any)
+pub(crate) fn with_suffix(path: &FilePath, n: usize) -> FilePath {
+    let s = path.as_str();
+    let (dir, name) = match s.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, s),
+    };
+    let name = match name.rfind('.').filter(|&i| i > 0) {
+        Some(i) => format!("{}-{n}{}", &name[..i], &name[i..]),
+        None => format!("{name}-{n}"),
+    };
+    let newpath = match dir {
+        Some(dir) => format!("{dir}/{name}"),
+        None => name,
+    };
+    FilePath::try_from(newpath.as_str()).expect("suffixed path should be a valid FilePath")
+}
+
+const ANNEX_OBJECTS_DIR: &str = "annex/objects/";
+
+const ANNEX_POINTER_PREFIX: &str = "/annex/objects/";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_path_status() {
+        let tmpdir = tempdir().unwrap();
+        let repo = tmpdir.path();
+        std::fs::create_dir_all(repo.join("dir/sub")).unwrap();
+        std::fs::write(repo.join("dir/plain.txt"), "Hello\n").unwrap();
+        std::fs::write(
+            repo.join("pointer.dat"),
+            "/annex/objects/SHA256E-s6--66a045b452102c59d840ec097d59d9467e13a3f34f6494e539ffd32c1bb35f18.dat\n",
+        )
+        .unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(
+            ".git/annex/objects/Xx/Yy/SHA256E-s6--abc.txt/SHA256E-s6--abc.txt",
+            repo.join("locked.txt"),
+        )
+        .unwrap();
+        for (path, status) in [
+            ("new.txt", PathStatus::Absent),
+            ("newdir/new.txt", PathStatus::Absent),
+            ("dir/new.txt", PathStatus::Absent),
+            ("dir/plain.txt", PathStatus::File),
+            ("dir/sub", PathStatus::Directory),
+            ("dir/plain.txt/foo", PathStatus::ParentNotDirectory),
+            ("pointer.dat", PathStatus::File),
+            #[cfg(unix)]
+            ("locked.txt", PathStatus::File),
+        ] {
+            let path = FilePath::try_from(path).unwrap();
+            assert_eq!(path_status(repo, &path).await.unwrap(), status, "{path}");
+        }
+        for (path, annexed) in [
+            ("dir/plain.txt", false),
+            ("pointer.dat", true),
+            #[cfg(unix)]
+            ("locked.txt", true),
+        ] {
+            let path = FilePath::try_from(path).unwrap();
+            assert_eq!(looks_annexed(repo, &path).await.unwrap(), annexed, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_backup() {
+        let tmpdir = tempdir().unwrap();
+        let repo = tmpdir.path();
+        std::fs::create_dir(repo.join("dir")).unwrap();
+        std::fs::write(repo.join("dir/a.txt"), "old\n").unwrap();
+        let path = FilePath::try_from("dir/a.txt").unwrap();
+        let moved = repo.join("dir/.a.txt.gamdam-replaced");
+
+        // Dropping the backup restores the original file
+        let backup = Backup::create(repo, &path).unwrap();
+        assert!(!repo.join("dir/a.txt").exists());
+        assert!(moved.exists());
+        assert!(Backup::create(repo, &path).is_err());
+        drop(backup);
+        assert_eq!(
+            std::fs::read_to_string(repo.join("dir/a.txt")).unwrap(),
+            "old\n"
+        );
+        assert!(!moved.exists());
+
+        // Discarding the backup once a replacement exists deletes the
+        // original
+        let backup = Backup::create(repo, &path).unwrap();
+        std::fs::write(repo.join("dir/a.txt"), "new\n").unwrap();
+        backup.discard();
+        assert_eq!(
+            std::fs::read_to_string(repo.join("dir/a.txt")).unwrap(),
+            "new\n"
+        );
+        assert!(!moved.exists());
+    }
+
+    #[rstest]
+    #[case("foo.txt", 1, "foo-1.txt")]
+    #[case("foo", 2, "foo-2")]
+    #[case("dir.d/foo", 1, "dir.d/foo-1")]
+    #[case("dir/foo.tar.gz", 3, "dir/foo.tar-3.gz")]
+    #[case(".hidden", 1, ".hidden-1")]
+    fn test_with_suffix(#[case] path: &str, #[case] n: usize, #[case] expected: &str) {
+        let path = FilePath::try_from(path).unwrap();
+        assert_eq!(with_suffix(&path, n).as_str(), expected);
+    }
+
+    #[rstest]
+    #[case("skip", ExistingPolicy::Skip)]
+    #[case("register-url-only", ExistingPolicy::RegisterUrlOnly)]
+    #[case("Replace", ExistingPolicy::Replace)]
+    #[case("rename-with-suffix", ExistingPolicy::RenameWithSuffix)]
+    #[case("error", ExistingPolicy::Error)]
+    fn test_parse_existing_policy(#[case] s: &str, #[case] policy: ExistingPolicy) {
+        assert_eq!(s.parse::<ExistingPolicy>(), Ok(policy));
+        assert_eq!(policy.to_string(), s.to_ascii_lowercase());
+        assert_eq!(
+            serde_json::to_string(&policy).unwrap(),
+            format!("\"{}\"", s.to_ascii_lowercase())
+        );
+    }
+}
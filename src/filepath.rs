@@ -1,7 +1,7 @@
 use serde::de::{Deserializer, Unexpected, Visitor};
 use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use std::fmt::{self, Write};
 use std::path::{Component, Path};
 use thiserror::Error;
 
@@ -14,6 +14,109 @@ impl FilePath {
     pub fn as_str(&self) -> &str {
         &self.0
     }
+
+    /// Construct a `FilePath` from an arbitrary string by rewriting whatever
+    /// would make it invalid or unportable instead of rejecting it:
+    ///
+    /// - Leading and repeated separators are collapsed, and `.` components
+    ///   are removed.
+    /// - Characters that are illegal in Windows filenames (`<>:"|?*` and, on
+    ///   platforms where it is not a separator, `\`) are replaced with `_`.
+    /// - Control characters are escaped as `%XX`.
+    /// - Trailing periods and spaces are stripped from each component, and a
+    ///   component left empty (e.g., `..`) becomes `_`.
+    /// - Components that are Windows reserved names (`CON`, `NUL`, `COM1`,
+    ///   etc., with or without an extension) are prefixed with `_`.
+    /// - Components longer than 255 bytes are truncated, keeping the
+    ///   extension.
+    ///
+    /// Fails only if the string contains no components at all.
+    pub fn sanitize(path: &str) -> Result<FilePath, FilePathError> {
+        let mut output = String::new();
+        for part in path.split(PATHSEP) {
+            if part.is_empty() || part == "." {
+                continue;
+            }
+            if !output.is_empty() {
+                output.push('/');
+            }
+            output.push_str(&sanitize_component(part));
+        }
+        if output.is_empty() {
+            return Err(FilePathError::Empty);
+        }
+        Ok(FilePath(output))
+    }
+}
+
+/// Maximum length in bytes of a filename on most filesystems
+const MAX_COMPONENT_BYTES: usize = 255;
+
+const ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn sanitize_component(part: &str) -> String {
+    let mut name = String::with_capacity(part.len());
+    for c in part.chars() {
+        if c.is_control() {
+            let _ = write!(name, "%{:02X}", u32::from(c));
+        } else if ILLEGAL_CHARS.contains(&c) {
+            name.push('_');
+        } else {
+            name.push(c);
+        }
+    }
+    let name = fit_component(name);
+    if name.is_empty() {
+        return String::from("_");
+    }
+    let stem = name.split('.').next().unwrap_or(&name);
+    if WINDOWS_RESERVED
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        // Prefixing can push the name back over the limit, but a name
+        // starting with `_` can't be reserved, so refitting it is safe.
+        fit_component(format!("_{name}"))
+    } else {
+        name
+    }
+}
+
+/// Truncate `name` to [`MAX_COMPONENT_BYTES`] if it's longer, keeping the
+/// extension, and then strip trailing periods & spaces
+fn fit_component(mut name: String) -> String {
+    if name.len() > MAX_COMPONENT_BYTES {
+        name = truncate_keeping_extension(&name, MAX_COMPONENT_BYTES);
+    }
+    let len = name.trim_end_matches(['.', ' ']).len();
+    name.truncate(len);
+    name
+}
+
+/// Truncate `name` to at most `limit` bytes on a character boundary.  If
+/// `name` has a reasonably short extension, the stem is truncated instead so
+/// that the extension is kept.
+fn truncate_keeping_extension(name: &str, limit: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() < limit / 2 => {
+            let stem = truncate_str(stem, limit - ext.len() - 1);
+            format!("{stem}.{ext}")
+        }
+        _ => truncate_str(name, limit).to_owned(),
+    }
+}
+
+fn truncate_str(s: &str, limit: usize) -> &str {
+    let mut end = limit.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
 }
 
 impl fmt::Debug for FilePath {
@@ -171,6 +274,50 @@ mod tests {
         assert_eq!(FilePath::try_from(path), Err(err));
     }
 
+    #[rstest]
+    #[case("foo/bar.txt", "foo/bar.txt")]
+    #[case("/foo//bar/", "foo/bar")]
+    #[case("../foo/./../bar", "_/foo/_/bar")]
+    #[case("AC/DC: Live?", "AC/DC_ Live_")]
+    #[case(r#"a<b>c"d|e*f"#, "a_b_c_d_e_f")]
+    #[case("line\nbreak\x7F", "line%0Abreak%7F")]
+    #[case("trailing. . /x", "trailing/x")]
+    #[case("con/Nul.txt/LPT1 .tar.gz/COM10", "_con/_Nul.txt/_LPT1 .tar.gz/COM10")]
+    #[cfg_attr(not(windows), case(r"back\slash", "back_slash"))]
+    #[cfg_attr(windows, case(r"back\slash", "back/slash"))]
+    fn test_sanitize(#[case] path: &str, #[case] sanitized: &str) {
+        assert_eq!(FilePath::sanitize(path).unwrap().as_str(), sanitized);
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("/")]
+    #[case("././/.")]
+    fn test_sanitize_empty(#[case] path: &str) {
+        assert_eq!(FilePath::sanitize(path), Err(FilePathError::Empty));
+    }
+
+    #[test]
+    fn test_sanitize_truncate() {
+        let long = format!("dir/{}.pdf", "é".repeat(200));
+        let path = FilePath::sanitize(&long).unwrap();
+        let name = path.as_str().strip_prefix("dir/").unwrap();
+        assert_eq!(name.len(), 254);
+        assert!(name.ends_with("é.pdf"));
+        let noext = "x".repeat(300);
+        assert_eq!(FilePath::sanitize(&noext).unwrap().as_str().len(), 255);
+    }
+
+    #[rstest]
+    #[case(format!("CON.{}", "x".repeat(300)), "_CON.")]
+    #[case(format!("con{}.txt", " ".repeat(300)), "_con ")]
+    #[case(format!("nul{}tail", " ".repeat(300)), "_nul")]
+    fn test_sanitize_truncate_reserved(#[case] path: String, #[case] prefix: &str) {
+        let name = FilePath::sanitize(&path).unwrap();
+        assert!(name.as_str().len() <= 255);
+        assert!(name.as_str().starts_with(prefix));
+    }
+
     #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
     struct Structure {
         path: FilePath,
@@ -0,0 +1,355 @@
+use crate::Downloadable;
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// Limits on how hard to hit a single host
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HostLimit {
+    /// Maximum number of downloads from the host to have in progress at once
+    pub jobs: Option<NonZeroUsize>,
+    /// Minimum time between the starts of consecutive downloads from the
+    /// host
+    pub min_interval: Option<Duration>,
+}
+
+impl HostLimit {
+    /// Returns a limit that uses the fields of `self` that are set and falls
+    /// back to `other` for the rest
+    pub fn or(self, other: HostLimit) -> HostLimit {
+        HostLimit {
+            jobs: self.jobs.or(other.jobs),
+            min_interval: self.min_interval.or(other.min_interval),
+        }
+    }
+}
+
+/// Parses a limit of the form `jobs=<INT>`, `rate=<REQUESTS PER SECOND>`, or
+/// `jobs=<INT>,rate=<REQUESTS PER SECOND>`
+impl FromStr for HostLimit {
+    type Err = ParseHostLimitError;
+
+    fn from_str(s: &str) -> Result<HostLimit, ParseHostLimitError> {
+        let mut limit = HostLimit::default();
+        for part in s.split(',') {
+            let Some((name, value)) = part.split_once('=') else {
+                return Err(ParseHostLimitError::Syntax(part.into()));
+            };
+            match name.trim() {
+                "jobs" => {
+                    let jobs = value
+                        .trim()
+                        .parse::<NonZeroUsize>()
+                        .map_err(|_| ParseHostLimitError::Jobs(value.into()))?;
+                    limit.jobs = Some(jobs);
+                }
+                "rate" => {
+                    let rate = value
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|r| r.is_finite() && *r > 0.0)
+                        .and_then(|r| Duration::try_from_secs_f64(1.0 / r).ok())
+                        .ok_or_else(|| ParseHostLimitError::Rate(value.into()))?;
+                    limit.min_interval = Some(rate);
+                }
+                other => return Err(ParseHostLimitError::Field(other.into())),
+            }
+        }
+        Ok(limit)
+    }
+}
+
+/// Error returned when parsing an invalid [`HostLimit`]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ParseHostLimitError {
+    #[error("expected <field>=<value>, got {0:?}")]
+    Syntax(String),
+    #[error("unknown host limit field {0:?}")]
+    Field(String),
+    #[error("invalid number of jobs {0:?}")]
+    Jobs(String),
+    #[error("invalid rate {0:?}; expected a positive number of requests per second")]
+    Rate(String),
+}
+
+/// Per-host limits applied when feeding URLs to `git-annex addurl`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HostLimits {
+    /// Limits applied to every host
+    pub default: HostLimit,
+    /// Limits for specific hosts; fields left unset fall back to `default`
+    pub overrides: HashMap<String, HostLimit>,
+}
+
+impl HostLimits {
+    pub fn for_host(&self, host: &str) -> HostLimit {
+        match self.overrides.get(host) {
+            Some(limit) => limit.or(self.default),
+            None => self.default,
+        }
+    }
+}
+
+/// Returns the host that per-host limits for a download are keyed by
+pub(crate) fn host_of(dl: &Downloadable) -> &str {
+    dl.url.host_str().unwrap_or_default()
+}
+
+/// Holds back items whose hosts are at their limits until they can be
+/// started
+#[derive(Debug)]
+pub(crate) struct HostScheduler<'a> {
+    limits: &'a HostLimits,
+    queues: HashMap<String, VecDeque<Downloadable>>,
+    next_start: HashMap<String, Instant>,
+    deferred: usize,
+}
+
+impl<'a> HostScheduler<'a> {
+    pub(crate) fn new(limits: &'a HostLimits) -> Self {
+        HostScheduler {
+            limits,
+            queues: HashMap::new(),
+            next_start: HashMap::new(),
+            deferred: 0,
+        }
+    }
+
+    /// Number of items being held back
+    pub(crate) fn len(&self) -> usize {
+        self.deferred
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.deferred == 0
+    }
+
+    /// Returns `dl` if it may be started now, or else holds it back.
+    /// `load` returns the number of downloads currently in progress for a
+    /// host.
+    pub(crate) fn submit<F>(
+        &mut self,
+        dl: Downloadable,
+        load: F,
+        now: Instant,
+    ) -> Option<Downloadable>
+    where
+        F: Fn(&str) -> usize,
+    {
+        let host = host_of(&dl);
+        let queue_empty = self.queues.get(host).map_or(true, VecDeque::is_empty);
+        if queue_empty && self.may_start(host, &load, now) {
+            self.started(host.to_owned(), now);
+            Some(dl)
+        } else {
+            self.queues
+                .entry(host.to_owned())
+                .or_default()
+                .push_back(dl);
+            self.deferred += 1;
+            None
+        }
+    }
+
+    /// Remove & return all held-back items that may now be started
+    pub(crate) fn ready<F>(&mut self, load: F, now: Instant) -> Vec<Downloadable>
+    where
+        F: Fn(&str) -> usize,
+    {
+        let mut ready = Vec::new();
+        let hosts = self
+            .queues
+            .iter()
+            .filter(|(_, q)| !q.is_empty())
+            .map(|(h, _)| h.clone())
+            .collect::<Vec<_>>();
+        for host in hosts {
+            // Items started in this call don't show up in `load` yet, so
+            // count them separately.
+            let mut started = 0;
+            while self.queues.get(&host).is_some_and(|q| !q.is_empty())
+                && self.may_start(&host, &|h: &str| load(h) + started, now)
+            {
+                if let Some(dl) = self.queues.get_mut(&host).and_then(VecDeque::pop_front) {
+                    self.deferred -= 1;
+                    started += 1;
+                    self.started(host.clone(), now);
+                    ready.push(dl);
+                }
+            }
+        }
+        self.queues.retain(|_, q| !q.is_empty());
+        ready
+    }
+
+    /// Returns the earliest time after `now` at which a held-back item may
+    /// become startable due to a rate limit expiring
+    pub(crate) fn next_wakeup(&self, now: Instant) -> Option<Instant> {
+        self.queues
+            .keys()
+            .filter_map(|host| self.next_start.get(host))
+            .filter(|&&t| t > now)
+            .min()
+            .copied()
+    }
+
+    /// Remove & return all held-back items
+    pub(crate) fn drain(&mut self) -> Vec<Downloadable> {
+        self.deferred = 0;
+        self.queues.drain().flat_map(|(_, q)| q).collect()
+    }
+
+    fn may_start<F>(&self, host: &str, load: &F, now: Instant) -> bool
+    where
+        F: Fn(&str) -> usize,
+    {
+        let limit = self.limits.for_host(host);
+        limit.jobs.map_or(true, |jobs| load(host) < jobs.get())
+            && self.next_start.get(host).map_or(true, |&t| t <= now)
+    }
+
+    fn started(&mut self, host: String, now: Instant) {
+        if let Some(interval) = self.limits.for_host(&host).min_interval {
+            self.next_start.insert(host, now + interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExpectedContent;
+    use rstest::rstest;
+    use std::collections::HashMap;
+    use url::Url;
+
+    fn dl(url: &str, path: &str) -> Downloadable {
+        Downloadable {
+            path: path.try_into().unwrap(),
+            url: Url::parse(url).unwrap(),
+            metadata: HashMap::new(),
+            metadata_ops: HashMap::new(),
+            extra_urls: Vec::new(),
+            on_existing: None,
+            original_path: None,
+            expected: ExpectedContent::default(),
+        }
+    }
+
+    #[rstest]
+    #[case("jobs=4", HostLimit {jobs: NonZeroUsize::new(4), min_interval: None})]
+    #[case("rate=2", HostLimit {jobs: None, min_interval: Some(Duration::from_millis(500))})]
+    #[case("jobs=1, rate=0.5", HostLimit {jobs: NonZeroUsize::new(1), min_interval: Some(Duration::from_secs(2))})]
+    fn test_parse_host_limit(#[case] s: &str, #[case] limit: HostLimit) {
+        assert_eq!(s.parse::<HostLimit>(), Ok(limit));
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("jobs")]
+    #[case("jobs=0")]
+    #[case("rate=0")]
+    #[case("rate=-1")]
+    #[case("rate=inf")]
+    #[case("speed=3")]
+    fn test_parse_host_limit_err(#[case] s: &str) {
+        assert!(s.parse::<HostLimit>().is_err());
+    }
+
+    #[test]
+    fn test_for_host() {
+        let limits = HostLimits {
+            default: HostLimit {
+                jobs: NonZeroUsize::new(2),
+                min_interval: Some(Duration::from_secs(1)),
+            },
+            overrides: HashMap::from([(
+                String::from("example.com"),
+                HostLimit {
+                    jobs: NonZeroUsize::new(8),
+                    min_interval: None,
+                },
+            )]),
+        };
+        assert_eq!(
+            limits.for_host("example.com"),
+            HostLimit {
+                jobs: NonZeroUsize::new(8),
+                min_interval: Some(Duration::from_secs(1)),
+            }
+        );
+        assert_eq!(limits.for_host("example.org"), limits.default);
+    }
+
+    #[test]
+    fn test_scheduler_jobs() {
+        let limits = HostLimits {
+            default: HostLimit {
+                jobs: NonZeroUsize::new(1),
+                min_interval: None,
+            },
+            overrides: HashMap::new(),
+        };
+        let mut sched = HostScheduler::new(&limits);
+        let now = Instant::now();
+        let mut load = HashMap::<String, usize>::new();
+        for (url, path, started) in [
+            ("https://a.example/1", "a1", true),
+            ("https://a.example/2", "a2", false),
+            ("https://b.example/1", "b1", true),
+            ("https://a.example/3", "a3", false),
+        ] {
+            let r = sched.submit(dl(url, path), |h| load.get(h).copied().unwrap_or(0), now);
+            assert_eq!(r.is_some(), started, "{path} start status");
+            if let Some(d) = r {
+                *load.entry(host_of(&d).to_owned()).or_default() += 1;
+            }
+        }
+        assert_eq!(sched.len(), 2);
+        let ready = sched.ready(|h| load.get(h).copied().unwrap_or(0), now);
+        assert!(ready.is_empty());
+        load.insert(String::from("a.example"), 0);
+        let ready = sched.ready(|h| load.get(h).copied().unwrap_or(0), now);
+        assert_eq!(ready, vec![dl("https://a.example/2", "a2")]);
+        assert_eq!(sched.len(), 1);
+        assert_eq!(sched.drain(), vec![dl("https://a.example/3", "a3")]);
+        assert!(sched.is_empty());
+    }
+
+    #[test]
+    fn test_scheduler_rate() {
+        let limits = HostLimits {
+            default: HostLimit::default(),
+            overrides: HashMap::from([(
+                String::from("a.example"),
+                HostLimit {
+                    jobs: None,
+                    min_interval: Some(Duration::from_secs(1)),
+                },
+            )]),
+        };
+        let mut sched = HostScheduler::new(&limits);
+        let now = Instant::now();
+        assert!(sched
+            .submit(dl("https://a.example/1", "a1"), |_| 0, now)
+            .is_some());
+        assert!(sched
+            .submit(dl("https://a.example/2", "a2"), |_| 0, now)
+            .is_none());
+        assert!(sched
+            .submit(dl("https://b.example/1", "b1"), |_| 0, now)
+            .is_some());
+        assert_eq!(sched.next_wakeup(now), Some(now + Duration::from_secs(1)));
+        assert!(sched.ready(|_| 0, now).is_empty());
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            sched.ready(|_| 0, later),
+            vec![dl("https://a.example/2", "a2")]
+        );
+        assert_eq!(sched.next_wakeup(later), None);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::filepath::FilePath;
+use anyhow::Context;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_jsonlines::AsyncBufReadJsonLines;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use url::Url;
+
+/// A record of a pipeline stage that was completed for a single file
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "stage", rename_all = "lowercase")]
+pub enum JournalEntry {
+    /// The file was downloaded with `git-annex addurl`.  `path` is the path
+    /// given in the input; if the existing-path policy caused the file to be
+    /// downloaded to a different path, that path is `renamed_to`.
+    Download {
+        path: FilePath,
+        url: Url,
+        key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        renamed_to: Option<FilePath>,
+    },
+    /// The file's metadata was set with `git-annex metadata`
+    Metadata { path: FilePath, key: String },
+    /// An extra URL was registered for the file with `git-annex registerurl`
+    RegisterURL {
+        path: FilePath,
+        key: String,
+        url: Url,
+    },
+}
+
+/// An append-only log of completed pipeline stages, used to resume an
+/// interrupted run without redoing work
+#[derive(Debug, Default)]
+pub(crate) struct Journal {
+    file: Option<Mutex<File>>,
+    downloaded: HashMap<FilePath, Option<String>>,
+    renamed: HashMap<FilePath, FilePath>,
+    metadata_set: HashSet<FilePath>,
+    urls_registered: HashMap<FilePath, HashSet<Url>>,
+}
+
+impl Journal {
+    /// Returns a `Journal` that records nothing and has no completed stages
+    pub(crate) fn disabled() -> Journal {
+        Journal::default()
+    }
+
+    /// Open the journal at `path` for appending.  If `resume` is true, the
+    /// stages recorded in the file by previous runs are loaded first.
+    pub(crate) async fn open(path: &Path, resume: bool) -> Result<Journal, anyhow::Error> {
+        let mut journal = Journal::default();
+        if resume {
+            match File::open(path).await {
+                Ok(fp) => journal.load(fp, path).await?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    log::warn!(
+                        "Journal {} does not exist; nothing to resume",
+                        path.display()
+                    );
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Error opening journal {} for reading", path.display())
+                    })
+                }
+            }
+        }
+        let fp = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Error opening journal {} for writing", path.display()))?;
+        journal.file = Some(Mutex::new(fp));
+        Ok(journal)
+    }
+
+    // The suggested "fix" for the `|inner| inner.is::<...>()` closure just
+    // looks ugly.
+    #[allow(clippy::redundant_closure_for_method_calls)]
+    async fn load(&mut self, fp: File, path: &Path) -> Result<(), anyhow::Error> {
+        let mut lines = BufReader::new(fp).json_lines::<JournalEntry>();
+        let mut lineno = 1;
+        while let Some(r) = lines.next().await {
+            match r {
+                Ok(entry) => self.insert(entry),
+                // A run that was killed mid-write can leave a partial line at
+                // the end of the journal.
+                Err(e)
+                    if e.get_ref()
+                        .is_some_and(|inner| inner.is::<serde_json::Error>()) =>
+                {
+                    log::warn!(
+                        "Journal {} line {} is invalid; ignoring: {}",
+                        path.display(),
+                        lineno,
+                        e
+                    );
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Error reading journal {}", path.display()))
+                }
+            }
+            lineno += 1;
+        }
+        log::info!(
+            "Loaded journal {}: {} downloaded, {} with metadata set, {} extra URLs registered",
+            path.display(),
+            self.downloaded.len(),
+            self.metadata_set.len(),
+            self.urls_registered
+                .values()
+                .map(HashSet::len)
+                .sum::<usize>(),
+        );
+        Ok(())
+    }
+
+    fn insert(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Download {
+                path,
+                key,
+                renamed_to,
+                ..
+            } => {
+                if let Some(newpath) = renamed_to {
+                    self.renamed.insert(path.clone(), newpath);
+                }
+                self.downloaded.insert(path, key);
+            }
+            JournalEntry::Metadata { path, .. } => {
+                self.metadata_set.insert(path);
+            }
+            JournalEntry::RegisterURL { path, url, .. } => {
+                self.urls_registered.entry(path).or_default().insert(url);
+            }
+        }
+    }
+
+    /// If a previous run recorded the input path `path` as downloaded,
+    /// returns the key it was assigned
+    pub(crate) fn downloaded(&self, path: &FilePath) -> Option<&Option<String>> {
+        self.downloaded.get(path)
+    }
+
+    /// If a previous run downloaded the item with input path `path` to a
+    /// different path, returns that path
+    pub(crate) fn renamed(&self, path: &FilePath) -> Option<&FilePath> {
+        self.renamed.get(path)
+    }
+
+    pub(crate) fn metadata_set(&self, path: &FilePath) -> bool {
+        self.metadata_set.contains(path)
+    }
+
+    pub(crate) fn url_registered(&self, path: &FilePath, url: &Url) -> bool {
+        self.urls_registered
+            .get(path)
+            .is_some_and(|urls| urls.contains(url))
+    }
+
+    /// Append `entry` to the journal file, if there is one
+    pub(crate) async fn record(&self, entry: JournalEntry) -> Result<(), anyhow::Error> {
+        if let Some(ref file) = self.file {
+            let mut line =
+                serde_json::to_string(&entry).context("Error serializing journal entry")?;
+            line.push('\n');
+            let mut fp = file.lock().await;
+            fp.write_all(line.as_bytes())
+                .await
+                .context("Error writing to journal")?;
+            fp.flush().await.context("Error writing to journal")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dump_journal_entry() {
+        let entry = JournalEntry::RegisterURL {
+            path: FilePath::try_from("foo/bar.txt").unwrap(),
+            key: String::from(
+                "SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt",
+            ),
+            url: Url::parse("https://example.com/bar.txt").unwrap(),
+        };
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"{"stage":"registerurl","path":"foo/bar.txt","key":"SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt","url":"https://example.com/bar.txt"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_journal() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("journal.jsonl");
+        let foo = FilePath::try_from("foo.txt").unwrap();
+        let bar = FilePath::try_from("bar.txt").unwrap();
+        let url = Url::parse("https://example.com/mirror/foo.txt").unwrap();
+        {
+            let journal = Journal::open(&path, false).await.unwrap();
+            journal
+                .record(JournalEntry::Download {
+                    path: foo.clone(),
+                    url: Url::parse("https://example.com/foo.txt").unwrap(),
+                    key: Some(String::from("KEY-foo")),
+                    renamed_to: None,
+                })
+                .await
+                .unwrap();
+            journal
+                .record(JournalEntry::Download {
+                    path: bar.clone(),
+                    url: Url::parse("https://example.com/bar.txt").unwrap(),
+                    key: Some(String::from("KEY-bar")),
+                    renamed_to: Some(FilePath::try_from("bar-1.txt").unwrap()),
+                })
+                .await
+                .unwrap();
+            journal
+                .record(JournalEntry::RegisterURL {
+                    path: foo.clone(),
+                    key: String::from("KEY-foo"),
+                    url: url.clone(),
+                })
+                .await
+                .unwrap();
+        }
+        // Simulate a run that was killed while writing a line
+        let mut fp = OpenOptions::new().append(true).open(&path).await.unwrap();
+        fp.write_all(br#"{"stage":"metadata","pa"#).await.unwrap();
+        drop(fp);
+        let journal = Journal::open(&path, true).await.unwrap();
+        assert_eq!(
+            journal.downloaded(&foo),
+            Some(&Some(String::from("KEY-foo")))
+        );
+        assert_eq!(journal.renamed(&foo), None);
+        assert_eq!(
+            journal.downloaded(&bar),
+            Some(&Some(String::from("KEY-bar")))
+        );
+        assert_eq!(
+            journal.renamed(&bar).map(FilePath::as_str),
+            Some("bar-1.txt")
+        );
+        let baz = FilePath::try_from("baz.txt").unwrap();
+        assert_eq!(journal.downloaded(&baz), None);
+        assert!(!journal.metadata_set(&foo));
+        assert!(journal.url_registered(&foo, &url));
+        assert!(!journal.url_registered(&baz, &url));
+    }
+
+    #[tokio::test]
+    async fn test_no_resume_ignores_contents() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("journal.jsonl");
+        let foo = FilePath::try_from("foo.txt").unwrap();
+        Journal::open(&path, false)
+            .await
+            .unwrap()
+            .record(JournalEntry::Metadata {
+                path: foo.clone(),
+                key: String::from("KEY-foo"),
+            })
+            .await
+            .unwrap();
+        let journal = Journal::open(&path, false).await.unwrap();
+        assert!(!journal.metadata_set(&foo));
+    }
+}
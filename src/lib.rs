@@ -1,33 +1,110 @@
 mod annex;
 pub mod blc;
+mod builder;
 pub mod cmd;
+mod collisions;
+mod commit;
+mod defaults;
+mod duplicates;
+mod events;
+mod existing;
 mod filepath;
+mod hosts;
+mod journal;
+mod metacheck;
+mod metaops;
+mod mirrors;
+mod plan;
+mod postprocess;
+mod preflight;
+mod report;
+mod sameurl;
+mod table;
+mod template;
+mod verify;
 use crate::annex::addurl::*;
+use crate::annex::fromkey::*;
+use crate::annex::key::key_size;
+use crate::annex::lookupkey::*;
 use crate::annex::metadata::*;
 use crate::annex::registerurl::*;
 pub use crate::annex::*;
+pub use crate::builder::*;
 use crate::cmd::*;
+pub use crate::collisions::{
+    Collision, CollisionKind, CollisionPolicies, CollisionPolicy, ParseCollisionError,
+};
+use crate::commit::{save_run, SaveOptions};
+pub use crate::defaults::*;
+pub use crate::duplicates::{DuplicatePolicy, ParseDuplicatePolicyError};
+use crate::events::EventSender;
+pub use crate::events::{Event, Stage};
+use crate::existing::{path_status, with_suffix, Backup, PathStatus};
+pub use crate::existing::{ExistingOutcome, ExistingPolicy, ParseExistingPolicyError};
 pub use crate::filepath::*;
+pub use crate::hosts::*;
+pub use crate::journal::*;
+pub use crate::metacheck::*;
+use crate::metaops::resolve_fields;
+pub use crate::metaops::MetadataOp;
+pub use crate::mirrors::*;
+pub use crate::plan::*;
+pub use crate::report::*;
+use crate::report::{write_failures, write_report};
+use crate::sameurl::{CopyJob, SharedUrls};
+pub use crate::table::*;
+pub use crate::template::*;
+pub use crate::verify::ExpectedContent;
+use crate::verify::{drop_content, verify_content};
 use anyhow::Context;
-use futures_util::{SinkExt, TryStreamExt};
+use chrono::{DateTime, Utc};
+use futures_util::{future::Either, SinkExt, Stream, StreamExt, TryStreamExt};
+use patharg::OutputArg;
 use serde::{Deserialize, Serialize};
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::fmt;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::pin::pin;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::fs::create_dir_all;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::time::DelayQueue;
 use url::Url;
 
+/// The log target used for the messages reporting the progress of each
+/// download.  Programs that display progress some other way (e.g., via
+/// [`Event::Progress`]) can filter out messages with this target.
+pub const PROGRESS_LOG_TARGET: &str = "gamdam::progress";
+
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Downloadable {
     pub path: FilePath,
     pub url: Url,
     #[serde(default)]
     pub metadata: HashMap<String, Vec<String>>,
+    /// Operations to perform on metadata fields relative to their current
+    /// values, applied after the fields in `metadata` are set
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata_ops: HashMap<String, MetadataOp>,
     #[serde(default)]
     pub extra_urls: Vec<Url>,
+    /// What to do if the path already exists, overriding
+    /// [`GamdamBuilder::on_existing()`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_existing: Option<ExistingPolicy>,
+    /// The path given in the input (or produced by the path template) before
+    /// it was rewritten by [`FilePath::sanitize()`] or renamed to avoid a
+    /// path collision, if it was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_path: Option<String>,
+    /// The size & checksums that the downloaded content must match
+    #[serde(flatten)]
+    pub expected: ExpectedContent,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -37,6 +114,28 @@ pub struct DownloadResult {
     pub key: Option<String>,
     pub metadata_added: Option<Result<(), AnnexError>>,
     pub urls_added: HashMap<Url, Result<(), AnnexError>>,
+    /// Errors from earlier download attempts that failed transiently and
+    /// were retried, in the order they occurred
+    pub failed_attempts: Vec<AnnexError>,
+    /// Number of bytes downloaded, if known
+    pub bytes: Option<u64>,
+    pub timings: Timings,
+    /// What was done about a file already existing at the item's path, if
+    /// an existing-path policy was applied
+    pub existing: Option<ExistingOutcome>,
+}
+
+/// Timestamps of the stages that an item passed through.  Stages that were
+/// skipped (e.g., because of `--resume`) are `None`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Timings {
+    /// When the item was first sent to `git-annex addurl`
+    pub started: Option<DateTime<Utc>>,
+    /// When `git-annex addurl` reported the outcome of the last download
+    /// attempt
+    pub downloaded: Option<DateTime<Utc>>,
+    /// When post-processing of the item's metadata and extra URLs finished
+    pub finished: Option<DateTime<Utc>>,
 }
 
 impl DownloadResult {
@@ -46,31 +145,173 @@ impl DownloadResult {
             && self.urls_added.values().all(Result::is_ok)
     }
 
-    fn successful_download(downloadable: Downloadable, key: Option<String>) -> DownloadResult {
+    /// Returns true if the item's URL was actually downloaded (as opposed to
+    /// failing or being skipped due to its path already existing)
+    pub fn downloaded(&self) -> bool {
+        self.download.is_ok()
+            && !matches!(
+                self.existing,
+                Some(
+                    ExistingOutcome::Skipped
+                        | ExistingOutcome::RegisteredUrl
+                        | ExistingOutcome::MetadataOnly
+                )
+            )
+    }
+
+    fn successful_download(mut entry: InProgressEntry, key: Option<String>) -> DownloadResult {
+        // The download has replaced any existing file, so the moved-aside
+        // copy is no longer needed.
+        if let Some(backup) = entry.backup.take() {
+            backup.discard();
+        }
         DownloadResult {
+            bytes: key.as_deref().and_then(key_size).or(entry.bytes),
+            timings: entry.timings(),
+            existing: entry.existing,
+            downloadable: entry.downloadable,
+            download: Ok(()),
+            key,
+            metadata_added: None,
+            urls_added: HashMap::new(),
+            failed_attempts: entry.failed_attempts,
+        }
+    }
+
+    fn failed_download(entry: InProgressEntry, err: AnnexError) -> DownloadResult {
+        DownloadResult {
+            bytes: entry.bytes,
+            timings: entry.timings(),
+            existing: entry.existing,
+            downloadable: entry.downloadable,
+            download: Err(err),
+            key: None,
+            metadata_added: None,
+            urls_added: HashMap::new(),
+            failed_attempts: entry.failed_attempts,
+        }
+    }
+
+    /// Construct a result for an item whose download was skipped because a
+    /// previous run already completed it.  If the previous run downloaded it
+    /// to a different path than the one given in the input, that path is
+    /// `renamed_to`.
+    fn resumed_download(
+        mut downloadable: Downloadable,
+        key: Option<String>,
+        renamed_to: Option<FilePath>,
+    ) -> DownloadResult {
+        let existing = renamed_to.map(|newpath| ExistingOutcome::Renamed {
+            from: std::mem::replace(&mut downloadable.path, newpath),
+        });
+        DownloadResult {
+            bytes: key.as_deref().and_then(key_size),
+            timings: Timings::default(),
             downloadable,
             download: Ok(()),
             key,
             metadata_added: None,
             urls_added: HashMap::new(),
+            failed_attempts: Vec::new(),
+            existing,
+        }
+    }
+
+    /// Construct a result for an item that was not downloaded because its
+    /// path already exists
+    fn not_downloaded(
+        downloadable: Downloadable,
+        download: Result<(), AnnexError>,
+        key: Option<String>,
+        existing: ExistingOutcome,
+    ) -> DownloadResult {
+        DownloadResult {
+            bytes: None,
+            timings: Timings::default(),
+            downloadable,
+            download,
+            key,
+            metadata_added: None,
+            urls_added: HashMap::new(),
+            failed_attempts: Vec::new(),
+            existing: Some(existing),
         }
     }
 
-    fn failed_download(downloadable: Downloadable, err: AnnexError) -> DownloadResult {
+    /// Construct a result for an item that was failed before being sent to
+    /// `git-annex addurl`
+    fn failed_early(downloadable: Downloadable, err: AnnexError) -> DownloadResult {
         DownloadResult {
+            bytes: None,
+            timings: Timings::default(),
             downloadable,
             download: Err(err),
             key: None,
             metadata_added: None,
             urls_added: HashMap::new(),
+            failed_attempts: Vec::new(),
+            existing: None,
         }
     }
+
+    /// The total number of times the download was attempted
+    pub fn attempts(&self) -> usize {
+        self.failed_attempts.len() + 1
+    }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Report {
     pub successful: Vec<DownloadResult>,
     pub failed: Vec<DownloadResult>,
+    /// Items that were never downloaded because the run was stopped early
+    /// via [`Shutdown::stop()`]
+    pub unprocessed: Vec<Downloadable>,
+    /// Number of downloaded files that were included in periodic commits
+    /// made during the run
+    pub committed: usize,
+}
+
+/// A handle for interrupting a run started with
+/// [`Gamdam::try_download_with_shutdown()`]
+///
+/// Clones of a `Shutdown` all refer to the same run.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    kill: CancellationToken,
+    stop: CancellationToken,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let kill = CancellationToken::new();
+        // Killing a run implies stopping it
+        let stop = kill.child_token();
+        Shutdown { kill, stop }
+    }
+
+    /// Stop feeding new items to `git-annex addurl`.  Downloads already in
+    /// progress are allowed to finish and have their metadata & URLs set, and
+    /// the remaining input items are returned in [`Report::unprocessed`].
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    /// Terminate the git-annex processes immediately, causing the run to
+    /// fail with an error
+    pub fn kill(&self) {
+        self.kill.cancel();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown::new()
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -88,90 +329,681 @@ impl fmt::Display for Jobs {
     }
 }
 
+/// How to handle downloads that fail for what looks like a transient reason
 #[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of times to attempt each download, including the first
+    /// attempt
+    pub max_attempts: NonZeroUsize,
+    /// How long to wait before the first retry; the delay is doubled after
+    /// each subsequent failure
+    pub initial_delay: Duration,
+    /// Upper bound on the delay between retries
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns the delay to wait before retrying a download that has failed
+    /// `failures` times, or `None` if no more attempts should be made
+    pub fn delay(&self, failures: usize) -> Option<Duration> {
+        if failures == 0 || failures >= self.max_attempts.get() {
+            return None;
+        }
+        let exp = u32::try_from(failures - 1).unwrap_or(u32::MAX);
+        Some(
+            self.initial_delay
+                .saturating_mul(2u32.saturating_pow(exp))
+                .min(self.max_delay),
+        )
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: NonZeroUsize::new(3).expect("3 should be nonzero"),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A configured downloader.  Construct one with [`Gamdam::builder()`].
+#[derive(Clone, Debug)]
 pub struct Gamdam {
-    pub repo: PathBuf,
-    pub addurl_options: Vec<String>,
-    pub addurl_jobs: Jobs,
+    repo: PathBuf,
+    /// If true, create & initialize `repo` at the start of each run
+    init_repo: bool,
+    addurl_options: Vec<String>,
+    addurl_jobs: Jobs,
+    retry: RetryPolicy,
+    /// Limits on the number of concurrent downloads from & rate of requests
+    /// to each host
+    host_limits: HostLimits,
+    /// What to do with items whose paths already exist in the repository.
+    /// If `None`, the items are passed to `git-annex addurl` as usual, which
+    /// registers the URL for an existing annexed file (failing if the sizes
+    /// don't match).
+    on_existing: Option<ExistingPolicy>,
+    /// What to do with items that have the same path as an earlier item
+    duplicates: DuplicatePolicy,
+    /// Which path collisions to check the input for before downloading
+    collisions: CollisionPolicies,
+    /// If true, download each distinct URL only once, and point the paths of
+    /// other items with the same URL at its key
+    dedupe_urls: bool,
+    /// If true, remove files that fail verification and drop their content
+    drop_mismatched: bool,
+    /// If true, only set the metadata & register the extra URLs of files
+    /// already in the repository instead of downloading
+    metadata_only: bool,
+    /// Metadata added to every downloaded item that doesn't set the same
+    /// fields
+    metadata_defaults: MetadataDefaults,
+    /// If true, also add `metadata_defaults` to items that weren't
+    /// downloaded because their paths already exist
+    defaults_for_existing: bool,
+    /// Number of `git-annex metadata` processes to run in parallel
+    metadata_jobs: NonZeroUsize,
+    /// Number of `git-annex registerurl` processes to run in parallel
+    registerurl_jobs: NonZeroUsize,
+    /// File to which to append a record of each pipeline stage completed for
+    /// each item
+    journal: Option<PathBuf>,
+    /// If true, skip any stages that `journal` records as already completed
+    resume: bool,
+    /// How to commit the downloaded files, or `None` to not commit
+    save: Option<SaveOptions>,
+    /// File to which to write a JSON Lines report of the run
+    report: Option<OutputArg>,
+    /// File to which to write the input records of failed & unprocessed
+    /// items
+    failures: Option<OutputArg>,
+    /// Where to send [`Event`]s
+    events: EventSender,
 }
 
 impl Gamdam {
-    pub async fn download<I>(&self, items: I) -> Result<Report, anyhow::Error>
+    /// Returns a builder for a `Gamdam` that downloads into `repo`
+    pub fn builder<P: Into<PathBuf>>(repo: P) -> GamdamBuilder {
+        GamdamBuilder::new(repo)
+    }
+
+    /// The git-annex repository that files are downloaded into
+    pub fn repo(&self) -> &Path {
+        &self.repo
+    }
+
+    /// Maximum number of finished downloads that may be queued for
+    /// post-processing before reading from `git-annex addurl` is paused
+    const RESULTS_QUEUE_SIZE: usize = 64;
+
+    /// Maximum number of input items that may be held back due to per-host
+    /// limits before reading further input is paused
+    const MAX_DEFERRED: usize = 10000;
+
+    /// Download the items yielded by `items`, feeding each one to `git-annex
+    /// addurl` as soon as it is received
+    pub async fn download<S>(&self, items: S) -> Result<Report, anyhow::Error>
     where
-        I: IntoIterator<Item = Downloadable> + Send,
-        I::IntoIter: Send,
+        S: Stream<Item = Downloadable> + Send,
     {
+        self.try_download(items.map(Ok)).await
+    }
+
+    /// Like [`Gamdam::download()`], but the stream may also yield errors, in
+    /// which case the run is aborted and the error is returned
+    pub async fn try_download<S>(&self, items: S) -> Result<Report, anyhow::Error>
+    where
+        S: Stream<Item = Result<Downloadable, anyhow::Error>> + Send,
+    {
+        self.try_download_with_shutdown(items, &Shutdown::new())
+            .await
+    }
+
+    /// Like [`Gamdam::try_download()`], but the run can be interrupted
+    /// through `shutdown`
+    ///
+    /// Unless disabled with [`GamdamBuilder::init_repo()`], the repository
+    /// is first created & initialized as needed.  Once all items have been
+    /// processed, the downloaded files are committed (if saving is enabled),
+    /// and the report and failures files (if configured) are written.  Errors
+    /// writing the latter are logged but do not cause the run to fail.
+    pub async fn try_download_with_shutdown<S>(
+        &self,
+        items: S,
+        shutdown: &Shutdown,
+    ) -> Result<Report, anyhow::Error>
+    where
+        S: Stream<Item = Result<Downloadable, anyhow::Error>> + Send,
+    {
+        if self.init_repo {
+            ensure_annex_repo(&self.repo).await?;
+        }
+        let items = if self.needs_preflight() {
+            let checked = self.preflight(items).await?;
+            let errors = checked.iter().filter(|(_, c)| c.is_some()).count();
+            if errors > 0 {
+                anyhow::bail!(
+                    "{} found; not downloading anything",
+                    quantify(errors, "path collision")
+                );
+            }
+            Either::Left(futures_util::stream::iter(
+                checked.into_iter().map(|(dl, _)| Ok(dl)),
+            ))
+        } else {
+            Either::Right(items)
+        };
+        let journal = match self.journal {
+            Some(ref path) => Journal::open(path, self.resume).await?,
+            None => Journal::disabled(),
+        };
+        let journal = &journal;
+        if self.metadata_only {
+            let r = self.update_existing(items, journal, shutdown).await;
+            return self.finish_run(r).await;
+        }
+        // Existing files are checked with a single `git-annex lookupkey`
+        // process rather than one per item, started once an existing path is
+        // first found.
+        let mut lookupkey = self.lazy_lookupkey(&shutdown.kill);
         let r = self
             .addurl()?
-            .in_context(|addurl| async {
-                self.metadata()?
-                    .in_context(|metadata| async move {
-                        self.registerurl()?
-                            .in_context(|registerurl| async move {
-                                let in_progress = Arc::new(InProgress::new());
-                                let (sender, receiver) = unbounded_channel();
-                                let (addurl_sink, addurl_stream) = addurl.split();
-                                tokio::try_join!(
-                                    self.feed_addurl(items, addurl_sink, in_progress.clone()),
-                                    self.read_addurl(addurl_stream, in_progress, sender),
-                                    self.add_metadata(receiver, metadata, registerurl),
-                                )
-                            })
-                            .await
-                    })
-                    .await
-            })
-            .await;
+            .in_context(
+                |addurl| async {
+                    let in_progress = Arc::new(InProgress::new());
+                    let (sender, receiver) = channel(Self::RESULTS_QUEUE_SIZE);
+                    let (requeue_sender, requeue_receiver) = unbounded_channel();
+                    let (urls, copy_receiver) = SharedUrls::new();
+                    let urls = self.dedupe_urls.then_some(urls);
+                    let (addurl_sink, addurl_stream) = addurl.split();
+                    tokio::try_join!(
+                        self.feed_addurl(
+                            items,
+                            addurl_sink,
+                            &mut lookupkey,
+                            in_progress.clone(),
+                            requeue_receiver,
+                            sender.clone(),
+                            urls.clone(),
+                            journal,
+                            shutdown,
+                        ),
+                        self.read_addurl(
+                            addurl_stream,
+                            in_progress.clone(),
+                            sender.clone(),
+                            requeue_sender,
+                            urls,
+                            journal,
+                        ),
+                        self.copy_keys(
+                            copy_receiver,
+                            in_progress,
+                            sender,
+                            journal,
+                            &shutdown.kill,
+                        ),
+                        self.add_metadata(receiver, journal, &shutdown.kill),
+                    )
+                },
+                &shutdown.kill,
+            )
+            .await
+            .map(|(unprocessed, (), (), report)| (unprocessed, report));
+        lookupkey.finish(r.is_ok()).await;
+        self.finish_run(r).await
+    }
+
+    /// Log a summary of a run's results, then commit & write the report and
+    /// failures files as configured
+    async fn finish_run(
+        &self,
+        r: Result<(Vec<Downloadable>, Report), anyhow::Error>,
+    ) -> Result<Report, anyhow::Error> {
         match r {
-            Ok(((), (), report)) => {
-                log::info!("Downloaded {}", quantify(report.successful.len(), "file"));
-                if !report.failed.is_empty() {
-                    log::error!(
-                        "{} failed to download",
-                        quantify(report.failed.len(), "file")
+            Ok((unprocessed, mut report)) => {
+                if self.metadata_only {
+                    log::info!(
+                        "Updated {}",
+                        quantify(report.successful.len(), "existing file")
                     );
+                    if !report.failed.is_empty() {
+                        log::error!("{} failed", quantify(report.failed.len(), "item"));
+                    }
+                } else {
+                    let downloaded = report.successful.iter().filter(|r| r.downloaded()).count();
+                    log::info!("Downloaded {}", quantify(downloaded, "file"));
+                    let skipped = report.successful.len() - downloaded;
+                    if skipped > 0 {
+                        log::info!(
+                            "{} not downloaded due to existing paths",
+                            quantify(skipped, "file")
+                        );
+                    }
+                    if !report.failed.is_empty() {
+                        log::error!(
+                            "{} failed to download",
+                            quantify(report.failed.len(), "file")
+                        );
+                    }
                 }
+                if !unprocessed.is_empty() {
+                    log::warn!(
+                        "{} not processed due to interruption",
+                        quantify(unprocessed.len(), "item")
+                    );
+                }
+                report.unprocessed = unprocessed;
+                if let Some(ref save) = self.save {
+                    save_run(&self.repo, save, &report, &self.events).await?;
+                }
+                self.write_outputs(&report).await;
                 Ok(report)
             }
             Err(e) => Err(e),
         }
     }
 
-    async fn feed_addurl<I>(
+    /// Set the metadata & register the extra URLs for the files already at
+    /// the items' paths without downloading anything.  Returns the input
+    /// items that were not processed due to the run being stopped, along with
+    /// the report.
+    async fn update_existing<S>(
         &self,
-        items: I,
+        items: S,
+        journal: &Journal,
+        shutdown: &Shutdown,
+    ) -> Result<(Vec<Downloadable>, Report), anyhow::Error>
+    where
+        S: Stream<Item = Result<Downloadable, anyhow::Error>> + Send,
+    {
+        self.lookupkey()?
+            .in_context(
+                |lookupkey| async {
+                    let (sender, receiver) = channel(Self::RESULTS_QUEUE_SIZE);
+                    tokio::try_join!(
+                        self.feed_lookupkey(items, lookupkey, sender, shutdown),
+                        self.add_metadata(receiver, journal, &shutdown.kill),
+                    )
+                },
+                &shutdown.kill,
+            )
+            .await
+    }
+
+    // Returns the input items that were not processed due to the run being
+    // stopped
+    async fn feed_lookupkey<S>(
+        &self,
+        items: S,
+        mut lookupkey: AnnexIO<LookupKeyInput, Option<String>, LookupKeyDecoder>,
+        sender: Sender<DownloadResult>,
+        shutdown: &Shutdown,
+    ) -> Result<Vec<Downloadable>, anyhow::Error>
+    where
+        S: Stream<Item = Result<Downloadable, anyhow::Error>> + Send,
+    {
+        let mut items = pin!(items);
+        let mut seen = HashSet::new();
+        loop {
+            let dl = tokio::select! {
+                r = items.try_next() => match r? {
+                    Some(dl) => dl,
+                    None => break,
+                },
+                () = shutdown.stop.cancelled() => break,
+                () = shutdown.kill.cancelled() => anyhow::bail!("Run was forcibly terminated"),
+            };
+            if !seen.insert(dl.path.clone()) {
+                self.duplicate(dl, &sender).await;
+                continue;
+            }
+            let path = dl.path.clone();
+            let res = if let Some(key) = lookupkey.chat(LookupKeyInput(path.clone())).await? {
+                log::info!("Found {path} (key = {key})");
+                DownloadResult::not_downloaded(dl, Ok(()), Some(key), ExistingOutcome::MetadataOnly)
+            } else {
+                let msg = format!("{path} does not exist or is not an annexed file");
+                log::error!("{msg}");
+                let error = AnnexError(vec![msg]);
+                self.events.emit(Event::Failed {
+                    path,
+                    stage: Stage::LookupKey,
+                    error: error.clone(),
+                });
+                DownloadResult::not_downloaded(dl, Err(error), None, ExistingOutcome::MetadataOnly)
+            };
+            // TODO: Do something if send() fails
+            let _ = sender.send(res).await;
+        }
+        log::debug!("Done looking up keys");
+        let mut unprocessed = Vec::new();
+        if shutdown.is_stopped() {
+            log::info!("Collecting unprocessed input items ...");
+            loop {
+                tokio::select! {
+                    r = items.try_next() => match r? {
+                        Some(dl) => unprocessed.push(dl),
+                        None => break,
+                    },
+                    () = shutdown.kill.cancelled() => anyhow::bail!("Run was forcibly terminated"),
+                }
+            }
+        }
+        Ok(unprocessed)
+    }
+
+    /// Apply the duplicate-path policy to `dl`, whose path is the same as
+    /// that of an earlier item
+    async fn duplicate(&self, dl: Downloadable, sender: &Sender<DownloadResult>) {
+        if self.duplicates == DuplicatePolicy::Error {
+            let msg = format!("Multiple entries encountered downloading to {}", dl.path);
+            log::error!("{msg}");
+            let error = AnnexError(vec![msg]);
+            self.events.emit(Event::Failed {
+                path: dl.path.clone(),
+                stage: Stage::Download,
+                error: error.clone(),
+            });
+            let res = DownloadResult::failed_early(dl, error);
+            // TODO: Do something if send() fails
+            let _ = sender.send(res).await;
+        } else {
+            log::warn!(
+                "Multiple entries encountered downloading to {}; discarding extra",
+                dl.path,
+            );
+        }
+    }
+
+    async fn write_outputs(&self, report: &Report) {
+        if let Some(ref path) = self.report {
+            if let Err(e) = write_report(path, report).await {
+                log::error!("Error writing run report: {e}");
+            }
+        }
+        if let Some(ref path) = self.failures {
+            if !report.failed.is_empty() || !report.unprocessed.is_empty() {
+                if let Err(e) = write_failures(path, report).await {
+                    log::error!("Error writing failures report: {e}");
+                }
+            }
+        }
+    }
+
+    // Returns the input items that were not processed due to the run being
+    // stopped
+    #[allow(clippy::too_many_arguments)]
+    async fn feed_addurl<S>(
+        &self,
+        items: S,
         mut addurl_sink: AnnexSink<AddURLInput>,
+        lookupkey: &mut LazyAnnexProcess<'_, LookupKeyInput, Option<String>, LookupKeyDecoder>,
         in_progress: Arc<InProgress>,
-    ) -> Result<(), anyhow::Error>
+        mut requeue_receiver: UnboundedReceiver<(Downloadable, Duration)>,
+        sender: Sender<DownloadResult>,
+        urls: Option<SharedUrls>,
+        journal: &Journal,
+        shutdown: &Shutdown,
+    ) -> Result<Vec<Downloadable>, anyhow::Error>
     where
-        I: IntoIterator<Item = Downloadable> + Send,
-        I::IntoIter: Send,
+        S: Stream<Item = Result<Downloadable, anyhow::Error>> + Send,
     {
-        for dl in items {
-            if in_progress.add(&dl) {
-                log::info!("Downloading {} to {}", dl.url, dl.path);
-                addurl_sink
-                    .send(AddURLInput {
-                        url: dl.url.clone(),
-                        path: dl.path.clone(),
-                    })
-                    .await?;
-            } else {
-                log::warn!(
-                    "Multiple entries encountered downloading to {}; discarding extra",
-                    dl.path,
-                );
+        let mut items = pin!(items);
+        // Input paths seen so far, for detecting duplicates across the run
+        let mut seen = HashSet::new();
+        let mut exhausted = false;
+        let mut stopped = false;
+        let mut retries = DelayQueue::<Downloadable>::new();
+        let mut retry_keys = HashMap::new();
+        let mut scheduler = HostScheduler::new(&self.host_limits);
+        let mut unprocessed = Vec::new();
+        // Keep addurl's stdin open until every download (including any that
+        // are waiting to be retried) has finished.
+        loop {
+            let wakeup = scheduler.next_wakeup(Instant::now());
+            tokio::select! {
+                r = items.try_next(), if !exhausted && !stopped && scheduler.len() < Self::MAX_DEFERRED => {
+                    if let Some(dl) = r? {
+                        if !seen.insert(dl.path.clone()) {
+                            self.duplicate(dl, &sender).await;
+                        } else if let Some(key) = journal.downloaded(&dl.path) {
+                            log::info!(
+                                "{} was already downloaded according to journal; skipping download",
+                                dl.path
+                            );
+                            if let (Some(urls), Some(key)) = (&urls, key) {
+                                urls.resumed(&dl.url, key);
+                            }
+                            let renamed_to = journal.renamed(&dl.path).cloned();
+                            if let Some(ref newpath) = renamed_to {
+                                seen.insert(newpath.clone());
+                            }
+                            let res = DownloadResult::resumed_download(dl, key.clone(), renamed_to);
+                            // TODO: Do something if send() fails
+                            let _ = sender.send(res).await;
+                        } else {
+                            match self.resolve_existing(dl, &mut seen, lookupkey).await? {
+                                Resolution::Download(dl, existing, backup) => {
+                                    in_progress.add(&dl, existing, backup);
+                                    self.events.emit(Event::Queued {
+                                        path: dl.path.clone(),
+                                        url: dl.url.clone(),
+                                    });
+                                    if urls.as_ref().map_or(true, |urls| urls.claim(&dl)) {
+                                        let load = |host: &str| in_progress.host_load(host);
+                                        if let Some(dl) = scheduler.submit(dl, load, Instant::now()) {
+                                            start_download(&dl, &mut addurl_sink, &in_progress, &self.events).await?;
+                                        }
+                                    }
+                                }
+                                Resolution::Done(res) => {
+                                    if let Err(ref e) = res.download {
+                                        self.events.emit(Event::Failed {
+                                            path: res.downloadable.path.clone(),
+                                            stage: Stage::Download,
+                                            error: e.clone(),
+                                        });
+                                    }
+                                    // TODO: Do something if send() fails
+                                    let _ = sender.send(res).await;
+                                }
+                            }
+                        }
+                    } else {
+                        log::debug!("Reached end of input");
+                        exhausted = true;
+                    }
+                }
+                () = shutdown.stop.cancelled(), if !stopped => {
+                    log::warn!("Stopping; waiting for downloads in progress to finish ...");
+                    stopped = true;
+                    for key in std::mem::take(&mut retry_keys).into_values() {
+                        let dl = retries.remove(&key).into_inner();
+                        abandon(dl, &in_progress, urls.as_ref(), &mut unprocessed)?;
+                    }
+                    for dl in scheduler.drain() {
+                        abandon(dl, &in_progress, urls.as_ref(), &mut unprocessed)?;
+                    }
+                }
+                Some((dl, delay)) = requeue_receiver.recv() => {
+                    if stopped {
+                        abandon(dl, &in_progress, urls.as_ref(), &mut unprocessed)?;
+                    } else {
+                        let path = dl.path.clone();
+                        retry_keys.insert(path, retries.insert(dl, delay));
+                    }
+                }
+                Some(expired) = retries.next() => {
+                    let dl = expired.into_inner();
+                    retry_keys.remove(&dl.path);
+                    log::info!("Retrying download of {} to {}", dl.url, dl.path);
+                    // Retries are subject to the same per-host limits as
+                    // fresh items.
+                    let load = |host: &str| in_progress.host_load(host);
+                    if let Some(dl) = scheduler.submit(dl, load, Instant::now()) {
+                        start_download(&dl, &mut addurl_sink, &in_progress, &self.events).await?;
+                    }
+                }
+                () = in_progress.wait_freed(), if !scheduler.is_empty() => {
+                    let load = |host: &str| in_progress.host_load(host);
+                    for dl in scheduler.ready(load, Instant::now()) {
+                        start_download(&dl, &mut addurl_sink, &in_progress, &self.events).await?;
+                    }
+                }
+                () = sleep_until(wakeup.unwrap_or_else(Instant::now)), if wakeup.is_some() => {
+                    let load = |host: &str| in_progress.host_load(host);
+                    for dl in scheduler.ready(load, Instant::now()) {
+                        start_download(&dl, &mut addurl_sink, &in_progress, &self.events).await?;
+                    }
+                }
+                () = in_progress.wait_empty(), if exhausted || stopped => break,
+                () = shutdown.kill.cancelled() => anyhow::bail!("Run was forcibly terminated"),
             }
         }
         log::debug!("Done feeding URLs to addurl");
-        Ok(())
+        if stopped && !exhausted {
+            log::info!("Collecting unprocessed input items ...");
+            loop {
+                tokio::select! {
+                    r = items.try_next() => match r? {
+                        Some(dl) => unprocessed.push(dl),
+                        None => break,
+                    },
+                    () = shutdown.kill.cancelled() => anyhow::bail!("Run was forcibly terminated"),
+                }
+            }
+        }
+        Ok(unprocessed)
+    }
+
+    /// Apply the existing-path policy for `dl`, if any.  `seen` is the set of
+    /// paths used by input items so far; paths generated for
+    /// [`ExistingPolicy::RenameWithSuffix`] are added to it so that they're
+    /// not reused.  Whether an existing file is annexed is determined with
+    /// `lookupkey`, which is thus only started once an existing path is
+    /// found.
+    async fn resolve_existing(
+        &self,
+        dl: Downloadable,
+        seen: &mut HashSet<FilePath>,
+        lookupkey: &mut LazyAnnexProcess<'_, LookupKeyInput, Option<String>, LookupKeyDecoder>,
+    ) -> Result<Resolution, anyhow::Error> {
+        let Some(policy) = dl.on_existing.or(self.on_existing) else {
+            return Ok(Resolution::Download(dl, None, None));
+        };
+        let status = path_status(&self.repo, &dl.path).await?;
+        if !status.exists() {
+            return Ok(Resolution::Download(dl, None, None));
+        }
+        let path = &dl.path;
+        match policy {
+            ExistingPolicy::Skip => {
+                log::info!("{path} already exists; skipping");
+                Ok(Resolution::done(dl, Ok(()), None, ExistingOutcome::Skipped))
+            }
+            ExistingPolicy::RegisterUrlOnly => {
+                let key = if status == PathStatus::File {
+                    lookupkey.chat(LookupKeyInput(path.clone())).await?
+                } else {
+                    None
+                };
+                if key.is_some() {
+                    log::info!("{path} already exists; registering URLs for it");
+                    Ok(Resolution::done(
+                        dl,
+                        Ok(()),
+                        key,
+                        ExistingOutcome::RegisteredUrl,
+                    ))
+                } else {
+                    let msg = format!("{path} already exists and is not an annexed file");
+                    log::error!("{msg}");
+                    Ok(Resolution::done(
+                        dl,
+                        Err(AnnexError(vec![msg])),
+                        None,
+                        ExistingOutcome::RegisteredUrl,
+                    ))
+                }
+            }
+            ExistingPolicy::Replace => {
+                if status == PathStatus::Directory {
+                    let msg = format!("{path} is a directory; not replacing");
+                    log::error!("{msg}");
+                    return Ok(Resolution::done(
+                        dl,
+                        Err(AnnexError(vec![msg])),
+                        None,
+                        ExistingOutcome::Replaced,
+                    ));
+                }
+                match Backup::create(&self.repo, path) {
+                    Ok(backup) => {
+                        log::info!("{path} already exists; replacing");
+                        Ok(Resolution::Download(
+                            dl,
+                            Some(ExistingOutcome::Replaced),
+                            Some(backup),
+                        ))
+                    }
+                    Err(msg) => {
+                        log::error!("{msg}");
+                        Ok(Resolution::done(
+                            dl,
+                            Err(AnnexError(vec![msg])),
+                            None,
+                            ExistingOutcome::Replaced,
+                        ))
+                    }
+                }
+            }
+            ExistingPolicy::RenameWithSuffix => {
+                let mut n = 1;
+                let newpath = loop {
+                    let candidate = with_suffix(path, n);
+                    if !seen.contains(&candidate)
+                        && !path_status(&self.repo, &candidate).await?.exists()
+                    {
+                        seen.insert(candidate.clone());
+                        break candidate;
+                    }
+                    n += 1;
+                };
+                log::info!("{path} already exists; downloading to {newpath} instead");
+                let from = path.clone();
+                Ok(Resolution::Download(
+                    Downloadable {
+                        path: newpath,
+                        ..dl
+                    },
+                    Some(ExistingOutcome::Renamed { from }),
+                    None,
+                ))
+            }
+            ExistingPolicy::Error => {
+                let msg = format!("{path} already exists");
+                log::error!("{msg}");
+                Ok(Resolution::done(
+                    dl,
+                    Err(AnnexError(vec![msg])),
+                    None,
+                    ExistingOutcome::Refused,
+                ))
+            }
+        }
     }
 
     async fn read_addurl(
         &self,
         mut addurl_stream: AnnexStream<AddURLOutput>,
         in_progress: Arc<InProgress>,
-        sender: UnboundedSender<DownloadResult>,
+        sender: Sender<DownloadResult>,
+        requeue_sender: UnboundedSender<(Downloadable, Duration)>,
+        urls: Option<SharedUrls>,
+        journal: &Journal,
     ) -> Result<(), anyhow::Error> {
         while let Some(r) = addurl_stream
             .try_next()
@@ -188,29 +1020,80 @@ impl Gamdam {
                     total_size,
                     percent_progress,
                     ..
-                }) => log::info!(
-                    "{}: Downloaded {} / {} bytes ({})",
-                    file,
-                    byte_progress,
-                    total_size.map_or("???".into(), |i| i.to_string()),
-                    percent_progress.unwrap_or_else(|| "??.??%".into()),
-                ),
+                }) => {
+                    log::info!(
+                        target: PROGRESS_LOG_TARGET,
+                        "{}: Downloaded {} / {} bytes ({})",
+                        file,
+                        byte_progress,
+                        total_size.map_or_else(|| "???".into(), |i| i.to_string()),
+                        percent_progress.unwrap_or_else(|| "??.??%".into()),
+                    );
+                    in_progress.progress(&file, byte_progress);
+                    self.events.emit(Event::Progress {
+                        path: file,
+                        bytes: byte_progress,
+                        total: total_size,
+                    });
+                }
                 Ok(AddURLOutput::Completion { key, .. }) => {
                     log::info!(
                         "Finished downloading {file} (key = {})",
                         key.clone().unwrap_or_else(|| "<none>".into())
                     );
-                    let downloadable = in_progress.pop(&file)?;
-                    let res = DownloadResult::successful_download(downloadable, key);
+                    let entry = in_progress.pop(&file)?;
+                    if let Some(ref urls) = urls {
+                        urls.finish(&entry.downloadable.url, Ok(key.clone()));
+                    }
+                    self.events.emit(Event::Downloaded {
+                        path: file.clone(),
+                        key: key.clone(),
+                    });
+                    journal
+                        .record(JournalEntry::Download {
+                            path: entry.input_path().clone(),
+                            url: entry.downloadable.url.clone(),
+                            key: key.clone(),
+                            renamed_to: (entry.input_path() != &file).then_some(file),
+                        })
+                        .await?;
+                    let res = DownloadResult::successful_download(entry, key);
                     // TODO: Do something if send() fails
-                    let _ = sender.send(res);
+                    let _ = sender.send(res).await;
                 }
                 Err(e) => {
+                    let failures = in_progress.failures(&file)? + 1;
+                    if let Some(delay) = self.retry.delay(failures).filter(|_| e.is_transient()) {
+                        log::warn!(
+                            "{file}: download attempt {failures} of {} failed; retrying in {:.1}s:{e}",
+                            self.retry.max_attempts,
+                            delay.as_secs_f64(),
+                        );
+                        self.events.emit(Event::Retrying {
+                            path: file.clone(),
+                            attempt: failures,
+                            delay,
+                            error: e.clone(),
+                        });
+                        let dl = in_progress.record_failure(&file, e)?;
+                        // The receiver is only dropped once in_progress is
+                        // empty, which it can't be while `file` is in it.
+                        let _ = requeue_sender.send((dl, delay));
+                        continue;
+                    }
                     log::error!("{file}: download failed:{e}");
-                    let downloadable = in_progress.pop(&file)?;
-                    let res = DownloadResult::failed_download(downloadable, e);
+                    self.events.emit(Event::Failed {
+                        path: file.clone(),
+                        stage: Stage::Download,
+                        error: e.clone(),
+                    });
+                    let entry = in_progress.pop(&file)?;
+                    if let Some(ref urls) = urls {
+                        urls.finish(&entry.downloadable.url, Err(e.clone()));
+                    }
+                    let res = DownloadResult::failed_download(entry, e);
                     // TODO: Do something if send() fails
-                    let _ = sender.send(res);
+                    let _ = sender.send(res).await;
                 }
             }
         }
@@ -218,70 +1101,223 @@ impl Gamdam {
         Ok(())
     }
 
-    async fn add_metadata(
+    /// Point the paths of items whose URLs were downloaded for other items at
+    /// the resulting keys
+    async fn copy_keys(
         &self,
-        mut receiver: UnboundedReceiver<DownloadResult>,
-        mut metadata: AnnexIO<MetadataInput, MetadataOutput>,
-        mut registerurl: AnnexIO<RegisterURLInput, RegisterURLOutput>,
-    ) -> Result<Report, anyhow::Error> {
-        let mut successful = Vec::new();
-        let mut failed = Vec::new();
-        while let Some(mut r) = receiver.recv().await {
-            let path = &r.downloadable.path;
-            if r.download.is_err() {
-                failed.push(r);
-            } else if let Some(ref key) = r.key {
-                let mut success = true;
-                if !r.downloadable.metadata.is_empty() {
-                    log::info!("Setting metadata for {path} ...");
-                    let input = MetadataInput {
-                        key: key.clone(),
-                        fields: r.downloadable.metadata.clone(),
-                    };
-                    match metadata.chat(input).await?.check() {
-                        Ok(_) => {
-                            log::info!("Set metadata on {path}");
-                            r.metadata_added = Some(Ok(()));
-                        }
-                        Err(e) => {
-                            log::error!("{path}: setting metadata failed:{e}");
-                            r.metadata_added = Some(Err(e));
-                            success = false;
-                        }
-                    }
-                }
-                for u in &r.downloadable.extra_urls {
-                    log::info!("Registering URL {u} for {path} ...");
-                    let input = RegisterURLInput {
-                        key: key.clone(),
-                        url: u.clone(),
-                    };
-                    match registerurl.chat(input).await?.check() {
-                        Ok(_) => {
-                            log::info!("Registered URL {u} for {path}");
-                            r.urls_added.insert(u.clone(), Ok(()));
-                        }
-                        Err(e) => {
-                            log::error!("{path}: registering URL {u} failed:{e}");
-                            r.urls_added.insert(u.clone(), Err(e));
-                            success = false;
-                        }
+        mut receiver: UnboundedReceiver<CopyJob>,
+        in_progress: Arc<InProgress>,
+        sender: Sender<DownloadResult>,
+        journal: &Journal,
+        kill: &CancellationToken,
+    ) -> Result<(), anyhow::Error> {
+        if !self.dedupe_urls {
+            return Ok(());
+        }
+        self.fromkey()?
+            .in_context(
+                |mut fromkey| async move {
+                    while let Some(CopyJob { path, key }) = receiver.recv().await {
+                        let res = match key {
+                            Ok(key) => {
+                                let input = FromKeyInput {
+                                    key: key.clone(),
+                                    path: path.clone(),
+                                };
+                                let r = fromkey
+                                    .chat(input)
+                                    .await
+                                    .context("Error communicating with `git-annex fromkey`")?
+                                    .check();
+                                let entry = in_progress.pop(&path)?;
+                                match r {
+                                    Ok(_) => {
+                                        log::info!(
+                                            "Pointed {path} at already-downloaded {} (key = {key})",
+                                            entry.downloadable.url
+                                        );
+                                        self.events.emit(Event::Downloaded {
+                                            path: path.clone(),
+                                            key: Some(key.clone()),
+                                        });
+                                        journal
+                                            .record(JournalEntry::Download {
+                                                path: entry.input_path().clone(),
+                                                url: entry.downloadable.url.clone(),
+                                                key: Some(key.clone()),
+                                                renamed_to: (entry.input_path() != &path)
+                                                    .then_some(path),
+                                            })
+                                            .await?;
+                                        DownloadResult::successful_download(entry, Some(key))
+                                    }
+                                    Err(e) => {
+                                        log::error!("{path}: pointing at key {key} failed:{e}");
+                                        self.events.emit(Event::Failed {
+                                            path,
+                                            stage: Stage::Download,
+                                            error: e.clone(),
+                                        });
+                                        DownloadResult::failed_download(entry, e)
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                let entry = in_progress.pop(&path)?;
+                                log::error!(
+                                    "{path}: download of {} for another path failed:{e}",
+                                    entry.downloadable.url
+                                );
+                                self.events.emit(Event::Failed {
+                                    path,
+                                    stage: Stage::Download,
+                                    error: e.clone(),
+                                });
+                                DownloadResult::failed_download(entry, e)
+                            }
+                        };
+                        // TODO: Do something if send() fails
+                        let _ = sender.send(res).await;
                     }
+                    log::debug!("Done pointing paths at downloaded keys");
+                    Ok(())
+                },
+                kill,
+            )
+            .await
+    }
+
+    /// Set the metadata fields `fields` and apply the metadata operations
+    /// `ops` on the key `key`.  If any of the operations depend on the key's
+    /// current metadata, that is read first.
+    async fn set_metadata(
+        &self,
+        metadata: &mut AnnexIO<MetadataInput, MetadataOutput>,
+        fields: &HashMap<String, Vec<String>>,
+        ops: &HashMap<String, MetadataOp>,
+        key: &str,
+    ) -> Result<Result<(), AnnexError>, anyhow::Error> {
+        let current = if ops.values().any(MetadataOp::needs_current) {
+            let input = MetadataInput {
+                key: key.to_owned(),
+                fields: HashMap::new(),
+            };
+            match metadata.chat(input).await?.check() {
+                Ok(output) => output.fields,
+                Err(e) => return Ok(Err(e)),
+            }
+        } else {
+            HashMap::new()
+        };
+        let fields = resolve_fields(&current, fields, ops);
+        if fields.is_empty() {
+            // Every operation was a `SetIfUnset` on a field that's already set
+            return Ok(Ok(()));
+        }
+        let input = MetadataInput {
+            key: key.to_owned(),
+            fields,
+        };
+        Ok(metadata.chat(input).await?.check().map(|_| ()))
+    }
+
+    /// Register `url` for `key` with `git-annex registerurl`.  `path` is the
+    /// file that the key belongs to, if known; it is used in log messages,
+    /// events, and the journal.
+    async fn register_url(
+        &self,
+        registerurl: &mut AnnexIO<RegisterURLInput, RegisterURLOutput>,
+        path: Option<&FilePath>,
+        key: &str,
+        url: &Url,
+        journal: &Journal,
+    ) -> Result<Result<(), AnnexError>, anyhow::Error> {
+        let label = path.map_or_else(|| key.to_owned(), ToString::to_string);
+        if path.is_some_and(|p| journal.url_registered(p, url)) {
+            log::info!(
+                "URL {url} was already registered for {label} according to journal; skipping"
+            );
+            return Ok(Ok(()));
+        }
+        log::info!("Registering URL {url} for {label} ...");
+        let input = RegisterURLInput {
+            key: key.to_owned(),
+            url: url.clone(),
+        };
+        match registerurl.chat(input).await?.check() {
+            Ok(_) => {
+                log::info!("Registered URL {url} for {label}");
+                if let Some(path) = path {
+                    self.events.emit(Event::UrlRegistered {
+                        path: path.clone(),
+                        key: key.to_owned(),
+                        url: url.clone(),
+                    });
+                    journal
+                        .record(JournalEntry::RegisterURL {
+                            path: path.clone(),
+                            key: key.to_owned(),
+                            url: url.clone(),
+                        })
+                        .await?;
                 }
-                if success {
-                    successful.push(r);
-                } else {
-                    failed.push(r);
+                Ok(Ok(()))
+            }
+            Err(e) => {
+                log::error!("{label}: registering URL {url} failed:{e}");
+                if let Some(path) = path {
+                    self.events.emit(Event::Failed {
+                        path: path.clone(),
+                        stage: Stage::RegisterUrl { url: url.clone() },
+                        error: e.clone(),
+                    });
                 }
-            } else {
-                if !r.downloadable.metadata.is_empty() || !r.downloadable.extra_urls.is_empty() {
-                    log::warn!("Cannot set metadata for {path} as it was not assigned a key");
+                Ok(Err(e))
+            }
+        }
+    }
+
+    /// Check the content downloaded for `r` against its expected size &
+    /// checksums
+    async fn verify(&self, r: &DownloadResult) -> Result<(), AnnexError> {
+        let path = &r.downloadable.path;
+        let mut mismatched = false;
+        let msgs = match r.key {
+            Some(ref key) => {
+                match verify_content(&self.repo, path, key, &r.downloadable.expected).await {
+                    Ok(mismatches) if mismatches.is_empty() => {
+                        log::info!("Verified content of {path}");
+                        return Ok(());
+                    }
+                    Ok(mismatches) => {
+                        mismatched = true;
+                        mismatches.iter().map(ToString::to_string).collect()
+                    }
+                    Err(e) => vec![format!("error reading content to verify it: {e}")],
                 }
-                successful.push(r);
+            }
+            None => vec![String::from(
+                "no key was assigned to verify the content against",
+            )],
+        };
+        let e = AnnexError(msgs);
+        log::error!("{path}: verification failed:{e}");
+        self.events.emit(Event::Failed {
+            path: path.clone(),
+            stage: Stage::Verify,
+            error: e.clone(),
+        });
+        if let Some(key) = r
+            .key
+            .as_deref()
+            .filter(|_| mismatched && self.drop_mismatched)
+        {
+            match drop_content(&self.repo, path, key).await {
+                Ok(()) => log::info!("Removed {path} and dropped its content"),
+                Err(e) => log::warn!("Failed to remove {path} with mismatched content: {e}"),
             }
         }
-        log::debug!("Done post-processing metadata");
-        Ok(Report { successful, failed })
+        Err(e)
     }
 
     fn addurl(&self) -> Result<AnnexProcess<AddURLInput, AddURLOutput>, anyhow::Error> {
@@ -316,37 +1352,297 @@ impl Gamdam {
             &self.repo,
         )
     }
+
+    fn lookupkey(
+        &self,
+    ) -> Result<AnnexProcess<LookupKeyInput, Option<String>, LookupKeyDecoder>, anyhow::Error> {
+        AnnexProcess::new("lookupkey", ["--batch"], &self.repo)
+    }
+
+    fn lazy_lookupkey<'a>(
+        &'a self,
+        kill: &'a CancellationToken,
+    ) -> LazyAnnexProcess<'a, LookupKeyInput, Option<String>, LookupKeyDecoder> {
+        LazyAnnexProcess::new("lookupkey", &["--batch"], &self.repo, kill)
+    }
+
+    fn fromkey(&self) -> Result<AnnexProcess<FromKeyInput, FromKeyOutput>, anyhow::Error> {
+        AnnexProcess::new(
+            "fromkey",
+            ["--batch", "--json", "--json-error-messages"],
+            &self.repo,
+        )
+    }
 }
 
+/// Send `dl` to `git-annex addurl` and mark it as started
+async fn start_download(
+    dl: &Downloadable,
+    addurl_sink: &mut AnnexSink<AddURLInput>,
+    in_progress: &InProgress,
+    events: &EventSender,
+) -> Result<(), anyhow::Error> {
+    in_progress.start(&dl.path);
+    log::info!("Downloading {} to {}", dl.url, dl.path);
+    events.emit(Event::Started {
+        path: dl.path.clone(),
+        url: dl.url.clone(),
+    });
+    addurl_sink.send(AddURLInput::from(dl)).await?;
+    Ok(())
+}
+
+/// Add `dl`, which will not be downloaded due to the run being stopped, to
+/// `unprocessed` along with any items waiting to be copied from it
+fn abandon(
+    dl: Downloadable,
+    in_progress: &InProgress,
+    urls: Option<&SharedUrls>,
+    unprocessed: &mut Vec<Downloadable>,
+) -> Result<(), anyhow::Error> {
+    in_progress.pop(&dl.path)?;
+    let waiting = urls.map(|urls| urls.abandon(&dl.url)).unwrap_or_default();
+    unprocessed.push(dl);
+    for path in waiting {
+        unprocessed.push(in_progress.pop(&path)?.downloadable);
+    }
+    Ok(())
+}
+
+/// Items that have been accepted from the input but not yet finished
+/// downloading, including items held back by per-host limits and items
+/// waiting to be retried
 struct InProgress {
-    data: Mutex<HashMap<FilePath, Downloadable>>,
+    data: Mutex<InProgressData>,
+    emptied: Notify,
+    freed: Notify,
+}
+
+#[derive(Default)]
+struct InProgressData {
+    entries: HashMap<FilePath, InProgressEntry>,
+    /// Number of started downloads per host
+    host_load: HashMap<String, usize>,
+}
+
+/// The result of applying the existing-path policy to an input item
+// Values are consumed as soon as they're created, so boxing isn't worth it.
+#[allow(clippy::large_enum_variant)]
+enum Resolution {
+    /// Download the item, having done the given action about an existing
+    /// file.  If the existing file is being replaced, it has been moved aside
+    /// to the given backup.
+    Download(Downloadable, Option<ExistingOutcome>, Option<Backup>),
+    /// Don't download the item; this is its result
+    Done(DownloadResult),
+}
+
+impl Resolution {
+    fn done(
+        dl: Downloadable,
+        download: Result<(), AnnexError>,
+        key: Option<String>,
+        existing: ExistingOutcome,
+    ) -> Resolution {
+        Resolution::Done(DownloadResult::not_downloaded(dl, download, key, existing))
+    }
+}
+
+struct InProgressEntry {
+    downloadable: Downloadable,
+    existing: Option<ExistingOutcome>,
+    failed_attempts: Vec<AnnexError>,
+    /// The existing file at the item's path that is being replaced.  It is
+    /// moved back into place when the entry is dropped unless the download
+    /// succeeded.
+    backup: Option<Backup>,
+    /// When the item was first sent to `git-annex addurl`; `None` if it is
+    /// still being held back
+    started: Option<DateTime<Utc>>,
+    /// Whether the item is currently being downloaded by `git-annex addurl`
+    /// (as opposed to being held back or waiting to be retried) and thus
+    /// counts against its host's load
+    active: bool,
+    bytes: Option<u64>,
+}
+
+impl InProgressEntry {
+    fn new(
+        downloadable: Downloadable,
+        existing: Option<ExistingOutcome>,
+        backup: Option<Backup>,
+    ) -> InProgressEntry {
+        InProgressEntry {
+            downloadable,
+            existing,
+            failed_attempts: Vec::new(),
+            backup,
+            started: None,
+            active: false,
+            bytes: None,
+        }
+    }
+
+    /// Returns the item's path as given in the input, before any renaming
+    /// by the existing-path policy
+    fn input_path(&self) -> &FilePath {
+        match self.existing {
+            Some(ExistingOutcome::Renamed { ref from }) => from,
+            _ => &self.downloadable.path,
+        }
+    }
+
+    fn timings(&self) -> Timings {
+        Timings {
+            started: self.started,
+            downloaded: Some(Utc::now()),
+            finished: None,
+        }
+    }
 }
 
 impl InProgress {
     fn new() -> Self {
         InProgress {
-            data: Mutex::new(HashMap::new()),
+            data: Mutex::new(InProgressData::default()),
+            emptied: Notify::new(),
+            freed: Notify::new(),
         }
     }
 
-    fn add(&self, dl: &Downloadable) -> bool {
+    fn add(
+        &self,
+        dl: &Downloadable,
+        existing: Option<ExistingOutcome>,
+        backup: Option<Backup>,
+    ) -> bool {
         let mut data = self.data.lock().expect("Mutex should not be poisoned");
-        match data.entry(dl.path.clone()) {
+        match data.entries.entry(dl.path.clone()) {
             Entry::Occupied(_) => false,
             Entry::Vacant(v) => {
-                v.insert(dl.clone());
+                v.insert(InProgressEntry::new(dl.clone(), existing, backup));
                 true
             }
         }
     }
 
-    fn pop(&self, file: &FilePath) -> Result<Downloadable, anyhow::Error> {
+    /// Mark `file` as having been sent to `git-annex addurl`, counting it
+    /// against its host's load
+    fn start(&self, file: &FilePath) {
+        let mut data = self.data.lock().expect("Mutex should not be poisoned");
+        let InProgressData {
+            ref mut entries,
+            ref mut host_load,
+        } = *data;
+        if let Some(entry) = entries.get_mut(file) {
+            entry.started.get_or_insert_with(Utc::now);
+            if !entry.active {
+                entry.active = true;
+                *host_load
+                    .entry(host_of(&entry.downloadable).to_owned())
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    /// Number of started downloads for `host` that have not yet finished
+    fn host_load(&self, host: &str) -> usize {
+        let data = self.data.lock().expect("Mutex should not be poisoned");
+        data.host_load.get(host).copied().unwrap_or(0)
+    }
+
+    fn progress(&self, file: &FilePath, byte_progress: usize) {
         let mut data = self.data.lock().expect("Mutex should not be poisoned");
-        match data.remove(file) {
-            Some(dl) => Ok(dl),
+        if let Some(entry) = data.entries.get_mut(file) {
+            entry.bytes = u64::try_from(byte_progress).ok();
+        }
+    }
+
+    fn failures(&self, file: &FilePath) -> Result<usize, anyhow::Error> {
+        let data = self.data.lock().expect("Mutex should not be poisoned");
+        match data.entries.get(file) {
+            Some(entry) => Ok(entry.failed_attempts.len()),
             None => anyhow::bail!("No record found for download of {file}"),
         }
     }
+
+    /// Record a failed attempt to download `file` while keeping the entry in
+    /// progress so that it can be retried.  The entry stops counting against
+    /// its host's load until it is started again.
+    fn record_failure(
+        &self,
+        file: &FilePath,
+        err: AnnexError,
+    ) -> Result<Downloadable, anyhow::Error> {
+        let mut data = self.data.lock().expect("Mutex should not be poisoned");
+        let InProgressData {
+            ref mut entries,
+            ref mut host_load,
+        } = *data;
+        match entries.get_mut(file) {
+            Some(entry) => {
+                entry.failed_attempts.push(err);
+                if entry.active {
+                    entry.active = false;
+                    if let Some(load) = host_load.get_mut(host_of(&entry.downloadable)) {
+                        *load = load.saturating_sub(1);
+                    }
+                    self.freed.notify_one();
+                }
+                Ok(entry.downloadable.clone())
+            }
+            None => anyhow::bail!("No record found for download of {file}"),
+        }
+    }
+
+    fn pop(&self, file: &FilePath) -> Result<InProgressEntry, anyhow::Error> {
+        let mut data = self.data.lock().expect("Mutex should not be poisoned");
+        match data.entries.remove(file) {
+            Some(entry) => {
+                if entry.active {
+                    let host = host_of(&entry.downloadable);
+                    if let Some(load) = data.host_load.get_mut(host) {
+                        *load = load.saturating_sub(1);
+                    }
+                }
+                if data.entries.is_empty() {
+                    self.emptied.notify_waiters();
+                }
+                // There is only ever one waiter (the feeder), so use
+                // notify_one() in order to store a permit if it isn't
+                // currently waiting.
+                self.freed.notify_one();
+                Ok(entry)
+            }
+            None => anyhow::bail!("No record found for download of {file}"),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data
+            .lock()
+            .expect("Mutex should not be poisoned")
+            .entries
+            .is_empty()
+    }
+
+    async fn wait_empty(&self) {
+        loop {
+            // Create the `Notified` before checking so that a notification
+            // sent in between isn't missed
+            let notified = self.emptied.notified();
+            if self.is_empty() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Wait until an item is removed or stops counting against its host's
+    /// load
+    async fn wait_freed(&self) {
+        self.freed.notified().await;
+    }
 }
 
 pub async fn ensure_annex_repo<P: AsRef<Path> + Send>(repo: P) -> Result<(), anyhow::Error> {
@@ -398,6 +1694,7 @@ fn quantify(n: usize, noun: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rstest::rstest;
 
     #[test]
     fn test_load_downloadable_defaults() {
@@ -409,9 +1706,68 @@ mod tests {
                 path: FilePath::try_from("foo/bar/baz.txt").unwrap(),
                 url: Url::parse("https://example.com/baz.txt").unwrap(),
                 metadata: HashMap::new(),
+                metadata_ops: HashMap::new(),
                 extra_urls: Vec::new(),
+                on_existing: None,
+                original_path: None,
+                expected: ExpectedContent::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_load_downloadable_expected_content() {
+        let s = r#"{"path": "baz.txt", "url": "https://example.com/baz.txt", "size": 19, "sha256": "6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191"}"#;
+        let parsed = serde_json::from_str::<Downloadable>(s).unwrap();
+        assert_eq!(
+            parsed.expected,
+            ExpectedContent {
+                size: Some(19),
+                sha256: Some(
+                    "6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191".into()
+                ),
+                ..ExpectedContent::default()
             }
         );
+        let json = serde_json::to_string(&parsed).unwrap();
+        assert_eq!(serde_json::from_str::<Downloadable>(&json).unwrap(), parsed);
+    }
+
+    #[rstest]
+    #[case(0, None)]
+    #[case(1, Some(Duration::from_secs(2)))]
+    #[case(2, Some(Duration::from_secs(4)))]
+    #[case(3, Some(Duration::from_secs(8)))]
+    #[case(4, Some(Duration::from_secs(10)))]
+    #[case(5, None)]
+    fn test_retry_delay(#[case] failures: usize, #[case] delay: Option<Duration>) {
+        let policy = RetryPolicy {
+            max_attempts: NonZeroUsize::new(5).unwrap(),
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+        };
+        assert_eq!(policy.delay(failures), delay);
+    }
+
+    #[test]
+    fn test_in_progress_retry_frees_host() {
+        let in_progress = InProgress::new();
+        let dl = serde_json::from_str::<Downloadable>(
+            r#"{"path": "a.txt", "url": "https://example.com/a.txt"}"#,
+        )
+        .unwrap();
+        assert!(in_progress.add(&dl, None, None));
+        in_progress.start(&dl.path);
+        assert_eq!(in_progress.host_load("example.com"), 1);
+        in_progress
+            .record_failure(&dl.path, AnnexError(vec!["timed out".into()]))
+            .unwrap();
+        assert_eq!(in_progress.host_load("example.com"), 0);
+        in_progress.start(&dl.path);
+        assert_eq!(in_progress.host_load("example.com"), 1);
+        let entry = in_progress.pop(&dl.path).unwrap();
+        assert_eq!(entry.failed_attempts.len(), 1);
+        assert_eq!(in_progress.host_load("example.com"), 0);
     }
 
     #[test]
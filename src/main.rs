@@ -1,15 +1,31 @@
+mod progress;
+use crate::progress::ProgressDisplay;
 use anyhow::Context;
 use clap::builder::ArgAction;
-use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
-use gamdam::cmd::{CommandError, LoggedCommand};
-use gamdam::{ensure_annex_repo, DownloadResult, Downloadable, Gamdam, Jobs};
+use clap::{Parser, ValueEnum};
+use futures_util::{future, future::Either, Stream, StreamExt};
+use gamdam::{
+    check_field_name, read_table, AutoField, BadMetadataPolicy, CollisionKind, CollisionPolicies,
+    CollisionPolicy, ColumnMapping, Downloadable, DuplicatePolicy, ExistingPolicy, Gamdam,
+    HostLimit, HostLimits, InputItem, Jobs, MetadataDefaults, MirrorItem, ParseCollisionError,
+    ParseHostLimitError, PathOptions, PathTemplate, RetryPolicy, Shutdown, TableError,
+    PROGRESS_LOG_TARGET,
+};
+use indicatif::MultiProgress;
 use patharg::{InputArg, OutputArg};
-use serde_jsonlines::{AsyncBufReadJsonLines, AsyncWriteJsonLines};
+use serde::de::DeserializeOwned;
+use serde_jsonlines::AsyncBufReadJsonLines;
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{IsTerminal, Read};
 use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::pin::pin;
 use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio::io::BufReader;
+use tokio::sync::mpsc::unbounded_channel;
 
 /// Git-Annex Mass Downloader and Metadata-er
 ///
@@ -32,6 +48,24 @@ struct Arguments {
     // not treat the option as multiuse.
     addurl_opts: Option<std::vec::Vec<String>>,
 
+    /// Set a metadata field to an automatically-determined value on every
+    /// downloaded item that does not set the field itself
+    ///
+    /// KIND is "downloaded" (the time at which the item finished
+    /// downloading), "source-url" (the item's URL), "gamdam-version", or
+    /// "input-file" (the path of the input file, if not reading from stdin).
+    /// The field is named after KIND unless `=FIELD` is given.  This option
+    /// may be given multiple times.
+    #[arg(long, value_name = "KIND[=FIELD]")]
+    auto_metadata: Vec<AutoField>,
+
+    /// Also check input paths for collisions with the files already
+    /// committed to the repository
+    ///
+    /// Only has an effect when `--on-collision` is given.
+    #[arg(long)]
+    check_repo_collisions: bool,
+
     /// git-annex repository to operate in  [default: current directory]
     ///
     /// If the given directory does not exist, it is created.  If it is not
@@ -39,10 +73,92 @@ struct Arguments {
     #[arg(short = 'C', long = "chdir", value_name = "DIR", default_value_os_t = PathBuf::from("."), hide_default_value = true)]
     repo: PathBuf,
 
+    /// Commit the downloaded files after every INT files are successfully
+    /// downloaded, in addition to at the end of the run
+    #[arg(long, value_name = "INT")]
+    commit_every: Option<NonZeroUsize>,
+
+    /// Commit the files downloaded so far at the given interval (in
+    /// seconds), in addition to at the end of the run
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    commit_interval: Option<Duration>,
+
+    /// Download each distinct URL in the input only once
+    ///
+    /// Items whose URLs were already downloaded for another path are added
+    /// by pointing their paths at the same git-annex key with `git-annex
+    /// fromkey`.  Each item's metadata and extra URLs are still applied, but
+    /// as git-annex metadata belongs to the key, items that share a URL also
+    /// share their metadata.
+    #[arg(long)]
+    dedupe_urls: bool,
+
+    /// Also apply `--metadata` and `--auto-metadata` fields to items that
+    /// aren't downloaded because their paths already exist, including all
+    /// items with `--metadata-only`
+    #[arg(long)]
+    defaults_for_existing: bool,
+
+    /// Remove files whose content doesn't match the "size" or checksum
+    /// fields of their input items from the repository, and drop their
+    /// content
+    ///
+    /// By default, such items are failed, but their files are kept.
+    #[arg(long)]
+    drop_mismatched: bool,
+
+    /// Check the input against the repository and report what would be
+    /// downloaded, skipped, or in conflict, without downloading anything
+    ///
+    /// Exits nonzero if any input lines are invalid or any items conflict
+    /// with existing files.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Name of the column containing extra URLs in CSV/TSV input
+    #[arg(long, default_value = "extra_urls", value_name = "NAME")]
+    extra_urls_column: String,
+
+    /// Character separating the URLs in the extra URLs column of CSV/TSV
+    /// input  [default: whitespace]
+    #[arg(long, value_name = "CHAR")]
+    extra_urls_separator: Option<char>,
+
     /// Write failed download items to the given file
     #[arg(short = 'F', long = "failures", value_name = "FILE")]
     failures: Option<OutputArg>,
 
+    /// Limit the downloads from each host
+    ///
+    /// LIMIT is `jobs=<INT>` (maximum number of downloads from a host in
+    /// progress at once), `rate=<NUM>` (maximum number of downloads started
+    /// per second for a host), or both, separated by a comma.  Prefix LIMIT
+    /// with `<HOST>:` to apply it only to the given host; fields not set for
+    /// a specific host fall back to those given without a host.  This option
+    /// may be given multiple times.
+    #[arg(long, value_name = "[HOST:]LIMIT", value_parser = parse_host_limit)]
+    host_limit: Vec<HostLimitArg>,
+
+    /// Format of the input
+    ///
+    /// CSV and TSV input must start with a header row.  The URL, path, and
+    /// extra URLs are taken from the columns named by `--url-column`,
+    /// `--path-column`, and `--extra-urls-column`, and the `on_existing`,
+    /// `size`, `md5`, `sha1`, `sha256`, and `sha512` columns are read as the
+    /// JSON fields of the same names; every other column becomes a metadata
+    /// field named after the column.  Metadata operations (`metadata_ops`)
+    /// are only supported in JSON input.
+    #[arg(long, value_enum, default_value = "json", value_name = "FORMAT")]
+    input_format: InputFormat,
+
+    /// Append a record of each completed download, metadata update, and URL
+    /// registration to the given file
+    ///
+    /// If the run is interrupted, it can later be continued by passing the
+    /// same file to `--resume`.
+    #[arg(long, value_name = "FILE")]
+    journal: Option<PathBuf>,
+
     /// Number of jobs for `git-annex addurl` to use  [default: one per CPU]
     #[arg(short = 'J', value_name = "INT")]
     jobs: Option<NonZeroUsize>,
@@ -59,7 +175,9 @@ struct Arguments {
     /// The commit message to use when saving
     ///
     /// Any occurrences of "{downloaded}" in the message will be replaced by
-    /// the number of successfully downloaded files.
+    /// the number of files successfully downloaded since the previous commit,
+    /// and any occurrences of "{total}" will be replaced by the number of
+    /// files successfully downloaded so far in the run.
     #[arg(
         short,
         long,
@@ -68,10 +186,145 @@ struct Arguments {
     )]
     message: String,
 
+    /// Maximum number of times to attempt each download
+    ///
+    /// Downloads that fail due to a timeout, a dropped connection, or a 5xx
+    /// server response are retried up to this many attempts in total.  Set
+    /// to 1 to disable retrying.
+    #[arg(long, default_value = "3", value_name = "INT")]
+    max_attempts: NonZeroUsize,
+
+    /// Upper bound on the delay between retries of a download, in seconds
+    #[arg(long, default_value = "60", value_name = "SECONDS", value_parser = parse_seconds)]
+    max_retry_delay: Duration,
+
+    /// Set a metadata field on every downloaded item that does not set the
+    /// field itself
+    ///
+    /// This option may be given multiple times; giving the same field more
+    /// than once sets it to multiple values.
+    #[arg(long, value_name = "FIELD=VALUE", value_parser = parse_metadata_arg)]
+    metadata: Vec<(String, String)>,
+
+    /// Number of `git-annex metadata` processes to run in parallel
+    ///
+    /// Updates to the same key are always made by the same process, in
+    /// order.
+    #[arg(long, default_value = "1", value_name = "INT")]
+    metadata_jobs: NonZeroUsize,
+
+    /// Don't download anything; instead, set the metadata and register the
+    /// extra URLs of the files already at the input items' paths
+    ///
+    /// Items whose paths are not annexed files are reported as failures.
+    #[arg(long, conflicts_with_all = ["dedupe_urls", "dry_run"])]
+    metadata_only: bool,
+
+    /// What to do with input items whose metadata git-annex would reject
+    ///
+    /// Field names must start with a letter or digit, may only contain
+    /// letters, digits, "_", "-", and ".", and cannot be "lastchanged" or end
+    /// in "-lastchanged"; values cannot be empty.  POLICY is one of "reject"
+    /// (discard the item as invalid), "drop" (remove the bad fields and
+    /// values), or "sanitize" (rewrite bad field names into valid ones and
+    /// remove empty values).
+    #[arg(long, default_value = "reject", value_name = "POLICY")]
+    on_bad_metadata: BadMetadataPolicy,
+
+    /// Check the input for path collisions before downloading anything
+    ///
+    /// Collisions are paths that differ only in case ("case"), paths that
+    /// differ only in Unicode normalization ("normalization"), and paths used
+    /// as both a file and a directory ("file-directory").  POLICY is "warn"
+    /// (log a warning), "error" (refuse to start the run), or "rename"
+    /// (respell colliding directories to match the earlier path, and insert
+    /// "-<N>" into other colliding names).  Prefix POLICY with `<KIND>=` to
+    /// apply it only to the given kind of collision; otherwise, it applies to
+    /// all kinds.  This option may be given multiple times.  The entire input
+    /// is read before the first download starts.
+    #[arg(long, value_name = "[KIND=]POLICY", value_parser = parse_collision_rule)]
+    on_collision: Vec<CollisionRule>,
+
+    /// What to do with items whose paths are the same as those of earlier
+    /// items in the input
+    ///
+    /// POLICY is one of "first-wins" (discard all but the first item),
+    /// "last-wins" (discard all but the last item), "merge" (merge the
+    /// metadata and extra URLs of all the items into the first one), or
+    /// "error" (fail all but the first item).  "last-wins" and "merge" read
+    /// the entire input before the first download starts.
+    #[arg(long, default_value = "first-wins", value_name = "POLICY")]
+    on_duplicate: DuplicatePolicy,
+
+    /// What to do with items whose paths already exist in the repository
+    ///
+    /// POLICY is one of "skip" (leave the file alone), "register-url-only"
+    /// (register the item's URLs for the existing annexed file and set its
+    /// metadata), "replace" (delete the file and download in its place),
+    /// "rename-with-suffix" (download to the path with "-<N>" inserted before
+    /// the file extension), or "error" (fail the item).  Input entries can
+    /// override this with an "`on_existing`" field.  By default, items are
+    /// passed to `git-annex addurl` as usual.
+    #[arg(long, value_name = "POLICY")]
+    on_existing: Option<ExistingPolicy>,
+
+    /// Don't show progress bars, even if stderr is a terminal
+    ///
+    /// When stderr is not a terminal, the progress of each download is
+    /// logged instead.
+    #[arg(long)]
+    no_progress: bool,
+
     /// Don't commit if any files failed to download
     #[arg(long)]
     no_save_on_fail: bool,
 
+    /// Name of the column containing the download path in CSV/TSV input
+    #[arg(long, default_value = "path", value_name = "NAME")]
+    path_column: String,
+
+    /// Template for the paths of input items that don't specify one
+    ///
+    /// Placeholders are `{url.host}`, `{url.path}`, `{url.segment.N}`,
+    /// `{url.filename}`, `{url.stem}`, `{url.ext}`, and `{metadata.FIELD}`.
+    /// Items for which the template cannot be filled in are discarded as
+    /// invalid.
+    #[arg(long, value_name = "TEMPLATE")]
+    path_template: Option<PathTemplate>,
+
+    /// Don't download anything; instead, register the extra URLs of each
+    /// input item for a known key
+    ///
+    /// Input must be JSON lines, each with either a "key" field giving a
+    /// git-annex key or a "path" field giving an annexed file, plus an
+    /// "`extra_urls`" field.
+    #[arg(long, conflicts_with_all = ["dedupe_urls", "dry_run", "metadata_only"])]
+    registerurl_only: bool,
+
+    /// Number of `git-annex registerurl` processes to run in parallel when
+    /// registering the extra URLs of downloaded or updated files
+    #[arg(long, default_value = "1", value_name = "INT")]
+    registerurl_jobs: NonZeroUsize,
+
+    /// Write a JSON Lines report with one record per input item to the given
+    /// file
+    #[arg(long, value_name = "FILE")]
+    report: Option<OutputArg>,
+
+    /// Resume an interrupted run using the given journal file
+    ///
+    /// Downloads, metadata updates, and URL registrations that the journal
+    /// records as completed are skipped.  Newly completed stages are appended
+    /// to the same file.
+    #[arg(long, value_name = "FILE", conflicts_with = "journal")]
+    resume: Option<PathBuf>,
+
+    /// Number of seconds to wait before the first retry of a failed download
+    ///
+    /// The delay doubles after each further failure of the same download.
+    #[arg(long, default_value = "1", value_name = "SECONDS", value_parser = parse_seconds)]
+    retry_delay: Duration,
+
     /// Commit the downloaded files when done  [default]
     #[arg(long = "save")]
     _no_save: bool,
@@ -80,24 +333,71 @@ struct Arguments {
     #[arg(long = "no-save", overrides_with = "_no_save", action = ArgAction::SetFalse)]
     save: bool,
 
+    /// Rewrite invalid or unportable paths instead of discarding their items
+    ///
+    /// Illegal characters are replaced, control characters are escaped,
+    /// overlong names are truncated, and Windows reserved names are
+    /// prefixed with an underscore.  Each rewrite is logged and recorded in
+    /// the report.
+    #[arg(long)]
+    sanitize_paths: bool,
+
+    /// Name of the column containing the URL in CSV/TSV input
+    #[arg(long, default_value = "url", value_name = "NAME")]
+    url_column: String,
+
     /// File containing JSON lines with "url", "path", "metadata" (optional),
-    /// and "`extra_urls`" (optional) fields  [default: read from stdin]
+    /// and "`extra_urls`" (optional) fields, or CSV/TSV data as selected by
+    /// `--input-format`  [default: read from stdin]
     #[arg(default_value_t, hide_default_value = true)]
     infile: InputArg,
 }
 
 impl Default for Arguments {
     fn default() -> Arguments {
+        let retry = RetryPolicy::default();
         Arguments {
             addurl_opts: None,
+            auto_metadata: Vec::new(),
+            check_repo_collisions: false,
             repo: PathBuf::from("."),
+            commit_every: None,
+            commit_interval: None,
+            dedupe_urls: false,
+            defaults_for_existing: false,
+            drop_mismatched: false,
+            dry_run: false,
+            extra_urls_column: "extra_urls".into(),
+            extra_urls_separator: None,
             failures: None,
+            host_limit: Vec::new(),
+            input_format: InputFormat::Json,
             jobs: None,
+            journal: None,
             log_level: log::LevelFilter::Info,
             message: "Downloaded {downloaded} URLs".into(),
+            max_attempts: retry.max_attempts,
+            max_retry_delay: retry.max_delay,
+            metadata: Vec::new(),
+            metadata_jobs: NonZeroUsize::MIN,
+            metadata_only: false,
+            path_column: "path".into(),
+            path_template: None,
+            registerurl_jobs: NonZeroUsize::MIN,
+            registerurl_only: false,
+            no_progress: false,
             no_save_on_fail: false,
+            on_bad_metadata: BadMetadataPolicy::Reject,
+            on_collision: Vec::new(),
+            on_duplicate: DuplicatePolicy::FirstWins,
+            on_existing: None,
+            report: None,
+            resume: None,
+            retry_delay: retry.initial_delay,
             save: true,
             _no_save: false,
+            sanitize_paths: false,
+            url_column: "url".into(),
             infile: InputArg::Stdin,
         }
     }
@@ -106,7 +406,9 @@ impl Default for Arguments {
 #[tokio::main]
 async fn main() -> Result<ExitCode, anyhow::Error> {
     let args = Arguments::parse();
-    fern::Dispatch::new()
+    let progress = (!args.dry_run && !args.no_progress && std::io::stderr().is_terminal())
+        .then(MultiProgress::new);
+    let logger = fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
                 "{} [{:<5}] {}",
@@ -115,116 +417,419 @@ async fn main() -> Result<ExitCode, anyhow::Error> {
                 message
             ));
         })
-        .level(args.log_level)
-        .chain(std::io::stderr())
-        .apply()
-        .expect("no other logger should have been previously initialized");
-    let items = read_input_file(args.infile).await?;
-    if items.is_empty() {
+        .level(args.log_level);
+    match progress {
+        Some(ref multi) => {
+            // Print log messages above the progress bars instead of the
+            // per-download progress messages
+            let multi = multi.clone();
+            logger
+                .level_for(PROGRESS_LOG_TARGET, log::LevelFilter::Off)
+                .chain(fern::Output::call(move |record| {
+                    multi.suspend(|| eprintln!("{}", record.args()));
+                }))
+        }
+        None => logger.chain(std::io::stderr()),
+    }
+    .apply()
+    .expect("no other logger should have been previously initialized");
+    if args.registerurl_only {
+        return register_mirrors(args).await;
+    }
+    let defaults = metadata_defaults(args.metadata, args.auto_metadata, &args.infile);
+    let invalid = AtomicUsize::new(0);
+    let mapping = ColumnMapping {
+        url: args.url_column,
+        path: args.path_column,
+        extra_urls: args.extra_urls_column,
+        extra_urls_separator: args.extra_urls_separator,
+    };
+    let mut items = pin!(read_input_file(
+        args.infile,
+        args.input_format,
+        mapping,
+        PathOptions {
+            template: args.path_template,
+            sanitize: args.sanitize_paths,
+        },
+        args.on_bad_metadata,
+        &invalid,
+    )
+    .await?
+    .peekable());
+    if !args.dry_run && items.as_mut().peek().await.is_none() {
         log::info!("Nothing to download");
         return Ok(ExitCode::SUCCESS);
     }
-    ensure_annex_repo(&args.repo).await?;
-    let gamdam = Gamdam {
-        repo: args.repo.clone(),
-        addurl_options: args.addurl_opts.unwrap_or_default(),
-        addurl_jobs: args.jobs.map_or(Jobs::CPUs, Jobs::Qty),
-    };
-    let report = gamdam.download(items).await?;
-    if !report.successful.is_empty()
-        && args.save
-        && (!args.no_save_on_fail || report.failed.is_empty())
-    {
-        match LoggedCommand::new("git", ["diff", "--cached", "--quiet"], &args.repo)
-            .status()
-            .await
-        {
-            Err(CommandError::Exit { .. }) => {
-                LoggedCommand::new(
-                    "git",
-                    [
-                        "commit",
-                        "-m",
-                        &args
-                            .message
-                            .replace("{downloaded}", &report.successful.len().to_string()),
-                    ],
-                    args.repo,
-                )
-                .status()
-                .await?;
-            }
-            Ok(()) => {
-                // This can happen if we only downloaded files that were
-                // already present in the repo.
-                log::info!("Nothing to commit");
-            }
-            Err(e) => return Err(e.into()),
+    let mut builder = Gamdam::builder(&args.repo)
+        .addurl_options(args.addurl_opts.unwrap_or_default())
+        .jobs(args.jobs.map_or(Jobs::CPUs, Jobs::Qty))
+        .retry(RetryPolicy {
+            max_attempts: args.max_attempts,
+            initial_delay: args.retry_delay,
+            max_delay: args.max_retry_delay,
+        })
+        .host_limits(host_limits(args.host_limit))
+        .duplicates(args.on_duplicate)
+        .collisions(collision_policies(
+            args.on_collision,
+            args.check_repo_collisions,
+        ))
+        .dedupe_urls(args.dedupe_urls)
+        .drop_mismatched(args.drop_mismatched)
+        .metadata_only(args.metadata_only)
+        .metadata_defaults(defaults)?
+        .defaults_for_existing(args.defaults_for_existing)
+        .metadata_jobs(args.metadata_jobs)
+        .registerurl_jobs(args.registerurl_jobs)
+        .save(args.save)
+        .commit_message(args.message)
+        .save_on_fail(!args.no_save_on_fail);
+    if let Some(policy) = args.on_existing {
+        builder = builder.on_existing(policy);
+    }
+    if let Some(n) = args.commit_every {
+        builder = builder.commit_every(n);
+    }
+    if let Some(interval) = args.commit_interval {
+        builder = builder.commit_interval(interval);
+    }
+    if let Some(path) = args.journal {
+        builder = builder.journal(path);
+    }
+    if let Some(path) = args.resume {
+        builder = builder.resume(path);
+    }
+    if let Some(path) = args.report {
+        builder = builder.report(path);
+    }
+    if let Some(path) = args.failures {
+        builder = builder.failures(path);
+    }
+    let mut display = None;
+    if let Some(multi) = progress {
+        let (sender, receiver) = unbounded_channel();
+        builder = builder.events(sender);
+        display = Some(tokio::spawn(ProgressDisplay::new(multi).run(receiver)));
+    }
+    let gamdam = builder.build();
+    if args.dry_run {
+        let plan = gamdam.plan(items).await?;
+        let invalid = invalid.load(Ordering::Relaxed);
+        if invalid > 0 {
+            log::error!("{invalid} input line(s) were invalid");
         }
+        return if plan.conflicts() == 0 && invalid == 0 {
+            Ok(ExitCode::SUCCESS)
+        } else {
+            Ok(ExitCode::FAILURE)
+        };
     }
-    if report.failed.is_empty() {
+    let shutdown = Shutdown::new();
+    handle_signals(&shutdown)?;
+    let report = Box::pin(gamdam.try_download_with_shutdown(items, &shutdown)).await?;
+    // Dropping the event sender lets the display finish.
+    drop(gamdam);
+    if let Some(handle) = display {
+        let _ = handle.await;
+    }
+    if report.failed.is_empty() && report.unprocessed.is_empty() {
         Ok(ExitCode::SUCCESS)
     } else {
-        if let Some(path) = args.failures {
-            if let Err(e) = write_failures(path, report.failed).await {
-                log::error!("Error writing failures report: {e}");
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+/// Stop `shutdown` on the first interrupt and kill it on the second
+fn handle_signals(shutdown: &Shutdown) -> Result<(), anyhow::Error> {
+    let mut signals = Signals::new().context("Error installing signal handlers")?;
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if signals.recv().await.is_ok() {
+            log::warn!(
+                "Received interrupt; no new downloads will be started.  Interrupt again to terminate immediately."
+            );
+            shutdown.stop();
+            if signals.recv().await.is_ok() {
+                log::warn!("Received second interrupt; terminating");
+                shutdown.kill();
             }
         }
+    });
+    Ok(())
+}
+
+/// Register the extra URLs of the input items for their keys without
+/// downloading anything
+async fn register_mirrors(args: Arguments) -> Result<ExitCode, anyhow::Error> {
+    if args.input_format != InputFormat::Json {
+        anyhow::bail!("--registerurl-only only supports JSON input");
+    }
+    let invalid = AtomicUsize::new(0);
+    let mut items = pin!(read_json_lines(args.infile, &invalid, |item: MirrorItem| {
+        Ok::<_, Infallible>(item)
+    })
+    .await?
+    .peekable());
+    if items.as_mut().peek().await.is_none() {
+        log::info!("Nothing to register");
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut builder = Gamdam::builder(&args.repo);
+    if let Some(path) = args.journal {
+        builder = builder.journal(path);
+    }
+    if let Some(path) = args.resume {
+        builder = builder.resume(path);
+    }
+    if let Some(path) = args.report {
+        builder = builder.report(path);
+    }
+    if let Some(path) = args.failures {
+        builder = builder.failures(path);
+    }
+    let gamdam = builder.build();
+    let shutdown = Shutdown::new();
+    handle_signals(&shutdown)?;
+    let report = gamdam.register_mirrors(items, &shutdown).await?;
+    if report.failed.is_empty()
+        && report.unprocessed.is_empty()
+        && invalid.load(Ordering::Relaxed) == 0
+    {
+        Ok(ExitCode::SUCCESS)
+    } else {
         Ok(ExitCode::FAILURE)
     }
 }
 
+/// The formats that input can be in
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum InputFormat {
+    Json,
+    Csv,
+    Tsv,
+}
+
+// Invalid lines are discarded and counted in `invalid`.
+async fn read_input_file(
+    infile: InputArg,
+    format: InputFormat,
+    mapping: ColumnMapping,
+    paths: PathOptions,
+    metadata: BadMetadataPolicy,
+    invalid: &AtomicUsize,
+) -> Result<impl Stream<Item = Result<Downloadable, anyhow::Error>> + Send + '_, anyhow::Error> {
+    let delimiter = match format {
+        InputFormat::Json => {
+            return Ok(Either::Left(
+                read_json_lines(infile, invalid, move |item: InputItem| {
+                    item.complete(&paths, metadata)
+                })
+                .await?,
+            ))
+        }
+        InputFormat::Csv => b',',
+        InputFormat::Tsv => b'\t',
+    };
+    let reader: Box<dyn Read + Send> = match infile {
+        InputArg::Stdin => Box::new(std::io::stdin()),
+        InputArg::Path(ref path) => Box::new(
+            File::open(path).with_context(|| format!("Error opening {infile} for reading"))?,
+        ),
+    };
+    Ok(Either::Right(
+        read_table(reader, delimiter, mapping, paths, metadata).filter_map(move |r| {
+            future::ready(match r {
+                Ok(d) => Some(Ok(d)),
+                Err(TableError::Row { line, source }) => {
+                    log::warn!("Input line {line} is invalid; discarding: {source}");
+                    invalid.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+                Err(e) => Some(Err(anyhow::Error::new(e))),
+            })
+        }),
+    ))
+}
+
 // The suggested "fix" for the `|inner| inner.is::<...>()` closure just looks
 // ugly.
 #[allow(clippy::redundant_closure_for_method_calls)]
-async fn read_input_file(infile: InputArg) -> Result<Vec<Downloadable>, anyhow::Error> {
-    let mut lines = BufReader::new(
+//
+// Each line is deserialized as a `T` and then passed to `convert`; lines for
+// which either step fails are discarded and counted in `invalid`.
+async fn read_json_lines<T, U, E, F>(
+    infile: InputArg,
+    invalid: &AtomicUsize,
+    mut convert: F,
+) -> Result<impl Stream<Item = Result<U, anyhow::Error>> + Send + '_, anyhow::Error>
+where
+    T: DeserializeOwned + Send + 'static,
+    U: Send + 'static,
+    E: std::fmt::Display,
+    F: FnMut(T) -> Result<U, E> + Send + 'static,
+{
+    let lines = BufReader::new(
         infile
             .async_open()
             .await
             .with_context(|| format!("Error opening {infile} for reading"))?,
     )
-    .json_lines::<Downloadable>();
-    let mut items = Vec::new();
-    let mut lineno = 1;
-    while let Some(r) = lines.next().await {
-        match r {
-            Ok(d) => items.push(d),
+    .json_lines::<T>();
+    Ok(lines.enumerate().filter_map(move |(i, r)| {
+        future::ready(match r.map(&mut convert) {
+            Ok(Ok(d)) => Some(Ok(d)),
+            Ok(Err(e)) => {
+                log::warn!("Input line {} is invalid; discarding: {}", i + 1, e);
+                invalid.fetch_add(1, Ordering::Relaxed);
+                None
+            }
             Err(e)
                 if e.get_ref()
                     .is_some_and(|inner| inner.is::<serde_json::Error>()) =>
             {
-                log::warn!("Input line {} is invalid; discarding: {}", lineno, e);
+                log::warn!("Input line {} is invalid; discarding: {}", i + 1, e);
+                invalid.fetch_add(1, Ordering::Relaxed);
+                None
             }
-            Err(e) => return Err(e).context("Error reading input"),
+            Err(e) => Some(Err(anyhow::Error::new(e).context("Error reading input"))),
+        })
+    }))
+}
+
+/// Listener for the signals that interrupt a run: Ctrl-C on all platforms,
+/// plus SIGTERM on Unix
+struct Signals {
+    #[cfg(unix)]
+    sigterm: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    fn new() -> std::io::Result<Signals> {
+        Ok(Signals {
+            #[cfg(unix)]
+            sigterm: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+        })
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) -> std::io::Result<()> {
+        tokio::select! {
+            r = tokio::signal::ctrl_c() => r,
+            _ = self.sigterm.recv() => Ok(()),
         }
-        lineno += 1;
     }
-    Ok(items)
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) -> std::io::Result<()> {
+        tokio::signal::ctrl_c().await
+    }
 }
 
-async fn write_failures<I>(outfile: OutputArg, failures: I) -> Result<(), anyhow::Error>
-where
-    I: IntoIterator<Item = DownloadResult> + Send,
-    I::IntoIter: Send,
-{
-    let mut sink = outfile
-        .async_create()
-        .await
-        .with_context(|| format!("Error opening {outfile} for writing"))?
-        .into_json_lines_sink();
-    for item in failures {
-        sink.send(item.downloadable)
-            .await
-            .context("Error writing to file")?;
+/// A `--host-limit` argument
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct HostLimitArg {
+    host: Option<String>,
+    limit: HostLimit,
+}
+
+fn parse_host_limit(s: &str) -> Result<HostLimitArg, ParseHostLimitError> {
+    // Limits never contain colons, but hosts (namely, IPv6 addresses) can.
+    match s.rsplit_once(':') {
+        Some((host, limit)) => Ok(HostLimitArg {
+            host: Some(host.to_ascii_lowercase()),
+            limit: limit.parse()?,
+        }),
+        None => Ok(HostLimitArg {
+            host: None,
+            limit: s.parse()?,
+        }),
     }
-    Ok(())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct CollisionRule {
+    kind: Option<CollisionKind>,
+    policy: CollisionPolicy,
+}
+
+fn parse_collision_rule(s: &str) -> Result<CollisionRule, ParseCollisionError> {
+    match s.split_once('=') {
+        Some((kind, policy)) => Ok(CollisionRule {
+            kind: Some(kind.parse()?),
+            policy: policy.parse()?,
+        }),
+        None => Ok(CollisionRule {
+            kind: None,
+            policy: s.parse()?,
+        }),
+    }
+}
+
+fn collision_policies(rules: Vec<CollisionRule>, check_repo: bool) -> CollisionPolicies {
+    let mut policies = CollisionPolicies {
+        check_repo,
+        ..CollisionPolicies::default()
+    };
+    for CollisionRule { kind, policy } in rules {
+        policies.set(kind, policy);
+    }
+    policies
+}
+
+fn parse_metadata_arg(s: &str) -> Result<(String, String), anyhow::Error> {
+    let Some((field, value)) = s.split_once('=') else {
+        anyhow::bail!("expected FIELD=VALUE");
+    };
+    check_field_name(field)?;
+    if value.is_empty() {
+        anyhow::bail!("value cannot be empty");
+    }
+    Ok((field.into(), value.into()))
+}
+
+fn metadata_defaults(
+    fields: Vec<(String, String)>,
+    automatic: Vec<AutoField>,
+    infile: &InputArg,
+) -> MetadataDefaults {
+    let mut defaults = MetadataDefaults {
+        automatic,
+        input_file: match infile {
+            InputArg::Stdin => None,
+            InputArg::Path(path) => Some(path.display().to_string()),
+        },
+        ..MetadataDefaults::default()
+    };
+    for (field, value) in fields {
+        defaults.fields.entry(field).or_default().push(value);
+    }
+    defaults
+}
+
+fn host_limits(args: Vec<HostLimitArg>) -> HostLimits {
+    let mut limits = HostLimits::default();
+    for HostLimitArg { host, limit } in args {
+        let slot = match host {
+            Some(host) => limits.overrides.entry(host).or_default(),
+            None => &mut limits.default,
+        };
+        *slot = limit.or(*slot);
+    }
+    limits
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, anyhow::Error> {
+    Ok(Duration::try_from_secs_f64(s.parse::<f64>()?)?)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use clap::CommandFactory;
+    use gamdam::AutoValue;
+    use rstest::rstest;
+    use std::collections::HashMap;
 
     #[test]
     fn verify_cli() {
@@ -321,6 +926,316 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cli_post_processing_jobs() {
+        let args =
+            Arguments::try_parse_from(["arg0", "--metadata-jobs", "2", "--registerurl-jobs", "4"])
+                .unwrap();
+        assert_eq!(
+            args,
+            Arguments {
+                metadata_jobs: NonZeroUsize::new(2).unwrap(),
+                registerurl_jobs: NonZeroUsize::new(4).unwrap(),
+                ..Arguments::default()
+            }
+        );
+        let args = Arguments::try_parse_from(["arg0", "--registerurl-jobs", "0"]);
+        assert!(args.is_err());
+    }
+
+    #[test]
+    fn test_cli_commit_batching() {
+        let args = Arguments::try_parse_from([
+            "arg0",
+            "--commit-every",
+            "100",
+            "--commit-interval",
+            "600",
+        ])
+        .unwrap();
+        assert_eq!(
+            args,
+            Arguments {
+                commit_every: NonZeroUsize::new(100),
+                commit_interval: Some(Duration::from_secs(600)),
+                ..Arguments::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_input_format() {
+        let args = Arguments::try_parse_from([
+            "arg0",
+            "--input-format",
+            "tsv",
+            "--url-column",
+            "Link",
+            "--extra-urls-separator",
+            "|",
+            "catalog.tsv",
+        ])
+        .unwrap();
+        assert_eq!(
+            args,
+            Arguments {
+                input_format: InputFormat::Tsv,
+                url_column: "Link".into(),
+                extra_urls_separator: Some('|'),
+                infile: InputArg::Path(PathBuf::from("catalog.tsv")),
+                ..Arguments::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_on_collision() {
+        let args = Arguments::try_parse_from([
+            "arg0",
+            "--on-collision",
+            "warn",
+            "--on-collision",
+            "case=rename",
+            "--check-repo-collisions",
+        ])
+        .unwrap();
+        assert_eq!(
+            collision_policies(args.on_collision, args.check_repo_collisions),
+            CollisionPolicies {
+                case: Some(CollisionPolicy::Rename),
+                normalization: Some(CollisionPolicy::Warn),
+                file_directory: Some(CollisionPolicy::Warn),
+                check_repo: true,
+            }
+        );
+        assert!(Arguments::try_parse_from(["arg0", "--on-collision", "size=warn"]).is_err());
+    }
+
+    #[rstest]
+    #[case("first-wins", DuplicatePolicy::FirstWins)]
+    #[case("last-wins", DuplicatePolicy::LastWins)]
+    #[case("merge", DuplicatePolicy::Merge)]
+    #[case("error", DuplicatePolicy::Error)]
+    fn test_cli_on_duplicate(#[case] value: &str, #[case] policy: DuplicatePolicy) {
+        let args = Arguments::try_parse_from(["arg0", "--on-duplicate", value]).unwrap();
+        assert_eq!(args.on_duplicate, policy);
+    }
+
+    #[test]
+    fn test_cli_metadata_defaults() {
+        let args = Arguments::try_parse_from([
+            "arg0",
+            "--metadata",
+            "source=crawl-2026-10",
+            "--metadata",
+            "tag=a=b",
+            "--metadata",
+            "source=extra",
+            "--auto-metadata",
+            "downloaded",
+            "--auto-metadata",
+            "input-file=batch",
+            "input.jsonl",
+        ])
+        .unwrap();
+        let defaults = metadata_defaults(args.metadata, args.auto_metadata, &args.infile);
+        assert_eq!(
+            defaults,
+            MetadataDefaults {
+                fields: HashMap::from([
+                    (
+                        "source".into(),
+                        vec!["crawl-2026-10".into(), "extra".into()]
+                    ),
+                    ("tag".into(), vec!["a=b".into()]),
+                ]),
+                automatic: vec![
+                    AutoField::new(AutoValue::Downloaded),
+                    AutoField {
+                        field: "batch".into(),
+                        value: AutoValue::InputFile,
+                    },
+                ],
+                input_file: Some("input.jsonl".into()),
+            }
+        );
+        assert!(Arguments::try_parse_from(["arg0", "--metadata", "source"]).is_err());
+        assert!(Arguments::try_parse_from(["arg0", "--metadata", "=x"]).is_err());
+        assert!(Arguments::try_parse_from(["arg0", "--metadata", "my field=x"]).is_err());
+        assert!(Arguments::try_parse_from(["arg0", "--metadata", "lastchanged=x"]).is_err());
+        assert!(Arguments::try_parse_from(["arg0", "--metadata", "tag="]).is_err());
+        assert!(Arguments::try_parse_from(["arg0", "--auto-metadata", "hostname"]).is_err());
+    }
+
+    #[rstest]
+    #[case("reject", BadMetadataPolicy::Reject)]
+    #[case("drop", BadMetadataPolicy::Drop)]
+    #[case("sanitize", BadMetadataPolicy::Sanitize)]
+    fn test_cli_on_bad_metadata(#[case] value: &str, #[case] policy: BadMetadataPolicy) {
+        let args = Arguments::try_parse_from(["arg0", "--on-bad-metadata", value]).unwrap();
+        assert_eq!(args.on_bad_metadata, policy);
+    }
+
+    #[test]
+    fn test_cli_metadata_only() {
+        let args = Arguments::try_parse_from(["arg0", "--metadata-only"]).unwrap();
+        assert!(args.metadata_only);
+        assert!(Arguments::try_parse_from(["arg0", "--metadata-only", "--dry-run"]).is_err());
+        assert!(Arguments::try_parse_from(["arg0", "--metadata-only", "--dedupe-urls"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_registerurl_only() {
+        let args =
+            Arguments::try_parse_from(["arg0", "--registerurl-only", "mirrors.jsonl"]).unwrap();
+        assert!(args.registerurl_only);
+        assert!(
+            Arguments::try_parse_from(["arg0", "--registerurl-only", "--metadata-only"]).is_err()
+        );
+        assert!(Arguments::try_parse_from(["arg0", "--registerurl-only", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_path_template() {
+        let args = Arguments::try_parse_from([
+            "arg0",
+            "--path-template",
+            "{url.host}/{url.filename}",
+            "urls.jsonl",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.path_template,
+            Some("{url.host}/{url.filename}".parse().unwrap())
+        );
+        assert!(Arguments::try_parse_from(["arg0", "--path-template", "{url.name}"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_dry_run() {
+        let args = Arguments::try_parse_from(["arg0", "--dry-run", "file.json"]).unwrap();
+        assert_eq!(
+            args,
+            Arguments {
+                dry_run: true,
+                infile: InputArg::Path("file.json".into()),
+                ..Arguments::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_on_existing() {
+        let args =
+            Arguments::try_parse_from(["arg0", "--on-existing", "rename-with-suffix"]).unwrap();
+        assert_eq!(
+            args,
+            Arguments {
+                on_existing: Some(ExistingPolicy::RenameWithSuffix),
+                ..Arguments::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_retry_options() {
+        let args = Arguments::try_parse_from([
+            "arg0",
+            "--max-attempts",
+            "5",
+            "--retry-delay",
+            "0.5",
+            "--max-retry-delay",
+            "30",
+        ])
+        .unwrap();
+        assert_eq!(
+            args,
+            Arguments {
+                max_attempts: NonZeroUsize::new(5).unwrap(),
+                retry_delay: Duration::from_millis(500),
+                max_retry_delay: Duration::from_secs(30),
+                ..Arguments::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_negative_retry_delay() {
+        let args = Arguments::try_parse_from(["arg0", "--retry-delay", "-1"]);
+        assert!(args.is_err());
+    }
+
+    #[test]
+    fn test_cli_resume() {
+        let args = Arguments::try_parse_from(["arg0", "--resume", "journal.jsonl"]).unwrap();
+        assert_eq!(
+            args,
+            Arguments {
+                resume: Some(PathBuf::from("journal.jsonl")),
+                ..Arguments::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_host_limit() {
+        let args = Arguments::try_parse_from([
+            "arg0",
+            "--host-limit",
+            "jobs=4",
+            "--host-limit",
+            "Example.com:jobs=1,rate=0.5",
+            "--host-limit",
+            "[::1]:rate=10",
+        ])
+        .unwrap();
+        let limits = host_limits(args.host_limit);
+        assert_eq!(
+            limits,
+            HostLimits {
+                default: HostLimit {
+                    jobs: NonZeroUsize::new(4),
+                    min_interval: None,
+                },
+                overrides: HashMap::from([
+                    (
+                        String::from("example.com"),
+                        HostLimit {
+                            jobs: NonZeroUsize::new(1),
+                            min_interval: Some(Duration::from_secs(2)),
+                        }
+                    ),
+                    (
+                        String::from("[::1]"),
+                        HostLimit {
+                            jobs: None,
+                            min_interval: Some(Duration::from_millis(100)),
+                        }
+                    ),
+                ]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_bad_host_limit() {
+        let args = Arguments::try_parse_from(["arg0", "--host-limit", "example.com:jobs=0"]);
+        assert!(args.is_err());
+    }
+
+    #[test]
+    fn test_cli_journal_resume_conflict() {
+        let args = Arguments::try_parse_from([
+            "arg0",
+            "--journal",
+            "journal.jsonl",
+            "--resume",
+            "journal.jsonl",
+        ]);
+        assert!(args.is_err());
+    }
+
     #[test]
     fn test_cli_zero_jobs() {
         let args = Arguments::try_parse_from(["arg0", "-J", "0"]);
@@ -0,0 +1,380 @@
+use crate::{Downloadable, MetadataOp};
+use std::collections::{hash_map::Entry, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// What to do with input items whose metadata git-annex would reject
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum BadMetadataPolicy {
+    /// Discard the item as invalid
+    #[default]
+    Reject,
+    /// Remove the invalid fields & values from the item's metadata
+    Drop,
+    /// Rewrite invalid field names into valid ones with
+    /// [`sanitize_field_name()`], and remove any fields that can't be
+    /// rewritten and any empty values
+    Sanitize,
+}
+
+impl BadMetadataPolicy {
+    const NAMES: [(BadMetadataPolicy, &'static str); 3] = [
+        (BadMetadataPolicy::Reject, "reject"),
+        (BadMetadataPolicy::Drop, "drop"),
+        (BadMetadataPolicy::Sanitize, "sanitize"),
+    ];
+}
+
+impl fmt::Display for BadMetadataPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Self::NAMES
+            .iter()
+            .find_map(|&(p, name)| (p == *self).then_some(name))
+            .unwrap_or_default();
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for BadMetadataPolicy {
+    type Err = ParseBadMetadataPolicyError;
+
+    fn from_str(s: &str) -> Result<BadMetadataPolicy, ParseBadMetadataPolicyError> {
+        Self::NAMES
+            .iter()
+            .find_map(|&(p, name)| name.eq_ignore_ascii_case(s).then_some(p))
+            .ok_or_else(|| ParseBadMetadataPolicyError(s.into()))
+    }
+}
+
+/// Error returned when parsing an invalid [`BadMetadataPolicy`]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("invalid bad-metadata policy {0:?}; expected one of reject, drop, sanitize")]
+pub struct ParseBadMetadataPolicyError(String);
+
+/// Error for a metadata field name or value that git-annex would reject
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum MetadataError {
+    #[error("metadata field name cannot be empty")]
+    EmptyField,
+    #[error("metadata field name {0:?} does not start with a letter or digit")]
+    BadStart(String),
+    #[error("metadata field name {field:?} contains invalid character {c:?}")]
+    BadChar { field: String, c: char },
+    #[error("metadata field name {0:?} is reserved by git-annex")]
+    Reserved(String),
+    #[error("metadata field {0:?} has an empty value")]
+    EmptyValue(String),
+}
+
+/// Check that `name` is a metadata field name accepted by git-annex: it must
+/// start with a letter or digit, contain only letters, digits, `_`, `-`, and
+/// `.`, and not be `lastchanged` or end in `-lastchanged` (compared
+/// case-insensitively), as those are maintained by git-annex itself.
+pub fn check_field_name(name: &str) -> Result<(), MetadataError> {
+    let mut chars = name.chars();
+    match chars.next() {
+        None => return Err(MetadataError::EmptyField),
+        Some(c) if !c.is_alphanumeric() => return Err(MetadataError::BadStart(name.into())),
+        Some(_) => (),
+    }
+    if let Some(c) = chars.find(|&c| !is_field_char(c)) {
+        return Err(MetadataError::BadChar {
+            field: name.into(),
+            c,
+        });
+    }
+    if is_reserved(name) {
+        return Err(MetadataError::Reserved(name.into()));
+    }
+    Ok(())
+}
+
+/// Rewrite `name` into a valid metadata field name by stripping any leading
+/// characters other than letters & digits, replacing other invalid
+/// characters with `_`, and changing a reserved `lastchanged` suffix to
+/// `_lastchanged` (or appending `_` if the name is just `lastchanged`).
+/// Returns `None` if nothing is left.
+pub fn sanitize_field_name(name: &str) -> Option<String> {
+    let mut sanitized = name
+        .chars()
+        .skip_while(|c| !c.is_alphanumeric())
+        .map(|c| if is_field_char(c) { c } else { '_' })
+        .collect::<String>();
+    if sanitized.is_empty() {
+        return None;
+    }
+    if is_reserved(&sanitized) {
+        let n = sanitized.len() - "lastchanged".len();
+        if n == 0 {
+            sanitized.push('_');
+        } else {
+            sanitized.replace_range((n - 1)..n, "_");
+        }
+    }
+    Some(sanitized)
+}
+
+fn is_field_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+fn is_reserved(name: &str) -> bool {
+    let name = name.as_bytes();
+    name.eq_ignore_ascii_case(b"lastchanged")
+        || name.len() >= 12 && name[(name.len() - 12)..].eq_ignore_ascii_case(b"-lastchanged")
+}
+
+impl Downloadable {
+    /// Check the names of the fields in the item's `metadata` and
+    /// `metadata_ops` with [`check_field_name()`], along with the values
+    /// being set, added, or removed, which cannot be empty.  With
+    /// [`BadMetadataPolicy::Reject`], the first problem found is returned as
+    /// an error, and the item is left unchanged; with the other policies,
+    /// problems are fixed as described for each policy and logged.
+    ///
+    /// Fields are processed in sorted order.  When sanitizing gives several
+    /// fields the same name, their values are combined in that order, while
+    /// only the first of their operations is kept and the rest are logged &
+    /// dropped.
+    pub fn check_metadata(&mut self, policy: BadMetadataPolicy) -> Result<(), MetadataError> {
+        let path = &self.path;
+        let mut metadata = HashMap::with_capacity(self.metadata.len());
+        let mut fields = self.metadata.iter().collect::<Vec<_>>();
+        fields.sort_unstable_by_key(|&(field, _)| field);
+        for (field, values) in fields {
+            let Some(field) = check_field(field.clone(), policy, path)? else {
+                continue;
+            };
+            let Some(values) = check_values(&field, values.clone(), policy, path)? else {
+                continue;
+            };
+            let existing: &mut Vec<String> = metadata.entry(field).or_default();
+            for v in values {
+                if !existing.contains(&v) {
+                    existing.push(v);
+                }
+            }
+        }
+        let mut ops = HashMap::with_capacity(self.metadata_ops.len());
+        let mut fields = self.metadata_ops.iter().collect::<Vec<_>>();
+        fields.sort_unstable_by_key(|&(field, _)| field);
+        for (original, op) in fields {
+            let Some(field) = check_field(original.clone(), policy, path)? else {
+                continue;
+            };
+            let op = match op.clone() {
+                MetadataOp::Add(values) => {
+                    check_values(&field, values, policy, path)?.map(MetadataOp::Add)
+                }
+                MetadataOp::Remove(values) => {
+                    check_values(&field, values, policy, path)?.map(MetadataOp::Remove)
+                }
+                MetadataOp::Delete => Some(MetadataOp::Delete),
+                MetadataOp::SetIfUnset(values) => {
+                    check_values(&field, values, policy, path)?.map(MetadataOp::SetIfUnset)
+                }
+            };
+            if let Some(op) = op {
+                match ops.entry(field) {
+                    Entry::Vacant(e) => {
+                        e.insert(op);
+                    }
+                    Entry::Occupied(e) => log::warn!(
+                        "{path}: dropping operation on metadata field {original:?}, as field {:?} already has one",
+                        e.key()
+                    ),
+                }
+            }
+        }
+        self.metadata = metadata;
+        self.metadata_ops = ops;
+        Ok(())
+    }
+}
+
+/// Check a field name according to `policy`, returning the name to use, or
+/// `None` if the field should be removed
+fn check_field(
+    field: String,
+    policy: BadMetadataPolicy,
+    path: impl fmt::Display,
+) -> Result<Option<String>, MetadataError> {
+    let Err(e) = check_field_name(&field) else {
+        return Ok(Some(field));
+    };
+    match policy {
+        BadMetadataPolicy::Reject => Err(e),
+        BadMetadataPolicy::Drop => {
+            log::warn!("{path}: dropping metadata field: {e}");
+            Ok(None)
+        }
+        BadMetadataPolicy::Sanitize => {
+            if let Some(sanitized) = sanitize_field_name(&field) {
+                log::info!("{path}: renamed metadata field {field:?} to {sanitized:?}");
+                Ok(Some(sanitized))
+            } else {
+                log::warn!("{path}: dropping metadata field: {e}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Check the values for a field according to `policy`, returning the values
+/// to use, or `None` if all of the given values were removed.  An empty list
+/// of values is valid (it deletes the field) and is returned unchanged.
+fn check_values(
+    field: &str,
+    values: Vec<String>,
+    policy: BadMetadataPolicy,
+    path: impl fmt::Display,
+) -> Result<Option<Vec<String>>, MetadataError> {
+    if !values.iter().any(String::is_empty) {
+        return Ok(Some(values));
+    }
+    if policy == BadMetadataPolicy::Reject {
+        return Err(MetadataError::EmptyValue(field.into()));
+    }
+    log::warn!("{path}: dropping empty value of metadata field {field:?}");
+    let values = values
+        .into_iter()
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>();
+    Ok((!values.is_empty()).then_some(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ExpectedContent, FilePath};
+    use rstest::rstest;
+    use url::Url;
+
+    #[rstest]
+    #[case("author", Ok(()))]
+    #[case("Year_2020.v-1", Ok(()))]
+    #[case("été", Ok(()))]
+    #[case("", Err(MetadataError::EmptyField))]
+    #[case("-tag", Err(MetadataError::BadStart("-tag".into())))]
+    #[case("my field", Err(MetadataError::BadChar {field: "my field".into(), c: ' '}))]
+    #[case("a/b", Err(MetadataError::BadChar {field: "a/b".into(), c: '/'}))]
+    #[case("lastchanged", Err(MetadataError::Reserved("lastchanged".into())))]
+    #[case("tag-LastChanged", Err(MetadataError::Reserved("tag-LastChanged".into())))]
+    #[case("taglastchanged", Ok(()))]
+    fn test_check_field_name(#[case] name: &str, #[case] r: Result<(), MetadataError>) {
+        assert_eq!(check_field_name(name), r);
+    }
+
+    #[rstest]
+    #[case("author", Some("author"))]
+    #[case("my field", Some("my_field"))]
+    #[case("--tag", Some("tag"))]
+    #[case("a/b:c", Some("a_b_c"))]
+    #[case("lastchanged", Some("lastchanged_"))]
+    #[case("tag-lastchanged", Some("tag_lastchanged"))]
+    #[case("---", None)]
+    #[case("", None)]
+    fn test_sanitize_field_name(#[case] name: &str, #[case] sanitized: Option<&str>) {
+        assert_eq!(sanitize_field_name(name).as_deref(), sanitized);
+        if let Some(s) = sanitized {
+            assert_eq!(check_field_name(s), Ok(()));
+        }
+    }
+
+    fn sample() -> Downloadable {
+        Downloadable {
+            path: FilePath::try_from("a.txt").unwrap(),
+            url: Url::parse("https://example.com/a.txt").unwrap(),
+            metadata: HashMap::from([
+                ("author".into(), vec!["Alice".into()]),
+                ("my tag".into(), vec!["x".into(), String::new()]),
+                ("my_tag".into(), vec!["y".into()]),
+                ("!!!".into(), vec!["z".into()]),
+                ("empty".into(), Vec::new()),
+            ]),
+            metadata_ops: HashMap::from([
+                ("flags".into(), MetadataOp::Add(vec![String::new()])),
+                ("old-lastchanged".into(), MetadataOp::Delete),
+            ]),
+            extra_urls: Vec::new(),
+            on_existing: None,
+            original_path: None,
+            expected: ExpectedContent::default(),
+        }
+    }
+
+    #[test]
+    fn test_check_metadata_reject() {
+        let mut dl = sample();
+        assert!(dl.check_metadata(BadMetadataPolicy::Reject).is_err());
+        assert_eq!(dl, sample());
+        let mut dl = sample();
+        dl.metadata.retain(|k, _| k == "author" || k == "empty");
+        dl.metadata_ops.clear();
+        let before = dl.clone();
+        assert_eq!(dl.check_metadata(BadMetadataPolicy::Reject), Ok(()));
+        assert_eq!(dl, before);
+    }
+
+    #[test]
+    fn test_check_metadata_drop() {
+        let mut dl = sample();
+        assert_eq!(dl.check_metadata(BadMetadataPolicy::Drop), Ok(()));
+        assert_eq!(
+            dl.metadata,
+            HashMap::from([
+                ("author".into(), vec!["Alice".into()]),
+                ("my_tag".into(), vec!["y".into()]),
+                ("empty".into(), Vec::new()),
+            ])
+        );
+        assert!(dl.metadata_ops.is_empty());
+    }
+
+    #[test]
+    fn test_check_metadata_sanitize() {
+        let mut dl = sample();
+        assert_eq!(dl.check_metadata(BadMetadataPolicy::Sanitize), Ok(()));
+        assert_eq!(
+            dl.metadata,
+            HashMap::from([
+                ("author".into(), vec!["Alice".into()]),
+                ("my_tag".into(), vec!["x".into(), "y".into()]),
+                ("empty".into(), Vec::new()),
+            ])
+        );
+        assert_eq!(
+            dl.metadata_ops,
+            HashMap::from([("old_lastchanged".into(), MetadataOp::Delete)])
+        );
+    }
+
+    #[test]
+    fn test_check_metadata_sanitize_op_clash() {
+        for _ in 0..10 {
+            let mut dl = sample();
+            dl.metadata_ops = HashMap::from([
+                ("my tag".into(), MetadataOp::Add(vec!["x".into()])),
+                ("my/tag".into(), MetadataOp::Delete),
+                ("my:tag".into(), MetadataOp::Remove(vec!["y".into()])),
+            ]);
+            assert_eq!(dl.check_metadata(BadMetadataPolicy::Sanitize), Ok(()));
+            assert_eq!(
+                dl.metadata_ops,
+                HashMap::from([("my_tag".into(), MetadataOp::Add(vec!["x".into()]))])
+            );
+        }
+    }
+
+    #[rstest]
+    #[case("reject", Ok(BadMetadataPolicy::Reject))]
+    #[case("SANITIZE", Ok(BadMetadataPolicy::Sanitize))]
+    #[case("skip", Err(ParseBadMetadataPolicyError("skip".into())))]
+    fn test_parse_policy(
+        #[case] s: &str,
+        #[case] r: Result<BadMetadataPolicy, ParseBadMetadataPolicyError>,
+    ) {
+        assert_eq!(s.parse::<BadMetadataPolicy>(), r);
+    }
+}
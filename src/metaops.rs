@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// An operation to perform on a single metadata field of an item's key,
+/// relative to the field's current values
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataOp {
+    /// Add the given values to the field, keeping its current values
+    Add(Vec<String>),
+    /// Remove the given values from the field, keeping its other values
+    Remove(Vec<String>),
+    /// Remove all values of the field
+    Delete,
+    /// Set the field to the given values if it currently has no values;
+    /// otherwise, leave it unchanged
+    SetIfUnset(Vec<String>),
+}
+
+impl MetadataOp {
+    /// Returns true if applying the operation requires knowing the field's
+    /// current values
+    pub(crate) fn needs_current(&self) -> bool {
+        !matches!(self, MetadataOp::Delete)
+    }
+
+    /// Returns the field's new values given its current values, or `None` if
+    /// the field should be left unchanged
+    fn apply(&self, current: &[String]) -> Option<Vec<String>> {
+        match self {
+            MetadataOp::Add(values) => {
+                let mut new = current.to_vec();
+                for v in values {
+                    if !new.contains(v) {
+                        new.push(v.clone());
+                    }
+                }
+                Some(new)
+            }
+            MetadataOp::Remove(values) => Some(
+                current
+                    .iter()
+                    .filter(|v| !values.contains(v))
+                    .cloned()
+                    .collect(),
+            ),
+            MetadataOp::Delete => Some(Vec::new()),
+            MetadataOp::SetIfUnset(values) => current.is_empty().then(|| values.clone()),
+        }
+    }
+}
+
+/// Compute the fields to send to `git-annex metadata` for an item whose
+/// `metadata` replaces the values of the fields it names and whose `ops` are
+/// then applied on top, given the current metadata of the item's key.  A
+/// field with an empty list of values in the result is deleted.
+///
+/// `current` is only consulted for fields whose operations
+/// [need it][MetadataOp::needs_current] and that are not also in `metadata`.
+pub(crate) fn resolve_fields(
+    current: &HashMap<String, Vec<String>>,
+    metadata: &HashMap<String, Vec<String>>,
+    ops: &HashMap<String, MetadataOp>,
+) -> HashMap<String, Vec<String>> {
+    let mut fields = metadata.clone();
+    for (name, op) in ops {
+        let base = metadata
+            .get(name)
+            .or_else(|| current.get(name))
+            .map_or(&[][..], Vec::as_slice);
+        if let Some(values) = op.apply(base) {
+            fields.insert(name.clone(), values);
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn strs(values: &[&str]) -> Vec<String> {
+        values.iter().map(|&s| String::from(s)).collect()
+    }
+
+    #[rstest]
+    #[case(MetadataOp::Add(strs(&["b", "c"])), &["a", "b"], Some(&["a", "b", "c"][..]))]
+    #[case(MetadataOp::Add(strs(&["b"])), &[], Some(&["b"][..]))]
+    #[case(MetadataOp::Remove(strs(&["a", "z"])), &["a", "b"], Some(&["b"][..]))]
+    #[case(MetadataOp::Delete, &["a", "b"], Some(&[][..]))]
+    #[case(MetadataOp::SetIfUnset(strs(&["x"])), &[], Some(&["x"][..]))]
+    #[case(MetadataOp::SetIfUnset(strs(&["x"])), &["a"], None)]
+    fn test_apply(#[case] op: MetadataOp, #[case] current: &[&str], #[case] new: Option<&[&str]>) {
+        assert_eq!(op.apply(&strs(current)), new.map(strs));
+    }
+
+    #[test]
+    fn test_resolve_fields() {
+        let current = HashMap::from([
+            ("tags".into(), strs(&["old", "stale"])),
+            ("status".into(), strs(&["done"])),
+            ("color".into(), strs(&["red"])),
+        ]);
+        let metadata = HashMap::from([
+            ("author".into(), strs(&["Alice"])),
+            ("color".into(), strs(&["blue"])),
+        ]);
+        let ops = HashMap::from([
+            ("tags".into(), MetadataOp::Add(strs(&["new"]))),
+            ("status".into(), MetadataOp::SetIfUnset(strs(&["todo"]))),
+            ("color".into(), MetadataOp::Add(strs(&["green"]))),
+            ("junk".into(), MetadataOp::Delete),
+        ]);
+        assert_eq!(
+            resolve_fields(&current, &metadata, &ops),
+            HashMap::from([
+                ("author".into(), strs(&["Alice"])),
+                ("color".into(), strs(&["blue", "green"])),
+                ("tags".into(), strs(&["old", "stale", "new"])),
+                ("junk".into(), Vec::new()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_load_metadata_ops() {
+        let ops = serde_json::from_str::<HashMap<String, MetadataOp>>(
+            r#"{"tags": {"add": ["x"]}, "old": "delete", "status": {"set_if_unset": ["new"]}, "flags": {"remove": ["y"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ops,
+            HashMap::from([
+                ("tags".into(), MetadataOp::Add(strs(&["x"]))),
+                ("old".into(), MetadataOp::Delete),
+                ("status".into(), MetadataOp::SetIfUnset(strs(&["new"]))),
+                ("flags".into(), MetadataOp::Remove(strs(&["y"]))),
+            ])
+        );
+    }
+}
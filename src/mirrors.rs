@@ -0,0 +1,296 @@
+use crate::annex::lookupkey::{LookupKeyDecoder, LookupKeyInput};
+use crate::annex::registerurl::{RegisterURLInput, RegisterURLOutput};
+use crate::annex::{AnnexError, AnnexIO};
+use crate::report::write_json_lines;
+use crate::{ensure_annex_repo, quantify, FilePath, Gamdam, Journal, Shutdown};
+use futures_util::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::pin::pin;
+use thiserror::Error;
+use url::Url;
+
+/// An input item for [`Gamdam::register_mirrors()`]: a set of URLs to
+/// register for a git-annex key, identified either directly or by the path of
+/// an annexed file
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "RawMirrorItem")]
+pub struct MirrorItem {
+    #[serde(flatten)]
+    pub target: MirrorTarget,
+    #[serde(default)]
+    pub extra_urls: Vec<Url>,
+}
+
+#[derive(Deserialize)]
+struct RawMirrorItem {
+    key: Option<String>,
+    path: Option<FilePath>,
+    #[serde(default)]
+    extra_urls: Vec<Url>,
+}
+
+impl TryFrom<RawMirrorItem> for MirrorItem {
+    type Error = MirrorItemError;
+
+    fn try_from(raw: RawMirrorItem) -> Result<MirrorItem, MirrorItemError> {
+        let target = match (raw.key, raw.path) {
+            (Some(key), None) => MirrorTarget::Key(key),
+            (None, Some(path)) => MirrorTarget::Path(path),
+            (None, None) => return Err(MirrorItemError::NoTarget),
+            (Some(_), Some(_)) => return Err(MirrorItemError::BothTargets),
+        };
+        Ok(MirrorItem {
+            target,
+            extra_urls: raw.extra_urls,
+        })
+    }
+}
+
+/// Error returned when deserializing a [`MirrorItem`] that does not have
+/// exactly one of a "key" field and a "path" field
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum MirrorItemError {
+    #[error("item has neither a \"key\" nor a \"path\" field")]
+    NoTarget,
+    #[error("item has both a \"key\" and a \"path\" field")]
+    BothTargets,
+}
+
+/// The key that a [`MirrorItem`]'s URLs are registered for
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorTarget {
+    /// A git-annex key
+    Key(String),
+    /// The path to an annexed file, relative to the repository root
+    Path(FilePath),
+}
+
+impl fmt::Display for MirrorTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MirrorTarget::Key(key) => write!(f, "{key}"),
+            MirrorTarget::Path(path) => write!(f, "{path}"),
+        }
+    }
+}
+
+/// The result of registering the URLs of a [`MirrorItem`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MirrorResult {
+    pub item: MirrorItem,
+    /// The key that the URLs were registered for, or the error that occurred
+    /// looking up the key of the item's path
+    pub key: Result<String, AnnexError>,
+    pub urls_added: HashMap<Url, Result<(), AnnexError>>,
+}
+
+impl MirrorResult {
+    pub fn success(&self) -> bool {
+        self.key.is_ok() && self.urls_added.values().all(Result::is_ok)
+    }
+}
+
+/// A report of a [`Gamdam::register_mirrors()`] run
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MirrorReport {
+    /// All items whose URLs were all registered successfully
+    pub successful: Vec<MirrorResult>,
+    /// All items for which looking up the key or registering a URL failed
+    pub failed: Vec<MirrorResult>,
+    /// All input items that were never processed because the run was
+    /// stopped early
+    pub unprocessed: Vec<MirrorItem>,
+}
+
+impl Gamdam {
+    /// Register the extra URLs of each item yielded by `items` for the item's
+    /// key using `git-annex registerurl`, without downloading anything.  Items
+    /// given by path have their keys looked up with `git-annex lookupkey`
+    /// first.  The run can be interrupted through `shutdown`.
+    ///
+    /// As with [`Gamdam::try_download_with_shutdown()`], the repository is
+    /// first created & initialized as needed unless disabled.  The journal,
+    /// report file, and failures file are used if configured; nothing is
+    /// committed, as registering URLs only changes the git-annex branch.  A
+    /// stream error aborts the run and is returned.
+    pub async fn register_mirrors<S>(
+        &self,
+        items: S,
+        shutdown: &Shutdown,
+    ) -> Result<MirrorReport, anyhow::Error>
+    where
+        S: Stream<Item = Result<MirrorItem, anyhow::Error>> + Send,
+    {
+        if self.init_repo {
+            ensure_annex_repo(&self.repo).await?;
+        }
+        let journal = match self.journal {
+            Some(ref path) => Journal::open(path, self.resume).await?,
+            None => Journal::disabled(),
+        };
+        let journal = &journal;
+        let report = self
+            .lookupkey()?
+            .in_context(
+                |lookupkey| async {
+                    self.registerurl()?
+                        .in_context(
+                            |registerurl| async move {
+                                self.feed_registerurl(
+                                    items,
+                                    lookupkey,
+                                    registerurl,
+                                    journal,
+                                    shutdown,
+                                )
+                                .await
+                            },
+                            &shutdown.kill,
+                        )
+                        .await
+                },
+                &shutdown.kill,
+            )
+            .await?;
+        log::info!(
+            "Registered URLs for {}",
+            quantify(report.successful.len(), "item")
+        );
+        if !report.failed.is_empty() {
+            log::error!("{} failed", quantify(report.failed.len(), "item"));
+        }
+        if !report.unprocessed.is_empty() {
+            log::warn!(
+                "{} not processed due to interruption",
+                quantify(report.unprocessed.len(), "item")
+            );
+        }
+        if let Some(ref path) = self.report {
+            if let Err(e) = write_json_lines(path, report.records()).await {
+                log::error!("Error writing run report: {e}");
+            }
+        }
+        if let Some(ref path) = self.failures {
+            if !report.failed.is_empty() || !report.unprocessed.is_empty() {
+                let failures = report
+                    .failed
+                    .iter()
+                    .map(|r| &r.item)
+                    .chain(report.unprocessed.iter());
+                if let Err(e) = write_json_lines(path, failures).await {
+                    log::error!("Error writing failures report: {e}");
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    async fn feed_registerurl<S>(
+        &self,
+        items: S,
+        mut lookupkey: AnnexIO<LookupKeyInput, Option<String>, LookupKeyDecoder>,
+        mut registerurl: AnnexIO<RegisterURLInput, RegisterURLOutput>,
+        journal: &Journal,
+        shutdown: &Shutdown,
+    ) -> Result<MirrorReport, anyhow::Error>
+    where
+        S: Stream<Item = Result<MirrorItem, anyhow::Error>> + Send,
+    {
+        let mut items = pin!(items);
+        let mut report = MirrorReport::default();
+        loop {
+            let item = tokio::select! {
+                r = items.try_next() => match r? {
+                    Some(item) => item,
+                    None => break,
+                },
+                () = shutdown.stop.cancelled() => break,
+                () = shutdown.kill.cancelled() => anyhow::bail!("Run was forcibly terminated"),
+            };
+            let (path, key) = match item.target {
+                MirrorTarget::Key(ref key) => (None, Ok(key.clone())),
+                MirrorTarget::Path(ref path) => {
+                    let key =
+                        if let Some(key) = lookupkey.chat(LookupKeyInput(path.clone())).await? {
+                            log::info!("Found {path} (key = {key})");
+                            Ok(key)
+                        } else {
+                            let msg = format!("{path} does not exist or is not an annexed file");
+                            log::error!("{msg}");
+                            Err(AnnexError(vec![msg]))
+                        };
+                    (Some(path), key)
+                }
+            };
+            let mut urls_added = HashMap::new();
+            if let Ok(ref key) = key {
+                for u in &item.extra_urls {
+                    let res = self
+                        .register_url(&mut registerurl, path, key, u, journal)
+                        .await?;
+                    urls_added.insert(u.clone(), res);
+                }
+            }
+            let r = MirrorResult {
+                item: item.clone(),
+                key,
+                urls_added,
+            };
+            if r.success() {
+                report.successful.push(r);
+            } else {
+                report.failed.push(r);
+            }
+        }
+        log::debug!("Done registering URLs");
+        if shutdown.is_stopped() {
+            log::info!("Collecting unprocessed input items ...");
+            loop {
+                tokio::select! {
+                    r = items.try_next() => match r? {
+                        Some(item) => report.unprocessed.push(item),
+                        None => break,
+                    },
+                    () = shutdown.kill.cancelled() => anyhow::bail!("Run was forcibly terminated"),
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        r#"{"key": "SHA256E-s19--0123.txt", "extra_urls": ["https://mirror.example/a.txt"]}"#,
+        MirrorItem {
+            target: MirrorTarget::Key("SHA256E-s19--0123.txt".into()),
+            extra_urls: vec![Url::parse("https://mirror.example/a.txt").unwrap()],
+        },
+    )]
+    #[case(
+        r#"{"path": "foo/a.txt"}"#,
+        MirrorItem {
+            target: MirrorTarget::Path(FilePath::try_from("foo/a.txt").unwrap()),
+            extra_urls: Vec::new(),
+        },
+    )]
+    fn test_load_mirror_item(#[case] s: &str, #[case] item: MirrorItem) {
+        assert_eq!(serde_json::from_str::<MirrorItem>(s).unwrap(), item);
+    }
+
+    #[rstest]
+    #[case(r#"{"extra_urls": ["https://mirror.example/a.txt"]}"#)]
+    #[case(r#"{"key": "SHA256E-s19--0123.txt", "path": "foo/a.txt"}"#)]
+    #[case(r#"{"path": "../a.txt"}"#)]
+    fn test_load_bad_mirror_item(#[case] s: &str) {
+        assert!(serde_json::from_str::<MirrorItem>(s).is_err());
+    }
+}
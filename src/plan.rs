@@ -0,0 +1,306 @@
+use crate::existing::{looks_annexed, path_status, PathStatus};
+use crate::report::write_json_lines;
+use crate::{quantify, Downloadable, DuplicatePolicy, ExistingPolicy, FilePath, Gamdam};
+use futures_util::{future::Either, Stream, TryStreamExt};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::pin::pin;
+
+/// What a run would do with a single input item
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "status", content = "reason", rename_all = "lowercase")]
+pub enum PlannedAction {
+    /// The URL would be downloaded to the path
+    Download,
+    /// The item would not be downloaded
+    Skip(SkipReason),
+    /// Downloading the item would fail
+    Conflict(ConflictReason),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// An earlier input item has the same path
+    DuplicatePath,
+    /// The path is already an annexed file; the URL would just be registered
+    /// for it
+    AlreadyAnnexed,
+    /// Something already exists at the path, and the existing-path policy is
+    /// to skip such items
+    Exists,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::DuplicatePath => write!(f, "an earlier entry has the same path"),
+            SkipReason::AlreadyAnnexed => write!(f, "path is already an annexed file"),
+            SkipReason::Exists => write!(f, "path already exists"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictReason {
+    /// A file that is not managed by git-annex exists at the path
+    NotAnnexed,
+    /// A directory exists at the path
+    DirectoryInTheWay,
+    /// A leading component of the path exists and is not a directory
+    ParentNotDirectory,
+    /// Something already exists at the path, and the existing-path policy is
+    /// to fail such items
+    Exists,
+    /// The path collides with another path, and the collision policy is to
+    /// fail
+    PathCollision,
+    /// An earlier input item has the same path, and the duplicate-path
+    /// policy is to fail
+    DuplicatePath,
+}
+
+impl fmt::Display for ConflictReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictReason::NotAnnexed => write!(f, "path exists and is not an annexed file"),
+            ConflictReason::DirectoryInTheWay => write!(f, "path is a directory"),
+            ConflictReason::ParentNotDirectory => {
+                write!(f, "a parent of the path is not a directory")
+            }
+            ConflictReason::Exists => write!(f, "path already exists"),
+            ConflictReason::PathCollision => write!(f, "path collides with another path"),
+            ConflictReason::DuplicatePath => write!(f, "an earlier entry has the same path"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct PlanEntry {
+    #[serde(flatten)]
+    pub action: PlannedAction,
+    #[serde(flatten)]
+    pub downloadable: Downloadable,
+}
+
+/// The outcome of [`Gamdam::plan()`]: what a run on the same input would do
+/// with each item, in input order
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Plan {
+    pub entries: Vec<PlanEntry>,
+}
+
+impl Plan {
+    pub fn downloads(&self) -> usize {
+        self.count(|a| matches!(a, PlannedAction::Download))
+    }
+
+    pub fn skips(&self) -> usize {
+        self.count(|a| matches!(a, PlannedAction::Skip(_)))
+    }
+
+    pub fn conflicts(&self) -> usize {
+        self.count(|a| matches!(a, PlannedAction::Conflict(_)))
+    }
+
+    fn count<F: Fn(&PlannedAction) -> bool>(&self, pred: F) -> usize {
+        self.entries.iter().filter(|e| pred(&e.action)).count()
+    }
+}
+
+impl Gamdam {
+    /// Determine what downloading the items yielded by `items` would do
+    /// without actually downloading anything.  Each item's path is checked
+    /// for duplicates within the input and against the files in the
+    /// repository's working tree, taking the existing-path policy into
+    /// account.  If the duplicate-path or collision policies require it, the
+    /// entire input is first read and checked.
+    ///
+    /// No git-annex processes are run, and the repository is not created or
+    /// initialized if it does not exist.  If a report file is configured, one
+    /// [`PlanEntry`] per item is written to it; errors doing so are logged
+    /// but do not cause planning to fail.
+    pub async fn plan<S>(&self, items: S) -> Result<Plan, anyhow::Error>
+    where
+        S: Stream<Item = Result<Downloadable, anyhow::Error>> + Send,
+    {
+        let mut items = pin!(if self.needs_preflight() {
+            let checked = self.preflight(items).await?;
+            Either::Left(futures_util::stream::iter(checked.into_iter().map(Ok)))
+        } else {
+            Either::Right(items.map_ok(|dl| (dl, None)))
+        });
+        let mut seen = HashSet::new();
+        let mut plan = Plan::default();
+        while let Some((dl, collision)) = items.try_next().await? {
+            let action = if collision.is_some() {
+                PlannedAction::Conflict(ConflictReason::PathCollision)
+            } else if seen.insert(dl.path.clone()) {
+                check_path(&self.repo, &dl.path, dl.on_existing.or(self.on_existing)).await?
+            } else if self.duplicates == DuplicatePolicy::Error {
+                PlannedAction::Conflict(ConflictReason::DuplicatePath)
+            } else {
+                PlannedAction::Skip(SkipReason::DuplicatePath)
+            };
+            match action {
+                PlannedAction::Download => {
+                    log::info!("Would download {} to {}", dl.url, dl.path);
+                }
+                PlannedAction::Skip(reason) => {
+                    log::warn!("Would skip {}: {reason}", dl.path);
+                }
+                PlannedAction::Conflict(reason) => {
+                    log::error!("Cannot download {} to {}: {reason}", dl.url, dl.path);
+                }
+            }
+            plan.entries.push(PlanEntry {
+                action,
+                downloadable: dl,
+            });
+        }
+        log::info!(
+            "Would download {}, skip {}; {} found",
+            quantify(plan.downloads(), "file"),
+            plan.skips(),
+            quantify(plan.conflicts(), "conflict"),
+        );
+        if let Some(ref path) = self.report {
+            if let Err(e) = write_json_lines(path, &plan.entries).await {
+                log::error!("Error writing run report: {e}");
+            }
+        }
+        Ok(plan)
+    }
+}
+
+/// Determine what would happen when downloading to `path` in `repo` under
+/// the given existing-path policy.  As git-annex is not run, whether an
+/// existing file is annexed is judged by [`looks_annexed()`].
+async fn check_path(
+    repo: &Path,
+    path: &FilePath,
+    policy: Option<ExistingPolicy>,
+) -> Result<PlannedAction, anyhow::Error> {
+    use ExistingPolicy::*;
+    use PathStatus::*;
+    let status = path_status(repo, path).await?;
+    Ok(match (status, policy) {
+        (Absent, _) => PlannedAction::Download,
+        (ParentNotDirectory, _) => PlannedAction::Conflict(ConflictReason::ParentNotDirectory),
+        (_, Some(Skip)) => PlannedAction::Skip(SkipReason::Exists),
+        (_, Some(Error)) => PlannedAction::Conflict(ConflictReason::Exists),
+        (_, Some(RenameWithSuffix)) | (File, Some(Replace)) => PlannedAction::Download,
+        (File, None | Some(RegisterUrlOnly)) => {
+            if looks_annexed(repo, path).await? {
+                PlannedAction::Skip(SkipReason::AlreadyAnnexed)
+            } else {
+                PlannedAction::Conflict(ConflictReason::NotAnnexed)
+            }
+        }
+        (Directory, _) => PlannedAction::Conflict(ConflictReason::DirectoryInTheWay),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExpectedContent;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+    use url::Url;
+
+    fn dl(path: &str) -> Downloadable {
+        Downloadable {
+            path: path.try_into().unwrap(),
+            url: Url::parse("https://example.com/file").unwrap(),
+            metadata: HashMap::new(),
+            metadata_ops: HashMap::new(),
+            extra_urls: Vec::new(),
+            on_existing: None,
+            original_path: None,
+            expected: ExpectedContent::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_path_policies() {
+        let tmpdir = tempdir().unwrap();
+        let repo = tmpdir.path();
+        std::fs::create_dir_all(repo.join("dir")).unwrap();
+        std::fs::write(repo.join("plain.txt"), "Hello\n").unwrap();
+        let plain = FilePath::try_from("plain.txt").unwrap();
+        let dir = FilePath::try_from("dir").unwrap();
+        let new = FilePath::try_from("dir/new.txt").unwrap();
+        for (path, policy, action) in [
+            (&new, Some(ExistingPolicy::Error), PlannedAction::Download),
+            (
+                &plain,
+                None,
+                PlannedAction::Conflict(ConflictReason::NotAnnexed),
+            ),
+            (
+                &plain,
+                Some(ExistingPolicy::Skip),
+                PlannedAction::Skip(SkipReason::Exists),
+            ),
+            (
+                &plain,
+                Some(ExistingPolicy::RegisterUrlOnly),
+                PlannedAction::Conflict(ConflictReason::NotAnnexed),
+            ),
+            (
+                &plain,
+                Some(ExistingPolicy::Replace),
+                PlannedAction::Download,
+            ),
+            (
+                &plain,
+                Some(ExistingPolicy::RenameWithSuffix),
+                PlannedAction::Download,
+            ),
+            (
+                &plain,
+                Some(ExistingPolicy::Error),
+                PlannedAction::Conflict(ConflictReason::Exists),
+            ),
+            (
+                &dir,
+                Some(ExistingPolicy::Replace),
+                PlannedAction::Conflict(ConflictReason::DirectoryInTheWay),
+            ),
+            (
+                &dir,
+                Some(ExistingPolicy::RenameWithSuffix),
+                PlannedAction::Download,
+            ),
+        ] {
+            assert_eq!(
+                check_path(repo, path, policy).await.unwrap(),
+                action,
+                "{path} with {policy:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_serialize_plan_entry() {
+        let entry = PlanEntry {
+            action: PlannedAction::Conflict(ConflictReason::DirectoryInTheWay),
+            downloadable: dl("foo/bar"),
+        };
+        assert_eq!(
+            serde_json::to_value(entry).unwrap(),
+            serde_json::json!({
+                "status": "conflict",
+                "reason": "directory_in_the_way",
+                "path": "foo/bar",
+                "url": "https://example.com/file",
+                "metadata": {},
+                "extra_urls": [],
+            })
+        );
+    }
+}
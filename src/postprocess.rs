@@ -0,0 +1,497 @@
+use crate::annex::AnnexError;
+use crate::commit::BatchCommitter;
+use crate::{
+    DownloadResult, Event, ExistingOutcome, FilePath, Gamdam, Journal, JournalEntry, MetadataOp,
+    Report, Stage,
+};
+use chrono::Utc;
+use futures_util::future::try_join_all;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{hash_map::Entry, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+/// A request to set the metadata of an item's key
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct MetadataJob {
+    id: usize,
+    path: FilePath,
+    key: String,
+    metadata: HashMap<String, Vec<String>>,
+    metadata_ops: HashMap<String, MetadataOp>,
+}
+
+/// A request to register a URL for an item's key
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct UrlJob {
+    id: usize,
+    path: FilePath,
+    key: String,
+    url: Url,
+}
+
+/// A message from a post-processing worker reporting the outcome of a job
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum JobDone {
+    Metadata {
+        id: usize,
+        result: Result<(), AnnexError>,
+    },
+    Url {
+        id: usize,
+        url: Url,
+        result: Result<(), AnnexError>,
+    },
+}
+
+/// The sending ends of the post-processing stages' queues
+#[derive(Debug)]
+struct StageQueues {
+    /// One queue per `git-annex metadata` worker.  Jobs are assigned by key
+    /// so that updates to the same key are applied in order.
+    metadata: Vec<Sender<MetadataJob>>,
+    /// A queue shared by all `git-annex registerurl` workers
+    urls: Sender<UrlJob>,
+}
+
+impl StageQueues {
+    async fn send_metadata(&self, job: MetadataJob) -> Result<(), anyhow::Error> {
+        let mut hasher = DefaultHasher::new();
+        job.key.hash(&mut hasher);
+        // The modulus is less than `usize::MAX`, so this can't truncate.
+        #[allow(clippy::cast_possible_truncation)]
+        let i = (hasher.finish() % (self.metadata.len() as u64)) as usize;
+        self.metadata[i]
+            .send(job)
+            .await
+            .map_err(|_| anyhow::anyhow!("`git-annex metadata` worker exited unexpectedly"))
+    }
+
+    async fn send_url(&self, job: UrlJob) -> Result<(), anyhow::Error> {
+        self.urls
+            .send(job)
+            .await
+            .map_err(|_| anyhow::anyhow!("`git-annex registerurl` worker exited unexpectedly"))
+    }
+}
+
+/// A received item whose post-processing jobs are not all done yet
+#[derive(Debug)]
+struct PendingItem {
+    result: DownloadResult,
+    remaining: usize,
+}
+
+/// What to do next with an item in [`Gamdam::join_post_processing()`]
+#[derive(Debug)]
+enum Step {
+    /// The item is ready to have its jobs dispatched
+    Dispatch(DownloadResult),
+    /// All of the item's jobs are done
+    Finished(DownloadResult),
+}
+
+impl Gamdam {
+    /// Verify each item received from `receiver`, then set its metadata and
+    /// register its extra URLs.  Up to `MAX_VERIFYING` items are verified at
+    /// once alongside the other stages.  Metadata is set by `metadata_jobs`
+    /// `git-annex metadata` processes and URLs are registered by
+    /// `registerurl_jobs` `git-annex registerurl` processes, each stage fed
+    /// from its own queue, so that a slow stage doesn't hold up the other.
+    /// An item is added to the report once all of its jobs are done.
+    pub(crate) async fn add_metadata(
+        &self,
+        receiver: Receiver<DownloadResult>,
+        journal: &Journal,
+        kill: &CancellationToken,
+    ) -> Result<Report, anyhow::Error> {
+        let (done_sender, done_receiver) = unbounded_channel();
+        let mut metadata_queues = Vec::with_capacity(self.metadata_jobs.get());
+        let mut metadata_workers = Vec::with_capacity(self.metadata_jobs.get());
+        for _ in 0..self.metadata_jobs.get() {
+            let (sender, receiver) = channel(Self::RESULTS_QUEUE_SIZE);
+            metadata_queues.push(sender);
+            metadata_workers.push(self.metadata_worker(
+                receiver,
+                done_sender.clone(),
+                journal,
+                kill,
+            ));
+        }
+        let (url_sender, url_receiver) = channel(Self::RESULTS_QUEUE_SIZE);
+        let url_receiver = Arc::new(Mutex::new(url_receiver));
+        let url_workers = (0..self.registerurl_jobs.get())
+            .map(|_| {
+                self.registerurl_worker(url_receiver.clone(), done_sender.clone(), journal, kill)
+            })
+            .collect::<Vec<_>>();
+        drop(done_sender);
+        let queues = StageQueues {
+            metadata: metadata_queues,
+            urls: url_sender,
+        };
+        let (report, _, _) = tokio::try_join!(
+            self.join_post_processing(receiver, queues, done_receiver, journal),
+            try_join_all(metadata_workers),
+            try_join_all(url_workers),
+        )?;
+        Ok(report)
+    }
+
+    /// Maximum number of received items whose content may be verified at
+    /// once before receiving further items is paused
+    const MAX_VERIFYING: usize = 8;
+
+    /// Verify each item received from `receiver` that needs it, dispatch the
+    /// metadata & URL jobs for each item to the stage queues, collect the
+    /// workers' outcomes from `done`, and add each item to the report once
+    /// its last job is done
+    async fn join_post_processing(
+        &self,
+        mut receiver: Receiver<DownloadResult>,
+        queues: StageQueues,
+        mut done: UnboundedReceiver<JobDone>,
+        journal: &Journal,
+    ) -> Result<Report, anyhow::Error> {
+        let mut queues = Some(queues);
+        let mut receiving = true;
+        let mut verifying = FuturesUnordered::new();
+        let mut pending: HashMap<usize, PendingItem> = HashMap::new();
+        let mut next_id = 0;
+        let mut successful = Vec::new();
+        let mut failed = Vec::new();
+        let mut committer = BatchCommitter::new(&self.repo, self.save.as_ref(), &self.events);
+        loop {
+            let step = tokio::select! {
+                r = receiver.recv(), if receiving && verifying.len() < Self::MAX_VERIFYING => {
+                    let Some(r) = r else {
+                        receiving = false;
+                        if verifying.is_empty() {
+                            // Closing the queues lets the workers exit once
+                            // they're drained, which in turn closes `done`.
+                            queues = None;
+                        }
+                        continue;
+                    };
+                    if r.download.is_ok() && r.downloaded() && !r.downloadable.expected.is_empty() {
+                        verifying.push(self.verified(r));
+                        continue;
+                    }
+                    Step::Dispatch(r)
+                }
+                Some(r) = verifying.next(), if !verifying.is_empty() => Step::Dispatch(r),
+                d = done.recv() => {
+                    let Some(d) = d else { break };
+                    let id = match d {
+                        JobDone::Metadata { id, .. } | JobDone::Url { id, .. } => id,
+                    };
+                    let Entry::Occupied(mut entry) = pending.entry(id) else {
+                        anyhow::bail!("Received outcome for unknown post-processing job");
+                    };
+                    let p = entry.get_mut();
+                    match d {
+                        JobDone::Metadata { result, .. } => {
+                            p.result.metadata_added = Some(result);
+                        }
+                        JobDone::Url { url, result, .. } => {
+                            p.result.urls_added.insert(url, result);
+                        }
+                    }
+                    p.remaining -= 1;
+                    if p.remaining > 0 {
+                        continue;
+                    }
+                    Step::Finished(entry.remove().result)
+                }
+                () = committer.tick() => {
+                    committer.commit().await;
+                    continue;
+                }
+            };
+            let mut r = match step {
+                Step::Dispatch(r) => {
+                    let Some(ref q) = queues else {
+                        unreachable!("queues should be open while items are being received");
+                    };
+                    let id = next_id;
+                    next_id += 1;
+                    let (r, remaining) = self.dispatch(id, r, q, journal).await?;
+                    if !receiving && verifying.is_empty() {
+                        queues = None;
+                    }
+                    if remaining > 0 {
+                        pending.insert(
+                            id,
+                            PendingItem {
+                                result: r,
+                                remaining,
+                            },
+                        );
+                        continue;
+                    }
+                    r
+                }
+                Step::Finished(r) => r,
+            };
+            r.timings.finished = Some(Utc::now());
+            if r.success() {
+                let downloaded = r.downloaded();
+                successful.push(r);
+                if downloaded {
+                    committer.downloaded().await;
+                }
+            } else {
+                committer.failed();
+                failed.push(r);
+            }
+        }
+        log::debug!("Done post-processing metadata");
+        Ok(Report {
+            successful,
+            failed,
+            unprocessed: Vec::new(),
+            committed: committer.committed(),
+        })
+    }
+
+    /// Verify the content of a downloaded item against its expected size &
+    /// checksums, failing the item if it doesn't match
+    async fn verified(&self, mut r: DownloadResult) -> DownloadResult {
+        if let Err(e) = self.verify(&r).await {
+            r.download = Err(e);
+        }
+        r
+    }
+
+    /// Queue the jobs for setting a received item's metadata and registering
+    /// its extra URLs.  Returns the item along with the number of jobs queued
+    /// for it.
+    async fn dispatch(
+        &self,
+        id: usize,
+        mut r: DownloadResult,
+        queues: &StageQueues,
+        journal: &Journal,
+    ) -> Result<(DownloadResult, usize), anyhow::Error> {
+        if r.download.is_err() {
+            return Ok((r, 0));
+        }
+        let path = &r.downloadable.path;
+        let Some(ref key) = r.key else {
+            if r.existing != Some(ExistingOutcome::Skipped)
+                && (!r.downloadable.metadata.is_empty()
+                    || !r.downloadable.metadata_ops.is_empty()
+                    || !r.downloadable.extra_urls.is_empty())
+            {
+                log::warn!("Cannot set metadata for {path} as it was not assigned a key");
+            }
+            return Ok((r, 0));
+        };
+        // Files that were already in the repository only get the defaults on
+        // request, so that a run doesn't rewrite metadata it didn't create.
+        // The defaults only go into the job so that the item's input record
+        // (as written to the failures file) stays as it was given.
+        let metadata = if !self.metadata_defaults.is_empty()
+            && (r.downloaded() || self.defaults_for_existing)
+        {
+            self.metadata_defaults
+                .merged(&r.downloadable, r.timings.downloaded)
+        } else {
+            r.downloadable.metadata.clone()
+        };
+        let mut jobs = 0;
+        let has_metadata = !metadata.is_empty() || !r.downloadable.metadata_ops.is_empty();
+        if has_metadata && journal.metadata_set(path) {
+            log::info!("Metadata for {path} was already set according to journal; skipping");
+            r.metadata_added = Some(Ok(()));
+        } else if has_metadata {
+            queues
+                .send_metadata(MetadataJob {
+                    id,
+                    path: path.clone(),
+                    key: key.clone(),
+                    metadata,
+                    metadata_ops: r.downloadable.metadata_ops.clone(),
+                })
+                .await?;
+            jobs += 1;
+        }
+        // When registering URLs for an existing file instead of downloading,
+        // the item's main URL needs registering as well.
+        let main_url = matches!(r.existing, Some(ExistingOutcome::RegisteredUrl))
+            .then_some(&r.downloadable.url);
+        for u in main_url.into_iter().chain(&r.downloadable.extra_urls) {
+            queues
+                .send_url(UrlJob {
+                    id,
+                    path: path.clone(),
+                    key: key.clone(),
+                    url: u.clone(),
+                })
+                .await?;
+            jobs += 1;
+        }
+        Ok((r, jobs))
+    }
+
+    /// Run a `git-annex metadata` process that handles the jobs received from
+    /// `jobs`, reporting their outcomes to `done`
+    async fn metadata_worker(
+        &self,
+        mut jobs: Receiver<MetadataJob>,
+        done: UnboundedSender<JobDone>,
+        journal: &Journal,
+        kill: &CancellationToken,
+    ) -> Result<(), anyhow::Error> {
+        self.metadata()?
+            .in_context(
+                |mut metadata| async move {
+                    while let Some(job) = jobs.recv().await {
+                        let MetadataJob { id, path, key, .. } = &job;
+                        log::info!("Setting metadata for {path} ...");
+                        let result = match self
+                            .set_metadata(&mut metadata, &job.metadata, &job.metadata_ops, key)
+                            .await?
+                        {
+                            Ok(()) => {
+                                log::info!("Set metadata on {path}");
+                                self.events.emit(Event::MetadataSet {
+                                    path: path.clone(),
+                                    key: key.clone(),
+                                });
+                                journal
+                                    .record(JournalEntry::Metadata {
+                                        path: path.clone(),
+                                        key: key.clone(),
+                                    })
+                                    .await?;
+                                Ok(())
+                            }
+                            Err(e) => {
+                                log::error!("{path}: setting metadata failed:{e}");
+                                self.events.emit(Event::Failed {
+                                    path: path.clone(),
+                                    stage: Stage::Metadata,
+                                    error: e.clone(),
+                                });
+                                Err(e)
+                            }
+                        };
+                        // If the receiver is gone, the run is being torn
+                        // down due to an error elsewhere.
+                        let _ = done.send(JobDone::Metadata { id: *id, result });
+                    }
+                    Ok(())
+                },
+                kill,
+            )
+            .await
+    }
+
+    /// Run a `git-annex registerurl` process that handles jobs taken from the
+    /// shared queue `jobs`, reporting their outcomes to `done`
+    async fn registerurl_worker(
+        &self,
+        jobs: Arc<Mutex<Receiver<UrlJob>>>,
+        done: UnboundedSender<JobDone>,
+        journal: &Journal,
+        kill: &CancellationToken,
+    ) -> Result<(), anyhow::Error> {
+        self.registerurl()?
+            .in_context(
+                |mut registerurl| async move {
+                    loop {
+                        let Some(UrlJob { id, path, key, url }) = jobs.lock().await.recv().await
+                        else {
+                            break;
+                        };
+                        let result = self
+                            .register_url(&mut registerurl, Some(&path), &key, &url, journal)
+                            .await?;
+                        let _ = done.send(JobDone::Url { id, url, result });
+                    }
+                    Ok(())
+                },
+                kill,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::write_failures;
+    use crate::{AutoField, AutoValue, Downloadable, MetadataDefaults, Timings};
+    use patharg::OutputArg;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_dispatch_keeps_input_metadata() {
+        let gamdam = Gamdam::builder("repo")
+            .metadata_defaults(MetadataDefaults {
+                fields: HashMap::from([("source".into(), vec!["crawl".into()])]),
+                automatic: vec![AutoField::new(AutoValue::Downloaded)],
+                input_file: None,
+            })
+            .unwrap()
+            .build();
+        let dl = serde_json::from_str::<Downloadable>(
+            r#"{"path": "a.txt", "url": "https://example.com/a.txt", "metadata": {"k": ["v"]}}"#,
+        )
+        .unwrap();
+        let r = DownloadResult {
+            downloadable: dl.clone(),
+            download: Ok(()),
+            key: Some(String::from("SHA256E-s19--0123.txt")),
+            metadata_added: None,
+            urls_added: HashMap::new(),
+            failed_attempts: Vec::new(),
+            bytes: None,
+            timings: Timings {
+                downloaded: Some(Utc::now()),
+                ..Timings::default()
+            },
+            existing: None,
+        };
+        let (sender, mut receiver) = channel(1);
+        let (urls, _) = channel(1);
+        let queues = StageQueues {
+            metadata: vec![sender],
+            urls,
+        };
+        let (mut r, jobs) = gamdam
+            .dispatch(0, r, &queues, &Journal::disabled())
+            .await
+            .unwrap();
+        assert_eq!(jobs, 1);
+        let job = receiver.recv().await.unwrap();
+        assert_eq!(job.metadata["k"], ["v"]);
+        assert_eq!(job.metadata["source"], ["crawl"]);
+        assert!(job.metadata.contains_key("downloaded"));
+        assert_eq!(r.downloadable, dl);
+        r.metadata_added = Some(Err(AnnexError(vec![String::from("bad field")])));
+        let report = Report {
+            successful: Vec::new(),
+            failed: vec![r],
+            unprocessed: Vec::new(),
+            committed: 0,
+        };
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("failures.jsonl");
+        write_failures(&OutputArg::Path(path.clone()), &report)
+            .await
+            .unwrap();
+        let record =
+            serde_json::from_str::<Downloadable>(std::fs::read_to_string(path).unwrap().trim())
+                .unwrap();
+        assert_eq!(record, dl);
+    }
+}
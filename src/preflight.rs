@@ -0,0 +1,41 @@
+use crate::collisions::{committed_files, resolve_collisions, Collision};
+use crate::duplicates::dedupe;
+use crate::{quantify, Downloadable, Gamdam};
+use futures_util::{Stream, TryStreamExt};
+
+impl Gamdam {
+    /// Returns true if the configured duplicate-path and collision policies
+    /// require reading the entire input before downloading anything
+    pub(crate) fn needs_preflight(&self) -> bool {
+        self.collisions.is_enabled() || self.duplicates.needs_whole_input()
+    }
+
+    /// Read all of `items`, apply the duplicate-path policy to them, and
+    /// check their paths for collisions according to the configured
+    /// [`CollisionPolicies`][crate::CollisionPolicies].  Returns each
+    /// remaining item (renamed as configured) paired with its first collision
+    /// whose policy is "error", if any.
+    pub(crate) async fn preflight<S>(
+        &self,
+        items: S,
+    ) -> Result<Vec<(Downloadable, Option<Collision>)>, anyhow::Error>
+    where
+        S: Stream<Item = Result<Downloadable, anyhow::Error>> + Send,
+    {
+        let items = items.try_collect::<Vec<_>>().await?;
+        let items = dedupe(items, self.duplicates);
+        if !self.collisions.is_enabled() {
+            return Ok(items.into_iter().map(|dl| (dl, None)).collect());
+        }
+        let existing = if self.collisions.check_repo {
+            committed_files(&self.repo).await?
+        } else {
+            Vec::new()
+        };
+        log::info!(
+            "Checking {} for path collisions",
+            quantify(items.len(), "item")
+        );
+        Ok(resolve_collisions(items, &existing, &self.collisions))
+    }
+}
@@ -0,0 +1,257 @@
+use gamdam::{Event, FilePath, Stage};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// Interactive display of the downloads in progress, driven by the events
+/// emitted by a run
+#[derive(Debug)]
+pub(crate) struct ProgressDisplay {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    active: HashMap<FilePath, ProgressBar>,
+    /// Number of queued items at each path that haven't finished yet
+    queued: HashMap<FilePath, usize>,
+    /// Bytes downloaded by finished downloads
+    finished_bytes: u64,
+    /// Number of items that failed to download or verify
+    failed: usize,
+    start: Instant,
+}
+
+impl ProgressDisplay {
+    pub(crate) fn new(multi: MultiProgress) -> ProgressDisplay {
+        let overall = multi.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template(
+                    "{bar:30.cyan/blue} {pos}/{len} files  {msg}  ETA {eta}",
+                )
+                .expect("overall progress template should be valid"),
+            ),
+        );
+        overall.enable_steady_tick(Duration::from_secs(1));
+        ProgressDisplay {
+            multi,
+            overall,
+            active: HashMap::new(),
+            queued: HashMap::new(),
+            finished_bytes: 0,
+            failed: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// Update the display with events until the sender is dropped, then
+    /// clear it
+    pub(crate) async fn run(mut self, mut events: UnboundedReceiver<Event>) {
+        while let Some(event) = events.recv().await {
+            self.handle(event);
+        }
+        for (_, bar) in self.active.drain() {
+            bar.finish_and_clear();
+        }
+        self.overall.finish_and_clear();
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Queued { path, .. } => {
+                *self.queued.entry(path).or_default() += 1;
+                self.overall.inc_length(1);
+            }
+            Event::Started { path, .. } => {
+                let bar = self
+                    .multi
+                    .insert_before(&self.overall, ProgressBar::new_spinner());
+                bar.set_style(
+                    ProgressStyle::with_template("{spinner} {wide_msg} {bytes}")
+                        .expect("spinner template should be valid"),
+                );
+                bar.set_message(path.to_string());
+                if let Some(old) = self.active.insert(path, bar) {
+                    // A retry of a download that already had a line
+                    old.finish_and_clear();
+                }
+            }
+            Event::Progress { path, bytes, total } => {
+                if let Some(bar) = self.active.get(&path) {
+                    if let Some(total) = total.filter(|_| bar.length().is_none()) {
+                        bar.set_length(u64::try_from(total).unwrap_or(u64::MAX));
+                        bar.set_style(
+                            ProgressStyle::with_template(
+                                "{wide_msg} {bytes}/{total_bytes} {bar:20} {percent:>3}%",
+                            )
+                            .expect("download template should be valid"),
+                        );
+                    }
+                    bar.set_position(u64::try_from(bytes).unwrap_or(u64::MAX));
+                }
+                self.update_bytes();
+            }
+            Event::Retrying { path, attempt, .. } => {
+                if let Some(bar) = self.active.get(&path) {
+                    bar.set_position(0);
+                    bar.set_message(format!("{path} (retrying after attempt {attempt})"));
+                }
+            }
+            Event::Downloaded { path, .. } => self.finish(&path, true),
+            Event::Failed { path, stage, .. } => self.fail(&path, &stage),
+            _ => (),
+        }
+    }
+
+    fn fail(&mut self, path: &FilePath, stage: &Stage) {
+        match stage {
+            Stage::Download => {
+                self.failed += 1;
+                self.finish(path, false);
+            }
+            // Verification happens after the item's `Downloaded` event, which
+            // already advanced the bar.
+            Stage::Verify => {
+                self.failed += 1;
+                self.update_bytes();
+            }
+            _ => (),
+        }
+    }
+
+    /// Advance the overall bar for an item that has finished downloading,
+    /// successfully or not, whether or not it was ever started
+    fn finish(&mut self, path: &FilePath, completed: bool) {
+        if let Some(bar) = self.active.remove(path) {
+            // addurl doesn't always report progress for the final chunk.
+            self.finished_bytes += match bar.length() {
+                Some(len) if completed => len,
+                _ => bar.position(),
+            };
+            bar.finish_and_clear();
+            self.multi.remove(&bar);
+        }
+        match self.queued.get_mut(path) {
+            Some(n) if *n > 1 => *n -= 1,
+            Some(_) => {
+                self.queued.remove(path);
+            }
+            // Items that fail before being queued (refused by the
+            // existing-path policy or rejected as duplicates) aren't counted
+            // in the length yet.
+            None => self.overall.inc_length(1),
+        }
+        self.overall.inc(1);
+        self.update_bytes();
+    }
+
+    fn update_bytes(&self) {
+        let bytes =
+            self.finished_bytes + self.active.values().map(ProgressBar::position).sum::<u64>();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        // Precision loss only matters for byte counts beyond 2^53.
+        #[allow(
+            clippy::cast_precision_loss,
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss
+        )]
+        let rate = if elapsed > 0.0 {
+            (bytes as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let failed = if self.failed > 0 {
+            format!("  {} failed", self.failed)
+        } else {
+            String::new()
+        };
+        self.overall.set_message(format!(
+            "{}  {}/s{failed}",
+            HumanBytes(bytes),
+            HumanBytes(rate)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indicatif::ProgressDrawTarget;
+    use url::Url;
+
+    #[test]
+    fn test_progress_display() {
+        let mut display =
+            ProgressDisplay::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()));
+        let url = Url::parse("https://example.com/file").unwrap();
+        let foo = FilePath::try_from("foo.txt").unwrap();
+        let bar = FilePath::try_from("bar.txt").unwrap();
+        for path in [&foo, &bar] {
+            display.handle(Event::Queued {
+                path: path.clone(),
+                url: url.clone(),
+            });
+            display.handle(Event::Started {
+                path: path.clone(),
+                url: url.clone(),
+            });
+        }
+        display.handle(Event::Progress {
+            path: foo.clone(),
+            bytes: 100,
+            total: Some(200),
+        });
+        display.handle(Event::Progress {
+            path: bar.clone(),
+            bytes: 50,
+            total: None,
+        });
+        assert_eq!(display.overall.length(), Some(2));
+        assert_eq!(display.active[&foo].length(), Some(200));
+        assert_eq!(display.active[&bar].length(), None);
+        display.handle(Event::Downloaded {
+            path: foo.clone(),
+            key: None,
+        });
+        assert_eq!(display.overall.position(), 1);
+        assert_eq!(display.finished_bytes, 200);
+        assert!(!display.active.contains_key(&foo));
+        assert!(display.overall.message().starts_with("250 B"));
+    }
+
+    #[test]
+    fn test_progress_display_unstarted_failures() {
+        let mut display =
+            ProgressDisplay::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()));
+        let url = Url::parse("https://example.com/file").unwrap();
+        let foo = FilePath::try_from("foo.txt").unwrap();
+        let bar = FilePath::try_from("bar.txt").unwrap();
+        // An item that's queued but fails without being started, as when
+        // copying an already-downloaded key fails
+        display.handle(Event::Queued {
+            path: foo.clone(),
+            url: url.clone(),
+        });
+        display.fail(&foo, &Stage::Download);
+        assert_eq!(display.overall.position(), 1);
+        assert_eq!(display.overall.length(), Some(1));
+        // An item that fails without ever being queued, as when rejected as a
+        // duplicate
+        display.fail(&foo, &Stage::Download);
+        assert_eq!(display.overall.position(), 2);
+        assert_eq!(display.overall.length(), Some(2));
+        // An item that fails verification after downloading is only counted
+        // once
+        display.handle(Event::Queued {
+            path: bar.clone(),
+            url,
+        });
+        display.handle(Event::Downloaded {
+            path: bar.clone(),
+            key: None,
+        });
+        display.fail(&bar, &Stage::Verify);
+        assert_eq!(display.overall.position(), 3);
+        assert_eq!(display.overall.length(), Some(3));
+        assert_eq!(display.failed, 3);
+        assert!(display.overall.message().ends_with("3 failed"));
+    }
+}
@@ -0,0 +1,381 @@
+use crate::annex::AnnexError;
+use crate::{
+    DownloadResult, Downloadable, ExistingOutcome, MirrorItem, MirrorReport, MirrorResult,
+    MirrorTarget, Report,
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures_util::SinkExt;
+use patharg::OutputArg;
+use serde::{Serialize, Serializer};
+use serde_jsonlines::AsyncWriteJsonLines;
+use std::collections::BTreeMap;
+use url::Url;
+
+/// A single record in a machine-readable report of a run, as yielded by
+/// [`Report::records()`] and [`MirrorReport::records()`]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ReportRecord<'a, R = DownloadResult, T = Downloadable> {
+    /// An item that was processed by the pipeline
+    Processed(&'a R),
+    /// An item that was never processed because the run was stopped early
+    Unprocessed(UnprocessedItem<'a, T>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct UnprocessedItem<'a, T = Downloadable> {
+    status: &'static str,
+    success: bool,
+    #[serde(flatten)]
+    item: &'a T,
+}
+
+impl Report {
+    /// Returns one record per item in the report: first the successful
+    /// items, then the failed items, then the unprocessed items
+    pub fn records(&self) -> impl Iterator<Item = ReportRecord<'_>> {
+        self.successful
+            .iter()
+            .chain(self.failed.iter())
+            .map(ReportRecord::Processed)
+            .chain(self.unprocessed.iter().map(|item| {
+                ReportRecord::Unprocessed(UnprocessedItem {
+                    status: "unprocessed",
+                    success: false,
+                    item,
+                })
+            }))
+    }
+}
+
+impl MirrorReport {
+    /// Returns one record per item in the report: first the successful
+    /// items, then the failed items, then the unprocessed items
+    pub fn records(&self) -> impl Iterator<Item = ReportRecord<'_, MirrorResult, MirrorItem>> {
+        self.successful
+            .iter()
+            .chain(self.failed.iter())
+            .map(ReportRecord::Processed)
+            .chain(self.unprocessed.iter().map(|item| {
+                ReportRecord::Unprocessed(UnprocessedItem {
+                    status: "unprocessed",
+                    success: false,
+                    item,
+                })
+            }))
+    }
+}
+
+/// Write one JSON Lines record per item in `report` to `outfile`
+pub(crate) async fn write_report(
+    outfile: &OutputArg,
+    report: &Report,
+) -> Result<(), anyhow::Error> {
+    write_json_lines(outfile, report.records()).await
+}
+
+/// Write the input records of the failed and unprocessed items in `report`
+/// to `outfile` as JSON Lines
+pub(crate) async fn write_failures(
+    outfile: &OutputArg,
+    report: &Report,
+) -> Result<(), anyhow::Error> {
+    let failures = report
+        .failed
+        .iter()
+        .map(|r| &r.downloadable)
+        .chain(report.unprocessed.iter());
+    write_json_lines(outfile, failures).await
+}
+
+pub(crate) async fn write_json_lines<I>(outfile: &OutputArg, items: I) -> Result<(), anyhow::Error>
+where
+    I: IntoIterator + Send,
+    I::IntoIter: Send,
+    I::Item: Serialize + Send,
+{
+    let mut sink = outfile
+        .async_create()
+        .await
+        .with_context(|| format!("Error opening {outfile} for writing"))?
+        .into_json_lines_sink();
+    for item in items {
+        sink.send(item).await.context("Error writing to file")?;
+    }
+    Ok(())
+}
+
+impl Serialize for DownloadResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DownloadRecord::from(self).serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct DownloadRecord<'a> {
+    status: &'static str,
+    success: bool,
+    #[serde(flatten)]
+    item: &'a Downloadable,
+    download: Outcome<'a>,
+    key: &'a Option<String>,
+    existing: &'a Option<ExistingOutcome>,
+    metadata_added: Option<Outcome<'a>>,
+    urls_added: BTreeMap<&'a Url, Outcome<'a>>,
+    failed_attempts: &'a [AnnexError],
+    bytes: Option<u64>,
+    started: Option<DateTime<Utc>>,
+    downloaded: Option<DateTime<Utc>>,
+    finished: Option<DateTime<Utc>>,
+    download_seconds: Option<f64>,
+    total_seconds: Option<f64>,
+}
+
+impl<'a> From<&'a DownloadResult> for DownloadRecord<'a> {
+    fn from(r: &'a DownloadResult) -> DownloadRecord<'a> {
+        let success = r.success();
+        let t = &r.timings;
+        DownloadRecord {
+            status: if success { "success" } else { "failed" },
+            success,
+            item: &r.downloadable,
+            download: Outcome::from(&r.download),
+            key: &r.key,
+            existing: &r.existing,
+            metadata_added: r.metadata_added.as_ref().map(Outcome::from),
+            urls_added: r
+                .urls_added
+                .iter()
+                .map(|(u, res)| (u, Outcome::from(res)))
+                .collect(),
+            failed_attempts: &r.failed_attempts,
+            bytes: r.bytes,
+            started: t.started,
+            downloaded: t.downloaded,
+            finished: t.finished,
+            download_seconds: seconds_between(t.started, t.downloaded),
+            total_seconds: seconds_between(t.started, t.finished),
+        }
+    }
+}
+
+impl Serialize for MirrorResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        MirrorRecord::from(self).serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct MirrorRecord<'a> {
+    status: &'static str,
+    success: bool,
+    #[serde(flatten)]
+    item: &'a MirrorItem,
+    /// For items given by path, the outcome of looking up the key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lookup: Option<Outcome<'a>>,
+    /// For items given by path, the key that was found
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+    urls_added: BTreeMap<&'a Url, Outcome<'a>>,
+}
+
+impl<'a> From<&'a MirrorResult> for MirrorRecord<'a> {
+    fn from(r: &'a MirrorResult) -> MirrorRecord<'a> {
+        let success = r.success();
+        let by_path = matches!(r.item.target, MirrorTarget::Path(_));
+        MirrorRecord {
+            status: if success { "success" } else { "failed" },
+            success,
+            item: &r.item,
+            lookup: by_path.then(|| Outcome::from(&r.key)),
+            key: if by_path { r.key.as_deref().ok() } else { None },
+            urls_added: r
+                .urls_added
+                .iter()
+                .map(|(u, res)| (u, Outcome::from(res)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Outcome<'a> {
+    success: bool,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    error_messages: &'a [String],
+}
+
+impl<'a, T> From<&'a Result<T, AnnexError>> for Outcome<'a> {
+    fn from(r: &'a Result<T, AnnexError>) -> Outcome<'a> {
+        match r {
+            Ok(_) => Outcome {
+                success: true,
+                error_messages: &[],
+            },
+            Err(e) => Outcome {
+                success: false,
+                error_messages: e.messages(),
+            },
+        }
+    }
+}
+
+fn seconds_between(start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> Option<f64> {
+    Some((end? - start?).to_std().ok()?.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ExpectedContent, FilePath, Timings};
+    use chrono::TimeZone;
+    use std::collections::HashMap;
+
+    fn sample() -> Downloadable {
+        Downloadable {
+            path: FilePath::try_from("foo/bar.txt").unwrap(),
+            url: Url::parse("https://example.com/bar.txt").unwrap(),
+            metadata: HashMap::new(),
+            metadata_ops: HashMap::new(),
+            extra_urls: vec![Url::parse("https://mirror.example.com/bar.txt").unwrap()],
+            on_existing: None,
+            original_path: None,
+            expected: ExpectedContent::default(),
+        }
+    }
+
+    #[test]
+    fn test_serialize_download_result() {
+        let dl = sample();
+        let r = DownloadResult {
+            urls_added: HashMap::from([(
+                dl.extra_urls[0].clone(),
+                Err(AnnexError(vec![String::from("bad URL")])),
+            )]),
+            downloadable: dl,
+            download: Ok(()),
+            key: Some(String::from("SHA256E-s19--0123.txt")),
+            metadata_added: None,
+            failed_attempts: vec![AnnexError(vec![String::from("timed out")])],
+            bytes: Some(19),
+            timings: Timings {
+                started: Some(Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap()),
+                downloaded: Some(Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 3).unwrap()),
+                finished: Some(Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 4).unwrap()),
+            },
+            existing: Some(ExistingOutcome::Renamed {
+                from: FilePath::try_from("foo/bar.txt").unwrap(),
+            }),
+        };
+        assert_eq!(
+            serde_json::to_value(&r).unwrap(),
+            serde_json::json!({
+                "status": "failed",
+                "success": false,
+                "path": "foo/bar.txt",
+                "url": "https://example.com/bar.txt",
+                "metadata": {},
+                "extra_urls": ["https://mirror.example.com/bar.txt"],
+                "download": {"success": true},
+                "key": "SHA256E-s19--0123.txt",
+                "existing": {"action": "renamed", "from": "foo/bar.txt"},
+                "metadata_added": null,
+                "urls_added": {
+                    "https://mirror.example.com/bar.txt": {
+                        "success": false,
+                        "error_messages": ["bad URL"],
+                    },
+                },
+                "failed_attempts": [["timed out"]],
+                "bytes": 19,
+                "started": "2023-04-01T12:00:00Z",
+                "downloaded": "2023-04-01T12:00:03Z",
+                "finished": "2023-04-01T12:00:04Z",
+                "download_seconds": 3.0,
+                "total_seconds": 4.0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_unprocessed_record() {
+        let report = Report {
+            successful: Vec::new(),
+            failed: Vec::new(),
+            unprocessed: vec![sample()],
+            committed: 0,
+        };
+        let records = report
+            .records()
+            .map(|r| serde_json::to_value(r).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            vec![serde_json::json!({
+                "status": "unprocessed",
+                "success": false,
+                "path": "foo/bar.txt",
+                "url": "https://example.com/bar.txt",
+                "metadata": {},
+                "extra_urls": ["https://mirror.example.com/bar.txt"],
+            })]
+        );
+    }
+
+    #[test]
+    fn test_serialize_mirror_result() {
+        let url = Url::parse("https://mirror.example.com/bar.txt").unwrap();
+        let r = MirrorResult {
+            item: MirrorItem {
+                target: MirrorTarget::Path(FilePath::try_from("foo/bar.txt").unwrap()),
+                extra_urls: vec![url.clone()],
+            },
+            key: Ok(String::from("SHA256E-s19--0123.txt")),
+            urls_added: HashMap::from([(url, Ok(()))]),
+        };
+        assert_eq!(
+            serde_json::to_value(&r).unwrap(),
+            serde_json::json!({
+                "status": "success",
+                "success": true,
+                "path": "foo/bar.txt",
+                "extra_urls": ["https://mirror.example.com/bar.txt"],
+                "lookup": {"success": true},
+                "key": "SHA256E-s19--0123.txt",
+                "urls_added": {
+                    "https://mirror.example.com/bar.txt": {"success": true},
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_serialize_mirror_result_by_key() {
+        let url = Url::parse("https://mirror.example.com/bar.txt").unwrap();
+        let r = MirrorResult {
+            item: MirrorItem {
+                target: MirrorTarget::Key(String::from("SHA256E-s19--0123.txt")),
+                extra_urls: vec![url.clone()],
+            },
+            key: Ok(String::from("SHA256E-s19--0123.txt")),
+            urls_added: HashMap::from([(url, Err(AnnexError(vec![String::from("bad URL")])))]),
+        };
+        assert_eq!(
+            serde_json::to_value(&r).unwrap(),
+            serde_json::json!({
+                "status": "failed",
+                "success": false,
+                "key": "SHA256E-s19--0123.txt",
+                "extra_urls": ["https://mirror.example.com/bar.txt"],
+                "urls_added": {
+                    "https://mirror.example.com/bar.txt": {
+                        "success": false,
+                        "error_messages": ["bad URL"],
+                    },
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{AnnexError, Downloadable, FilePath};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use url::Url;
+
+/// State shared between the tasks of a run for downloading each distinct URL
+/// only once.  The first item with a given URL is downloaded as usual, and
+/// every later item with the same URL is instead pointed at the key of the
+/// first once it has finished downloading.
+///
+/// Clones refer to the same state.  The receiver returned by
+/// [`SharedUrls::new()`] yields [`CopyJob`]s until all clones are dropped.
+#[derive(Clone, Debug)]
+pub(crate) struct SharedUrls {
+    state: Arc<Mutex<HashMap<Url, UrlState>>>,
+    copier: UnboundedSender<CopyJob>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum UrlState {
+    /// The URL is being downloaded; these are the paths of the items waiting
+    /// for it
+    Downloading(Vec<FilePath>),
+    /// The URL was downloaded to a file with the given key, or downloading it
+    /// failed
+    Finished(Result<String, AnnexError>),
+}
+
+/// A request to point `path` at the key downloaded for another item with the
+/// same URL
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct CopyJob {
+    pub(crate) path: FilePath,
+    /// The key to use, or the error that occurred downloading the URL
+    pub(crate) key: Result<String, AnnexError>,
+}
+
+impl SharedUrls {
+    pub(crate) fn new() -> (SharedUrls, UnboundedReceiver<CopyJob>) {
+        let (copier, receiver) = unbounded_channel();
+        let urls = SharedUrls {
+            state: Arc::new(Mutex::new(HashMap::new())),
+            copier,
+        };
+        (urls, receiver)
+    }
+
+    /// Called for each item that is about to be downloaded.  Returns true if
+    /// the item's URL should be downloaded; otherwise, the item will be
+    /// copied from another item with the same URL.
+    pub(crate) fn claim(&self, dl: &Downloadable) -> bool {
+        let mut state = self.state.lock().expect("Mutex should not be poisoned");
+        match state.entry(dl.url.clone()) {
+            Entry::Vacant(e) => {
+                e.insert(UrlState::Downloading(Vec::new()));
+                true
+            }
+            Entry::Occupied(mut e) => {
+                log::info!(
+                    "{} is already downloaded or being downloaded for another path; will point {} at its key",
+                    dl.url,
+                    dl.path
+                );
+                match e.get_mut() {
+                    UrlState::Downloading(waiting) => waiting.push(dl.path.clone()),
+                    UrlState::Finished(key) => self.copy(dl.path.clone(), key.clone()),
+                }
+                false
+            }
+        }
+    }
+
+    /// Record that `url` was downloaded to a file with key `key` by a
+    /// previous run
+    pub(crate) fn resumed(&self, url: &Url, key: &str) {
+        let mut state = self.state.lock().expect("Mutex should not be poisoned");
+        state
+            .entry(url.clone())
+            .or_insert_with(|| UrlState::Finished(Ok(key.to_owned())));
+    }
+
+    /// Record that downloading `url` finished with the given result, and
+    /// release the items waiting for it
+    pub(crate) fn finish(&self, url: &Url, result: Result<Option<String>, AnnexError>) {
+        let key = result.and_then(|key| {
+            key.ok_or_else(|| {
+                AnnexError(vec![format!(
+                    "git-annex addurl did not report a key for {url}"
+                )])
+            })
+        });
+        let mut state = self.state.lock().expect("Mutex should not be poisoned");
+        let waiting = match state.insert(url.clone(), UrlState::Finished(key.clone())) {
+            Some(UrlState::Downloading(waiting)) => waiting,
+            _ => Vec::new(),
+        };
+        for path in waiting {
+            self.copy(path, key.clone());
+        }
+    }
+
+    /// Forget about a download of `url` that will not be performed due to
+    /// the run being stopped, returning the paths of the items that were
+    /// waiting for it
+    pub(crate) fn abandon(&self, url: &Url) -> Vec<FilePath> {
+        let mut state = self.state.lock().expect("Mutex should not be poisoned");
+        match state.remove(url) {
+            Some(UrlState::Downloading(waiting)) => waiting,
+            Some(finished) => {
+                state.insert(url.clone(), finished);
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn copy(&self, path: FilePath, key: Result<String, AnnexError>) {
+        // The receiver is only dropped once all senders are gone or the run
+        // has failed, so there's nothing to do if sending fails.
+        let _ = self.copier.send(CopyJob { path, key });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExpectedContent;
+
+    fn dl(path: &str, url: &str) -> Downloadable {
+        Downloadable {
+            path: FilePath::try_from(path).unwrap(),
+            url: Url::parse(url).unwrap(),
+            metadata: HashMap::new(),
+            metadata_ops: HashMap::new(),
+            extra_urls: Vec::new(),
+            on_existing: None,
+            original_path: None,
+            expected: ExpectedContent::default(),
+        }
+    }
+
+    fn job(path: &str, key: Result<&str, &str>) -> CopyJob {
+        CopyJob {
+            path: FilePath::try_from(path).unwrap(),
+            key: key
+                .map(String::from)
+                .map_err(|e| AnnexError(vec![e.into()])),
+        }
+    }
+
+    #[test]
+    fn test_claim_and_finish() {
+        let (urls, mut receiver) = SharedUrls::new();
+        assert!(urls.claim(&dl("a.pdf", "https://example.com/a")));
+        assert!(urls.claim(&dl("b.pdf", "https://example.com/b")));
+        assert!(!urls.claim(&dl("c/a.pdf", "https://example.com/a")));
+        assert!(!urls.claim(&dl("d/a.pdf", "https://example.com/a")));
+        assert!(receiver.try_recv().is_err());
+        urls.finish(
+            &Url::parse("https://example.com/a").unwrap(),
+            Ok(Some("KEY-a".into())),
+        );
+        assert_eq!(receiver.try_recv().unwrap(), job("c/a.pdf", Ok("KEY-a")));
+        assert_eq!(receiver.try_recv().unwrap(), job("d/a.pdf", Ok("KEY-a")));
+        assert!(!urls.claim(&dl("e/a.pdf", "https://example.com/a")));
+        assert_eq!(receiver.try_recv().unwrap(), job("e/a.pdf", Ok("KEY-a")));
+        assert!(!urls.claim(&dl("c/b.pdf", "https://example.com/b")));
+        urls.finish(
+            &Url::parse("https://example.com/b").unwrap(),
+            Err(AnnexError(vec!["Not Found".into()])),
+        );
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            job("c/b.pdf", Err("Not Found"))
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_finish_without_key() {
+        let (urls, mut receiver) = SharedUrls::new();
+        assert!(urls.claim(&dl("a.pdf", "https://example.com/a")));
+        assert!(!urls.claim(&dl("b.pdf", "https://example.com/a")));
+        urls.finish(&Url::parse("https://example.com/a").unwrap(), Ok(None));
+        let job = receiver.try_recv().unwrap();
+        assert!(job.key.is_err());
+    }
+
+    #[test]
+    fn test_resumed() {
+        let (urls, mut receiver) = SharedUrls::new();
+        urls.resumed(&Url::parse("https://example.com/a").unwrap(), "KEY-a");
+        assert!(!urls.claim(&dl("b.pdf", "https://example.com/a")));
+        assert_eq!(receiver.try_recv().unwrap(), job("b.pdf", Ok("KEY-a")));
+    }
+
+    #[test]
+    fn test_abandon() {
+        let (urls, mut receiver) = SharedUrls::new();
+        let url = Url::parse("https://example.com/a").unwrap();
+        assert!(urls.claim(&dl("a.pdf", "https://example.com/a")));
+        assert!(!urls.claim(&dl("b.pdf", "https://example.com/a")));
+        assert_eq!(urls.abandon(&url), [FilePath::try_from("b.pdf").unwrap()]);
+        assert!(urls.claim(&dl("c.pdf", "https://example.com/a")));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,513 @@
+use crate::{
+    check_field_name, BadMetadataPolicy, Downloadable, ExistingPolicy, ExpectedContent,
+    ItemPathError, MetadataError, ParseExistingPolicyError, PathOptions,
+};
+use futures_util::Stream;
+use std::collections::HashMap;
+use std::io::Read;
+use std::num::ParseIntError;
+use thiserror::Error;
+use tokio::sync::mpsc::channel;
+use url::Url;
+
+/// The names of the columns of tabular (CSV or TSV) input that hold the
+/// fields of each [`Downloadable`].  Columns named `on_existing`, `size`,
+/// `md5`, `sha1`, `sha256`, and `sha512` are read as the fields of the same
+/// names, as in JSON input; all other columns are treated as metadata fields
+/// named after the column.  Metadata operations cannot be given in tabular
+/// input.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ColumnMapping {
+    /// Column containing the URL to download (required)
+    pub url: String,
+    /// Column containing the path to download to (required unless a
+    /// [`PathTemplate`][crate::PathTemplate] is given)
+    pub path: String,
+    /// Column containing extra URLs to register (optional)
+    pub extra_urls: String,
+    /// Character separating the URLs in the extra URLs column.  If `None`,
+    /// the URLs are separated by whitespace.
+    pub extra_urls_separator: Option<char>,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> ColumnMapping {
+        ColumnMapping {
+            url: String::from("url"),
+            path: String::from("path"),
+            extra_urls: String::from("extra_urls"),
+            extra_urls_separator: None,
+        }
+    }
+}
+
+impl ColumnMapping {
+    /// Determine the positions of the mapped columns in a table with the
+    /// given header row.  With [`BadMetadataPolicy::Reject`], the names of
+    /// the metadata columns are checked here so that a bad name fails the
+    /// whole input instead of every row.
+    fn locate(
+        &self,
+        headers: &csv::StringRecord,
+        paths: PathOptions,
+        bad_metadata: BadMetadataPolicy,
+    ) -> Result<Columns, TableError> {
+        let find = |name: &str| headers.iter().position(|h| h == name);
+        let url = find(&self.url).ok_or_else(|| TableError::MissingColumn(self.url.clone()))?;
+        let path = find(&self.path);
+        if path.is_none() && paths.template.is_none() {
+            return Err(TableError::MissingColumn(self.path.clone()));
+        }
+        let extra_urls = find(&self.extra_urls);
+        let mapped = [Some(url), path, extra_urls];
+        let fixed = |name: &str| find(name).filter(|i| !mapped.contains(&Some(*i)));
+        let on_existing = fixed("on_existing");
+        let size = fixed("size");
+        let md5 = fixed("md5");
+        let sha1 = fixed("sha1");
+        let sha256 = fixed("sha256");
+        let sha512 = fixed("sha512");
+        let fields = [on_existing, size, md5, sha1, sha256, sha512];
+        let metadata = headers
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !mapped.contains(&Some(i)) && !fields.contains(&Some(i)))
+            .map(|(i, h)| (i, h.to_owned()))
+            .collect::<Vec<_>>();
+        if bad_metadata == BadMetadataPolicy::Reject {
+            for (_, name) in &metadata {
+                check_field_name(name).map_err(TableError::BadColumn)?;
+            }
+        }
+        Ok(Columns {
+            url,
+            path,
+            extra_urls,
+            on_existing,
+            size,
+            md5,
+            sha1,
+            sha256,
+            sha512,
+            metadata,
+            separator: self.extra_urls_separator,
+            paths,
+            bad_metadata,
+        })
+    }
+}
+
+/// The positions of the columns in a table
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Columns {
+    url: usize,
+    path: Option<usize>,
+    extra_urls: Option<usize>,
+    on_existing: Option<usize>,
+    size: Option<usize>,
+    md5: Option<usize>,
+    sha1: Option<usize>,
+    sha256: Option<usize>,
+    sha512: Option<usize>,
+    metadata: Vec<(usize, String)>,
+    separator: Option<char>,
+    paths: PathOptions,
+    bad_metadata: BadMetadataPolicy,
+}
+
+impl Columns {
+    fn parse_row(&self, record: &csv::StringRecord) -> Result<Downloadable, RowError> {
+        let field = |i: usize| record.get(i).unwrap_or_default();
+        let url = parse_url(field(self.url))?;
+        let extra_urls = match self.extra_urls.map(field) {
+            Some(s) => match self.separator {
+                Some(sep) => s
+                    .split(sep)
+                    .map(str::trim)
+                    .filter(|u| !u.is_empty())
+                    .map(parse_url)
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => s
+                    .split_whitespace()
+                    .map(parse_url)
+                    .collect::<Result<Vec<_>, _>>()?,
+            },
+            None => Vec::new(),
+        };
+        let metadata = self
+            .metadata
+            .iter()
+            .filter_map(|(i, name)| {
+                let value = field(*i);
+                (!value.is_empty()).then(|| (name.clone(), vec![value.to_owned()]))
+            })
+            .collect::<HashMap<_, _>>();
+        let optional = |i: Option<usize>| i.map(field).filter(|s| !s.is_empty());
+        let on_existing = optional(self.on_existing)
+            .map(str::parse::<ExistingPolicy>)
+            .transpose()?;
+        let size = optional(self.size)
+            .map(|s| {
+                s.parse::<u64>().map_err(|source| RowError::Size {
+                    value: s.to_owned(),
+                    source,
+                })
+            })
+            .transpose()?;
+        let expected = ExpectedContent {
+            size,
+            md5: optional(self.md5).map(str::to_owned),
+            sha1: optional(self.sha1).map(str::to_owned),
+            sha256: optional(self.sha256).map(str::to_owned),
+            sha512: optional(self.sha512).map(str::to_owned),
+        };
+        let path = self.path.map(field).filter(|p| !p.is_empty());
+        let (path, original_path) = self.paths.resolve(path, &url, &metadata)?;
+        let mut dl = Downloadable {
+            path,
+            url,
+            metadata,
+            metadata_ops: HashMap::new(),
+            extra_urls,
+            on_existing,
+            original_path,
+            expected,
+        };
+        dl.check_metadata(self.bad_metadata)?;
+        Ok(dl)
+    }
+}
+
+fn parse_url(s: &str) -> Result<Url, RowError> {
+    if s.is_empty() {
+        return Err(RowError::EmptyUrl);
+    }
+    Url::parse(s).map_err(|source| RowError::Url {
+        value: s.to_owned(),
+        source,
+    })
+}
+
+/// Error returned while reading tabular input
+#[derive(Debug, Error)]
+pub enum TableError {
+    /// A row of the input could not be converted to a [`Downloadable`].  The
+    /// row can be skipped and reading continued.
+    #[error("line {line}: {source}")]
+    Row { line: u64, source: RowError },
+    /// The header row lacks a required column
+    #[error("input has no {0:?} column")]
+    MissingColumn(String),
+    /// The header row contains a column whose name is not a valid metadata
+    /// field name, and the bad-metadata policy is to reject such fields
+    #[error("invalid metadata column: {0}")]
+    BadColumn(#[source] MetadataError),
+    /// The input could not be read
+    #[error("error reading input: {0}")]
+    Read(#[source] csv::Error),
+}
+
+/// Error for a single row of tabular input that could not be converted to a
+/// [`Downloadable`]
+#[derive(Debug, Error)]
+pub enum RowError {
+    #[error("URL is empty")]
+    EmptyUrl,
+    #[error("invalid URL {value:?}: {source}")]
+    Url {
+        value: String,
+        source: url::ParseError,
+    },
+    #[error("{0}")]
+    Path(#[from] ItemPathError),
+    #[error("{0}")]
+    Metadata(#[from] MetadataError),
+    #[error("{0}")]
+    OnExisting(#[from] ParseExistingPolicyError),
+    #[error("invalid size {value:?}: {source}")]
+    Size {
+        value: String,
+        source: ParseIntError,
+    },
+    #[error("{0}")]
+    Malformed(csv::Error),
+}
+
+/// Read [`Downloadable`]s from CSV-like input whose first row is a header,
+/// with fields separated by `delimiter` (e.g., `b','` for CSV or `b'\t'`
+/// for TSV).  Rows are read on a separate thread and yielded as they are
+/// parsed, so input can be streamed.  The thread is detached rather than
+/// being one of the runtime's blocking threads, as a read that never returns
+/// (e.g., from a terminal on stdin) would otherwise prevent the runtime from
+/// shutting down; the thread exits on its next read once the stream is
+/// dropped.
+///
+/// If `paths` has a template, the path column may be absent, and rows with
+/// an empty path have their paths filled in from the template.  Each row's
+/// metadata is checked with [`Downloadable::check_metadata()`] according to
+/// `bad_metadata`.
+///
+/// Rows that cannot be converted are yielded as [`TableError::Row`] errors,
+/// after which reading continues; any other error ends the stream.
+pub fn read_table<R>(
+    reader: R,
+    delimiter: u8,
+    mapping: ColumnMapping,
+    paths: PathOptions,
+    bad_metadata: BadMetadataPolicy,
+) -> impl Stream<Item = Result<Downloadable, TableError>> + Send
+where
+    R: Read + Send + 'static,
+{
+    let (sender, mut receiver) = channel(64);
+    std::thread::spawn(move || {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .from_reader(reader);
+        let columns = match reader.headers() {
+            Ok(headers) => match mapping.locate(headers, paths, bad_metadata) {
+                Ok(columns) => columns,
+                Err(e) => {
+                    let _ = sender.blocking_send(Err(e));
+                    return;
+                }
+            },
+            Err(e) => {
+                let _ = sender.blocking_send(Err(TableError::Read(e)));
+                return;
+            }
+        };
+        for r in reader.records() {
+            let (r, fatal) = match r {
+                Ok(record) => {
+                    let line = record.position().map_or(0, csv::Position::line);
+                    let r = columns
+                        .parse_row(&record)
+                        .map_err(|source| TableError::Row { line, source });
+                    (r, false)
+                }
+                Err(e) if e.is_io_error() => (Err(TableError::Read(e)), true),
+                Err(e) => {
+                    let line = e.position().map_or(0, csv::Position::line);
+                    let source = RowError::Malformed(e);
+                    (Err(TableError::Row { line, source }), false)
+                }
+            };
+            if sender.blocking_send(r).is_err() || fatal {
+                // The receiver was dropped, or reading can't continue.
+                return;
+            }
+        }
+    });
+    futures_util::stream::poll_fn(move |cx| receiver.poll_recv(cx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FilePath, RenderPathError};
+    use futures_util::StreamExt;
+
+    async fn collect(
+        input: &'static str,
+        delimiter: u8,
+        mapping: ColumnMapping,
+    ) -> Vec<Result<Downloadable, TableError>> {
+        read_table(
+            input.as_bytes(),
+            delimiter,
+            mapping,
+            PathOptions::default(),
+            BadMetadataPolicy::Reject,
+        )
+        .collect::<Vec<_>>()
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_read_csv() {
+        let input = concat!(
+            "path,url,title,extra_urls\n",
+            "foo.txt,https://example.com/foo,\"Foo, the Sequel\",https://mirror.example/foo https://backup.example/foo\n",
+            "bar.txt,https://example.com/bar,,\n",
+        );
+        let items = collect(input, b',', ColumnMapping::default()).await;
+        assert_eq!(items.len(), 2);
+        let foo = items[0].as_ref().unwrap();
+        assert_eq!(foo.path, FilePath::try_from("foo.txt").unwrap());
+        assert_eq!(foo.url.as_str(), "https://example.com/foo");
+        assert_eq!(
+            foo.metadata,
+            HashMap::from([(String::from("title"), vec![String::from("Foo, the Sequel")])])
+        );
+        assert_eq!(
+            foo.extra_urls.iter().map(Url::as_str).collect::<Vec<_>>(),
+            ["https://mirror.example/foo", "https://backup.example/foo"]
+        );
+        let bar = items[1].as_ref().unwrap();
+        assert!(bar.metadata.is_empty());
+        assert!(bar.extra_urls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_tsv_mapped() {
+        let input = "Link\tFile\tMirrors\tauthor\nhttps://example.com/a\ta.pdf\thttps://m.example/a|https://n.example/a\tMe\n";
+        let mapping = ColumnMapping {
+            url: "Link".into(),
+            path: "File".into(),
+            extra_urls: "Mirrors".into(),
+            extra_urls_separator: Some('|'),
+        };
+        let items = collect(input, b'\t', mapping).await;
+        let a = items[0].as_ref().unwrap();
+        assert_eq!(a.path, FilePath::try_from("a.pdf").unwrap());
+        assert_eq!(a.extra_urls.len(), 2);
+        assert_eq!(
+            a.metadata,
+            HashMap::from([(String::from("author"), vec![String::from("Me")])])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_csv_item_fields() {
+        let input = concat!(
+            "url,path,size,sha256,on_existing,title\n",
+            "https://example.com/a,a.txt,19,0123abcd,skip,A\n",
+            "https://example.com/b,b.txt,,,,\n",
+            "https://example.com/c,c.txt,big,,,\n",
+            "https://example.com/d,d.txt,,,clobber,\n",
+        );
+        let items = collect(input, b',', ColumnMapping::default()).await;
+        assert_eq!(items.len(), 4);
+        let a = items[0].as_ref().unwrap();
+        assert_eq!(
+            a.expected,
+            ExpectedContent {
+                size: Some(19),
+                sha256: Some("0123abcd".into()),
+                ..ExpectedContent::default()
+            }
+        );
+        assert_eq!(a.on_existing, Some(ExistingPolicy::Skip));
+        assert_eq!(
+            a.metadata,
+            HashMap::from([(String::from("title"), vec![String::from("A")])])
+        );
+        let b = items[1].as_ref().unwrap();
+        assert!(b.expected.is_empty());
+        assert_eq!(b.on_existing, None);
+        assert!(matches!(
+            items[2],
+            Err(TableError::Row {
+                line: 4,
+                source: RowError::Size { .. }
+            })
+        ));
+        assert!(matches!(
+            items[3],
+            Err(TableError::Row {
+                line: 5,
+                source: RowError::OnExisting(_)
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_csv_bad_rows() {
+        let input = concat!(
+            "url,path\n",
+            "https://example.com/1,one.txt\n",
+            "not a url,two.txt\n",
+            "https://example.com/3,../three.txt\n",
+            "https://example.com/4\n",
+            "https://example.com/5,\n",
+            "https://example.com/6,six.txt\n",
+        );
+        let items = collect(input, b',', ColumnMapping::default()).await;
+        let lines = items
+            .iter()
+            .map(|r| match r {
+                Ok(_) => None,
+                Err(TableError::Row { line, .. }) => Some(*line),
+                Err(e) => panic!("Unexpected error: {e}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [None, Some(3), Some(4), Some(5), Some(6), None]);
+    }
+
+    #[tokio::test]
+    async fn test_read_csv_missing_column() {
+        let items = collect(
+            "url,file\nhttps://example.com,foo\n",
+            b',',
+            ColumnMapping::default(),
+        )
+        .await;
+        assert_eq!(items.len(), 1);
+        assert!(matches!(items[0], Err(TableError::MissingColumn(ref c)) if c == "path"));
+    }
+
+    #[tokio::test]
+    async fn test_read_csv_path_template() {
+        let input = concat!(
+            "url,author\n",
+            "https://example.com/papers/one.pdf,Alice\n",
+            "https://example.com/papers/two.pdf,\n",
+        );
+        let paths = PathOptions {
+            template: Some("{metadata.author}/{url.filename}".parse().unwrap()),
+            sanitize: false,
+        };
+        let items = read_table(
+            input.as_bytes(),
+            b',',
+            ColumnMapping::default(),
+            paths,
+            BadMetadataPolicy::Reject,
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0].as_ref().unwrap().path,
+            FilePath::try_from("Alice/one.pdf").unwrap()
+        );
+        assert!(matches!(
+            items[1],
+            Err(TableError::Row {
+                line: 3,
+                source: RowError::Path(ItemPathError::Template(RenderPathError::NoMetadata(_)))
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_csv_bad_metadata_column() {
+        let input = concat!(
+            "path,url,Author Name\n",
+            "foo.txt,https://example.com/foo,Alice\n",
+            "bar.txt,https://example.com/bar,Bob\n",
+        );
+        let items = collect(input, b',', ColumnMapping::default()).await;
+        assert_eq!(items.len(), 1);
+        assert!(matches!(
+            items[0],
+            Err(TableError::BadColumn(MetadataError::BadChar { ref field, c: ' ' }))
+                if field == "Author Name"
+        ));
+        let items = read_table(
+            input.as_bytes(),
+            b',',
+            ColumnMapping::default(),
+            PathOptions::default(),
+            BadMetadataPolicy::Sanitize,
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0].as_ref().unwrap().metadata,
+            HashMap::from([(String::from("Author_Name"), vec![String::from("Alice")])])
+        );
+    }
+}
@@ -0,0 +1,515 @@
+use crate::{
+    BadMetadataPolicy, Downloadable, ExistingPolicy, ExpectedContent, FilePath, FilePathError,
+    MetadataError, MetadataOp,
+};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+use url::Url;
+
+/// A template for deriving an item's path from its URL and metadata, e.g.,
+/// `{metadata.author}/{metadata.year}/{url.filename}`.
+///
+/// The following placeholders are supported:
+///
+/// - `{url.host}` — the URL's host
+/// - `{url.path}` — the URL's path, without the leading slash
+/// - `{url.segment.N}` — the `N`-th segment of the URL's path, counting
+///   from 0; negative `N` count from the end, so `-1` is the last segment
+/// - `{url.filename}` — the last segment of the URL's path
+/// - `{url.stem}` — `{url.filename}` without its extension
+/// - `{url.ext}` — the extension of `{url.filename}`, without the leading
+///   period
+/// - `{metadata.FIELD}` — the first value of the given metadata field
+///
+/// Path components of the URL are percent-decoded.  Literal braces are
+/// written `{{` and `}}`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Field {
+    Host,
+    Path,
+    Segment(isize),
+    Filename,
+    Stem,
+    Extension,
+    Metadata(String),
+}
+
+impl Field {
+    fn parse(s: &str) -> Result<Field, ParsePathTemplateError> {
+        if let Some(name) = s.strip_prefix("metadata.") {
+            if name.is_empty() {
+                return Err(ParsePathTemplateError::Placeholder(s.into()));
+            }
+            return Ok(Field::Metadata(name.into()));
+        }
+        if let Some(index) = s.strip_prefix("url.segment.") {
+            return index
+                .parse::<isize>()
+                .map(Field::Segment)
+                .map_err(|_| ParsePathTemplateError::Placeholder(s.into()));
+        }
+        match s {
+            "url.host" => Ok(Field::Host),
+            "url.path" => Ok(Field::Path),
+            "url.filename" => Ok(Field::Filename),
+            "url.stem" => Ok(Field::Stem),
+            "url.ext" => Ok(Field::Extension),
+            _ => Err(ParsePathTemplateError::Placeholder(s.into())),
+        }
+    }
+
+    fn render(
+        &self,
+        url: &Url,
+        metadata: &HashMap<String, Vec<String>>,
+    ) -> Result<String, RenderPathError> {
+        let segments = || -> Result<Vec<&str>, RenderPathError> {
+            url.path_segments()
+                .map(Iterator::collect)
+                .ok_or(RenderPathError::NoPath)
+        };
+        let filename = || -> Result<String, RenderPathError> {
+            segments()?
+                .last()
+                .filter(|s| !s.is_empty())
+                .map(|s| decode(s))
+                .ok_or(RenderPathError::NoFilename)
+        };
+        match self {
+            Field::Host => url
+                .host_str()
+                .map(String::from)
+                .ok_or(RenderPathError::NoHost),
+            Field::Path => Ok(decode(url.path().trim_start_matches('/'))),
+            Field::Segment(i) => {
+                let segments = segments()?;
+                let index = if *i < 0 {
+                    segments.len().checked_sub(i.unsigned_abs())
+                } else {
+                    Some(i.unsigned_abs())
+                };
+                index
+                    .and_then(|j| segments.get(j))
+                    .map(|s| decode(s))
+                    .ok_or(RenderPathError::NoSegment(*i))
+            }
+            Field::Filename => filename(),
+            Field::Stem => {
+                let name = filename()?;
+                Ok(match split_extension(&name) {
+                    Some((stem, _)) => stem.to_owned(),
+                    None => name,
+                })
+            }
+            Field::Extension => split_extension(&filename()?)
+                .map(|(_, ext)| ext.to_owned())
+                .ok_or(RenderPathError::NoExtension),
+            Field::Metadata(name) => metadata
+                .get(name)
+                .and_then(|values| values.first())
+                .cloned()
+                .ok_or_else(|| RenderPathError::NoMetadata(name.clone())),
+        }
+    }
+}
+
+fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().into_owned()
+}
+
+/// Split a filename into its stem and extension.  A leading period does not
+/// start an extension.
+fn split_extension(name: &str) -> Option<(&str, &str)> {
+    name.rsplit_once('.')
+        .filter(|(stem, ext)| !stem.is_empty() && !ext.is_empty())
+}
+
+impl PathTemplate {
+    /// Fill in the template for the given URL and metadata
+    pub fn render(
+        &self,
+        url: &Url,
+        metadata: &HashMap<String, Vec<String>>,
+    ) -> Result<FilePath, RenderPathError> {
+        let path = self.render_string(url, metadata)?;
+        FilePath::try_from(path.as_str()).map_err(|source| RenderPathError::Path { path, source })
+    }
+
+    fn render_string(
+        &self,
+        url: &Url,
+        metadata: &HashMap<String, Vec<String>>,
+    ) -> Result<String, RenderPathError> {
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => path.push_str(s),
+                Part::Field(f) => path.push_str(&f.render(url, metadata)?),
+            }
+        }
+        Ok(path)
+    }
+}
+
+impl fmt::Display for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = ParsePathTemplateError;
+
+    fn from_str(s: &str) -> Result<PathTemplate, ParsePathTemplateError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '{' if chars.next_if(|&(_, c)| c == '{').is_some() => literal.push('{'),
+                '}' if chars.next_if(|&(_, c)| c == '}').is_some() => literal.push('}'),
+                '{' => {
+                    let rest = &s[i + 1..];
+                    let Some(end) = rest.find('}') else {
+                        return Err(ParsePathTemplateError::Unclosed);
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(Field::parse(&rest[..end])?));
+                    // Skip past the closing brace
+                    while chars.next_if(|&(j, _)| j <= i + 1 + end).is_some() {}
+                }
+                '}' => return Err(ParsePathTemplateError::Unopened),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(PathTemplate {
+            source: s.to_owned(),
+            parts,
+        })
+    }
+}
+
+/// Error returned when parsing an invalid [`PathTemplate`]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ParsePathTemplateError {
+    #[error("unknown placeholder {{{0}}}")]
+    Placeholder(String),
+    #[error("unclosed '{{' in path template")]
+    Unclosed,
+    #[error("unmatched '}}' in path template; use '}}}}' for a literal brace")]
+    Unopened,
+}
+
+/// Error returned when a [`PathTemplate`] cannot be filled in for an item
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum RenderPathError {
+    #[error("URL has no host")]
+    NoHost,
+    #[error("URL has no path")]
+    NoPath,
+    #[error("URL path has no segment {0}")]
+    NoSegment(isize),
+    #[error("URL path does not end in a filename")]
+    NoFilename,
+    #[error("URL filename has no extension")]
+    NoExtension,
+    #[error("item has no {0:?} metadata field")]
+    NoMetadata(String),
+    #[error("templated path {path:?} is invalid: {source}")]
+    Path { path: String, source: FilePathError },
+}
+
+/// How to determine the paths of input items
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PathOptions {
+    /// Template for deriving the paths of items that don't specify one
+    pub template: Option<PathTemplate>,
+    /// Rewrite invalid paths with [`FilePath::sanitize()`] instead of
+    /// rejecting them
+    pub sanitize: bool,
+}
+
+impl PathOptions {
+    /// Determine the path of an item from its input path, if any, or else
+    /// from the template.  If sanitization changed the path, the path before
+    /// sanitization is returned as well.
+    pub(crate) fn resolve(
+        &self,
+        path: Option<&str>,
+        url: &Url,
+        metadata: &HashMap<String, Vec<String>>,
+    ) -> Result<(FilePath, Option<String>), ItemPathError> {
+        let path = match (path, &self.template) {
+            (Some(p), _) => p.to_owned(),
+            (None, Some(template)) => template.render_string(url, metadata)?,
+            (None, None) => return Err(ItemPathError::Missing),
+        };
+        let checked = FilePath::try_from(path.as_str());
+        if !self.sanitize {
+            return match checked {
+                Ok(fp) => Ok((fp, None)),
+                Err(source) => Err(ItemPathError::Invalid { path, source }),
+            };
+        }
+        let sanitized = FilePath::sanitize(&path).map_err(|source| ItemPathError::Invalid {
+            path: path.clone(),
+            source,
+        })?;
+        if checked.as_ref() == Ok(&sanitized) {
+            Ok((sanitized, None))
+        } else {
+            log::info!("Sanitized path {path:?} to {sanitized}");
+            Ok((sanitized, Some(path)))
+        }
+    }
+}
+
+/// Error returned when an input item's path cannot be determined
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ItemPathError {
+    #[error("item has no path, and no path template was given")]
+    Missing,
+    #[error("could not fill in path template: {0}")]
+    Template(#[from] RenderPathError),
+    #[error("invalid path {path:?}: {source}")]
+    Invalid { path: String, source: FilePathError },
+}
+
+/// Error returned by [`InputItem::complete()`]
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum InputItemError {
+    #[error("{0}")]
+    Path(#[from] ItemPathError),
+    #[error("{0}")]
+    Metadata(#[from] MetadataError),
+}
+
+/// An input item as read from JSON Lines input, before its path has been
+/// determined according to a [`PathOptions`]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct InputItem {
+    #[serde(default)]
+    pub path: Option<String>,
+    pub url: Url,
+    #[serde(default)]
+    pub metadata: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub metadata_ops: HashMap<String, MetadataOp>,
+    #[serde(default)]
+    pub extra_urls: Vec<Url>,
+    #[serde(default)]
+    pub on_existing: Option<ExistingPolicy>,
+    #[serde(flatten)]
+    pub expected: ExpectedContent,
+}
+
+impl InputItem {
+    /// Convert to a [`Downloadable`], determining its path according to
+    /// `options` and checking its metadata with
+    /// [`Downloadable::check_metadata()`] according to `metadata`
+    pub fn complete(
+        self,
+        options: &PathOptions,
+        metadata: BadMetadataPolicy,
+    ) -> Result<Downloadable, InputItemError> {
+        let (path, original_path) =
+            options.resolve(self.path.as_deref(), &self.url, &self.metadata)?;
+        let mut dl = Downloadable {
+            path,
+            url: self.url,
+            metadata: self.metadata,
+            metadata_ops: self.metadata_ops,
+            extra_urls: self.extra_urls,
+            on_existing: self.on_existing,
+            original_path,
+            expected: self.expected,
+        };
+        dl.check_metadata(metadata)?;
+        Ok(dl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        "{url.host}/{url.path}",
+        "example.com/dir/sub/file%20name.tar.gz",
+        "example.com/dir/sub/file name.tar.gz"
+    )]
+    #[case(
+        "{url.segment.0}/{url.segment.-1}",
+        "example.com/dir/sub/file.txt",
+        "dir/file.txt"
+    )]
+    #[case(
+        "{metadata.author}/{metadata.year}/{url.filename}",
+        "example.com/a/paper.pdf",
+        "Jane Doe/2021/paper.pdf"
+    )]
+    #[case(
+        "{url.stem}-{metadata.year}.{url.ext}",
+        "example.com/paper.pdf",
+        "paper-2021.pdf"
+    )]
+    #[case("{url.stem}", "example.com/.profile", ".profile")]
+    #[case("{{lit}}/{url.filename}", "example.com/x", "{lit}/x")]
+    fn test_render(#[case] template: &str, #[case] url: &str, #[case] path: &str) {
+        let template = template.parse::<PathTemplate>().unwrap();
+        let url = Url::parse(&format!("https://{url}")).unwrap();
+        let metadata = HashMap::from([
+            (String::from("author"), vec![String::from("Jane Doe")]),
+            (
+                String::from("year"),
+                vec![String::from("2021"), String::from("2022")],
+            ),
+        ]);
+        assert_eq!(template.render(&url, &metadata).unwrap().as_str(), path);
+    }
+
+    #[rstest]
+    #[case("{metadata.title}", "https://example.com/a", RenderPathError::NoMetadata("title".into()))]
+    #[case(
+        "{url.filename}",
+        "https://example.com/dir/",
+        RenderPathError::NoFilename
+    )]
+    #[case(
+        "{url.ext}",
+        "https://example.com/README",
+        RenderPathError::NoExtension
+    )]
+    #[case(
+        "{url.segment.3}",
+        "https://example.com/a/b",
+        RenderPathError::NoSegment(3)
+    )]
+    #[case("{url.segment.-3}", "https://example.com/a/b", RenderPathError::NoSegment(-3))]
+    #[case("../{url.filename}", "https://example.com/a", RenderPathError::Path {path: "../a".into(), source: FilePathError::NotNormalized})]
+    fn test_render_err(#[case] template: &str, #[case] url: &str, #[case] err: RenderPathError) {
+        let template = template.parse::<PathTemplate>().unwrap();
+        let url = Url::parse(url).unwrap();
+        assert_eq!(template.render(&url, &HashMap::new()), Err(err));
+    }
+
+    #[rstest]
+    #[case("{url.hostname}", ParsePathTemplateError::Placeholder("url.hostname".into()))]
+    #[case("{metadata.}", ParsePathTemplateError::Placeholder("metadata.".into()))]
+    #[case("{url.segment.x}", ParsePathTemplateError::Placeholder("url.segment.x".into()))]
+    #[case("{url.host", ParsePathTemplateError::Unclosed)]
+    #[case("foo}", ParsePathTemplateError::Unopened)]
+    fn test_parse_err(#[case] template: &str, #[case] err: ParsePathTemplateError) {
+        assert_eq!(template.parse::<PathTemplate>(), Err(err));
+    }
+
+    #[test]
+    fn test_complete() {
+        let item = serde_json::from_str::<InputItem>(
+            r#"{"url": "https://example.com/files/data.csv", "metadata": {"set": ["alpha"]}}"#,
+        )
+        .unwrap();
+        let options = PathOptions {
+            template: Some("{metadata.set}/{url.filename}".parse().unwrap()),
+            sanitize: false,
+        };
+        assert_eq!(
+            item.clone()
+                .complete(&PathOptions::default(), BadMetadataPolicy::Reject),
+            Err(InputItemError::Path(ItemPathError::Missing))
+        );
+        let dl = item.complete(&options, BadMetadataPolicy::Reject).unwrap();
+        assert_eq!(dl.path.as_str(), "alpha/data.csv");
+        assert_eq!(dl.original_path, None);
+        let item = serde_json::from_str::<InputItem>(
+            r#"{"url": "https://example.com/files/data.csv", "path": "given.csv"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            item.complete(&options, BadMetadataPolicy::Reject)
+                .unwrap()
+                .path
+                .as_str(),
+            "given.csv"
+        );
+    }
+
+    #[test]
+    fn test_complete_bad_metadata() {
+        let item = serde_json::from_str::<InputItem>(
+            r#"{"url": "https://example.com/x", "path": "x.txt", "metadata": {"my tag": ["a"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            item.clone()
+                .complete(&PathOptions::default(), BadMetadataPolicy::Reject),
+            Err(InputItemError::Metadata(MetadataError::BadChar {
+                field: "my tag".into(),
+                c: ' '
+            }))
+        );
+        let dl = item
+            .complete(&PathOptions::default(), BadMetadataPolicy::Sanitize)
+            .unwrap();
+        assert_eq!(
+            dl.metadata,
+            HashMap::from([(String::from("my_tag"), vec![String::from("a")])])
+        );
+    }
+
+    #[test]
+    fn test_complete_sanitize() {
+        let item = serde_json::from_str::<InputItem>(
+            r#"{"url": "https://example.com/x", "path": "../Q&A: part 1?.txt"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            item.clone()
+                .complete(&PathOptions::default(), BadMetadataPolicy::Reject),
+            Err(InputItemError::Path(ItemPathError::Invalid {
+                source: FilePathError::NotNormalized,
+                ..
+            }))
+        ));
+        let options = PathOptions {
+            template: None,
+            sanitize: true,
+        };
+        let dl = item.complete(&options, BadMetadataPolicy::Reject).unwrap();
+        assert_eq!(dl.path.as_str(), "_/Q&A_ part 1_.txt");
+        assert_eq!(dl.original_path.as_deref(), Some("../Q&A: part 1?.txt"));
+        let item = serde_json::from_str::<InputItem>(
+            r#"{"url": "https://example.com/x", "path": "./ok/path.txt"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            item.complete(&options, BadMetadataPolicy::Reject)
+                .unwrap()
+                .original_path,
+            None
+        );
+    }
+}
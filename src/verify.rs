@@ -0,0 +1,352 @@
+use crate::annex::key::key_size;
+use crate::cmd::{CommandError, LoggedCommand};
+use crate::FilePath;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::digest::DynDigest;
+use sha2::{Sha256, Sha512};
+use std::fmt::{self, Write};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The expected size & checksums of the content downloaded for an item.
+/// Any fields that are set are checked once the download finishes.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ExpectedContent {
+    /// Size in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Hex-encoded MD5 digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    /// Hex-encoded SHA-1 digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+    /// Hex-encoded SHA-256 digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Hex-encoded SHA-512 digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha512: Option<String>,
+}
+
+impl ExpectedContent {
+    /// Returns true if nothing is expected of the content
+    pub fn is_empty(&self) -> bool {
+        self.size.is_none() && self.hashes().next().is_none()
+    }
+
+    fn hashes(&self) -> impl Iterator<Item = (HashAlgorithm, &str)> + '_ {
+        [
+            (HashAlgorithm::Md5, &self.md5),
+            (HashAlgorithm::Sha1, &self.sha1),
+            (HashAlgorithm::Sha256, &self.sha256),
+            (HashAlgorithm::Sha512, &self.sha512),
+        ]
+        .into_iter()
+        .filter_map(|(alg, hash)| Some((alg, hash.as_deref()?)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// Determine the algorithm used by the git-annex backend with the given
+    /// name (e.g., "SHA256E"), if it's one we know
+    fn for_backend(backend: &str) -> Option<HashAlgorithm> {
+        match backend.strip_suffix('E').unwrap_or(backend) {
+            "MD5" => Some(HashAlgorithm::Md5),
+            "SHA1" => Some(HashAlgorithm::Sha1),
+            "SHA256" => Some(HashAlgorithm::Sha256),
+            "SHA512" => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    fn hasher(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            HashAlgorithm::Md5 => Box::new(Md5::default()),
+            HashAlgorithm::Sha1 => Box::new(Sha1::default()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::default()),
+            HashAlgorithm::Sha512 => Box::new(Sha512::default()),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Md5 => write!(f, "MD5"),
+            HashAlgorithm::Sha1 => write!(f, "SHA-1"),
+            HashAlgorithm::Sha256 => write!(f, "SHA-256"),
+            HashAlgorithm::Sha512 => write!(f, "SHA-512"),
+        }
+    }
+}
+
+/// A way in which downloaded content differs from an [`ExpectedContent`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Mismatch {
+    Size {
+        expected: u64,
+        actual: u64,
+    },
+    Hash {
+        algorithm: HashAlgorithm,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Size { expected, actual } => {
+                write!(f, "size mismatch: expected {expected} bytes, got {actual}")
+            }
+            Mismatch::Hash {
+                algorithm,
+                expected,
+                actual,
+            } => write!(f, "{algorithm} mismatch: expected {expected}, got {actual}"),
+        }
+    }
+}
+
+/// Check the content of the annexed file at `path` (relative to `repo`) with
+/// key `key` against `expected`, returning all mismatches found.
+///
+/// Whatever can be checked using the size & hash embedded in the key is
+/// checked that way; the content is only read if a checksum for an algorithm
+/// other than the key's backend is expected, or if a size is expected and
+/// the key doesn't record one.
+pub(crate) async fn verify_content(
+    repo: &Path,
+    path: &FilePath,
+    key: &str,
+    expected: &ExpectedContent,
+) -> Result<Vec<Mismatch>, io::Error> {
+    let mut mismatches = Vec::new();
+    let mut to_hash = Vec::new();
+    let mut read_size = false;
+    if let Some(expected) = expected.size {
+        match key_size(key) {
+            Some(actual) if actual != expected => {
+                mismatches.push(Mismatch::Size { expected, actual });
+            }
+            Some(_) => (),
+            None => read_size = true,
+        }
+    }
+    let key_hash = key_hash(key);
+    for (algorithm, expected) in expected.hashes() {
+        match key_hash {
+            Some((alg, actual)) if alg == algorithm => {
+                if !actual.eq_ignore_ascii_case(expected) {
+                    mismatches.push(Mismatch::Hash {
+                        algorithm,
+                        expected: expected.to_owned(),
+                        actual: actual.to_ascii_lowercase(),
+                    });
+                }
+            }
+            _ => to_hash.push((algorithm, expected.to_owned())),
+        }
+    }
+    if !read_size && to_hash.is_empty() {
+        return Ok(mismatches);
+    }
+    let fullpath = repo.join(path.as_str());
+    let algorithms = to_hash.iter().map(|&(alg, _)| alg).collect::<Vec<_>>();
+    let (size, digests) = tokio::task::spawn_blocking(move || hash_file(&fullpath, &algorithms))
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+    if let Some(expected) = expected.size.filter(|_| read_size) {
+        if expected != size {
+            mismatches.push(Mismatch::Size {
+                expected,
+                actual: size,
+            });
+        }
+    }
+    for ((algorithm, expected), actual) in to_hash.into_iter().zip(digests) {
+        if !actual.eq_ignore_ascii_case(&expected) {
+            mismatches.push(Mismatch::Hash {
+                algorithm,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Remove the file at `path` with mismatched content from the repository and
+/// drop the content of its key `key`
+pub(crate) async fn drop_content(
+    repo: &Path,
+    path: &FilePath,
+    key: &str,
+) -> Result<(), CommandError> {
+    LoggedCommand::new(
+        "git",
+        ["rm", "--quiet", "--force", "--", path.as_str()],
+        repo,
+    )
+    .status()
+    .await?;
+    LoggedCommand::new(
+        "git-annex",
+        ["drop", "--force", &format!("--key={key}")],
+        repo,
+    )
+    .status()
+    .await
+}
+
+/// Extract the hash algorithm & hex digest embedded in a git-annex key (e.g.,
+/// the SHA-256 digest `6fef…e191` in `SHA256E-s19--6fef…e191.txt`), if any
+fn key_hash(key: &str) -> Option<(HashAlgorithm, &str)> {
+    let (fields, name) = key.split_once("--")?;
+    let backend = fields.split('-').next()?;
+    let algorithm = HashAlgorithm::for_backend(backend)?;
+    let digest = if backend.ends_with('E') {
+        name.split_once('.').map_or(name, |(digest, _)| digest)
+    } else {
+        name
+    };
+    Some((algorithm, digest))
+}
+
+/// Read the file at `path`, returning its size and its hex digests under
+/// each of `algorithms`
+fn hash_file(path: &Path, algorithms: &[HashAlgorithm]) -> io::Result<(u64, Vec<String>)> {
+    let mut hashers = algorithms
+        .iter()
+        .map(|alg| alg.hasher())
+        .collect::<Vec<_>>();
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; 65536];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        size += n as u64;
+        for h in &mut hashers {
+            h.update(&buf[..n]);
+        }
+    }
+    let digests = hashers
+        .into_iter()
+        .map(|h| {
+            h.finalize().iter().fold(String::new(), |mut s, b| {
+                let _ = write!(s, "{b:02x}");
+                s
+            })
+        })
+        .collect();
+    Ok((size, digests))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const KEY: &str =
+        "SHA256E-s19--6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191.txt";
+
+    #[rstest]
+    #[case(KEY, Some((HashAlgorithm::Sha256, "6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191")))]
+    #[case("MD5E-s3405224--dd15380fc1b27858f647a30cc2399a52.pdf", Some((HashAlgorithm::Md5, "dd15380fc1b27858f647a30cc2399a52")))]
+    #[case("SHA1-s0--da39a3ee5e6b4b0d3255bfef95601890afd80709", Some((HashAlgorithm::Sha1, "da39a3ee5e6b4b0d3255bfef95601890afd80709")))]
+    #[case(
+        "BLAKE2B256E-s0--0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8.txt",
+        None
+    )]
+    #[case("URL--https&c%%example.com%foo.txt", None)]
+    #[case("WORM-m1666048413--file.txt", None)]
+    fn test_key_hash(#[case] key: &str, #[case] hash: Option<(HashAlgorithm, &str)>) {
+        assert_eq!(key_hash(key), hash);
+    }
+
+    #[tokio::test]
+    async fn test_verify_by_key() {
+        let path = FilePath::try_from("file.txt").unwrap();
+        let expected = ExpectedContent {
+            size: Some(19),
+            sha256: Some("6FEF386EFA7208EAF1C596B6AB2F8A5A3583696EF8649BE0552AB3EFFAD1E191".into()),
+            ..ExpectedContent::default()
+        };
+        // The file doesn't exist, so this passes only if it isn't read.
+        let r = verify_content(Path::new("nonexistent"), &path, KEY, &expected).await;
+        assert_eq!(r.unwrap(), []);
+        let expected = ExpectedContent {
+            size: Some(20),
+            ..ExpectedContent::default()
+        };
+        let r = verify_content(Path::new("nonexistent"), &path, KEY, &expected).await;
+        assert_eq!(
+            r.unwrap(),
+            [Mismatch::Size {
+                expected: 20,
+                actual: 19
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_by_content() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::write(tmpdir.path().join("file.txt"), "This is test text.\n").unwrap();
+        let path = FilePath::try_from("file.txt").unwrap();
+        let expected = ExpectedContent {
+            size: Some(19),
+            md5: Some("29658667aa7b837642278ce9ea31fb6e".into()),
+            sha1: Some("0a5d2d8c7c3b9b2ca1bd4bd1c7b2de3ab5f8a1a3".into()),
+            sha256: Some("6fef386efa7208eaf1c596b6ab2f8a5a3583696ef8649be0552ab3effad1e191".into()),
+            ..ExpectedContent::default()
+        };
+        let mismatches = verify_content(
+            tmpdir.path(),
+            &path,
+            "WORM-m1666048413--file.txt",
+            &expected,
+        )
+        .await
+        .unwrap();
+        let wrong = mismatches
+            .iter()
+            .map(|m| match m {
+                Mismatch::Hash { algorithm, .. } => *algorithm,
+                Mismatch::Size { .. } => panic!("Unexpected size mismatch"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(wrong, [HashAlgorithm::Sha1]);
+    }
+
+    #[test]
+    fn test_display_mismatch() {
+        let m = Mismatch::Size {
+            expected: 20,
+            actual: 19,
+        };
+        assert_eq!(m.to_string(), "size mismatch: expected 20 bytes, got 19");
+        let m = Mismatch::Hash {
+            algorithm: HashAlgorithm::Sha256,
+            expected: "abc".into(),
+            actual: "def".into(),
+        };
+        assert_eq!(m.to_string(), "SHA-256 mismatch: expected abc, got def");
+    }
+}
@@ -0,0 +1,44 @@
+use futures_util::{stream, StreamExt};
+use gamdam::{ensure_annex_repo, Downloadable, Event, Gamdam, Shutdown};
+use std::time::Duration;
+use tempfile::tempdir;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::timeout;
+
+#[tokio::test]
+#[ignore = "requires git-annex and network access"]
+async fn test_download_starts_before_input_ends() {
+    let tmpdir = tempdir().unwrap();
+    let repo = tmpdir.path();
+    ensure_annex_repo(repo).await.unwrap();
+    let (sender, mut receiver) = unbounded_channel();
+    let gamdam = Gamdam::builder(repo).events(sender).save(false).build();
+    let dl = serde_json::from_str::<Downloadable>(
+        r#"{"path": "first.txt", "url": "https://www.example.com/first.txt"}"#,
+    )
+    .unwrap();
+    // The stream yields one item and then never ends, like a producer that
+    // is still writing to a pipe.
+    let items = stream::iter([Ok(dl)]).chain(stream::pending());
+    let shutdown = Shutdown::new();
+    let watch = async {
+        let started = timeout(Duration::from_secs(30), async {
+            while let Some(event) = receiver.recv().await {
+                if let Event::Started { path, .. } = event {
+                    return Some(path);
+                }
+            }
+            None
+        })
+        .await;
+        shutdown.kill();
+        started
+    };
+    let (r, started) = tokio::join!(gamdam.try_download_with_shutdown(items, &shutdown), watch);
+    assert_eq!(
+        started.expect("download was not started before the input ended"),
+        Some("first.txt".try_into().unwrap())
+    );
+    // The run is forcibly terminated because the input never ends.
+    assert!(r.is_err());
+}